@@ -1,16 +1,32 @@
-use std::path::PathBuf;
+use std::{net::IpAddr, path::PathBuf};
 
 use argh::FromArgs;
 use log::LevelFilter;
 
+use crate::discovery::{DEFAULT_DISCOVERY_TARGET, DEFAULT_DISCOVERY_TIMEOUT_SECS};
+
 #[derive(FromArgs)]
 #[argh(description = "Tapo REST server")]
 pub struct Cmd {
-    #[argh(positional, description = "path to the configuration file (.json)")]
-    pub config_path: PathBuf,
+    #[argh(subcommand)]
+    pub command: Option<Command>,
+
+    #[argh(
+        positional,
+        description = "path to the configuration file (.json, .toml, .yaml or .yml)"
+    )]
+    pub config_path: Option<PathBuf>,
 
     #[argh(option, short = 'p', long = "port", description = "port to serve on")]
-    pub port: u16,
+    pub port: Option<u16>,
+
+    #[argh(
+        switch,
+        short = 'w',
+        long = "watch-config",
+        description = "reload the configuration automatically when its file changes"
+    )]
+    pub watch_config: bool,
 
     #[argh(
         option,
@@ -21,3 +37,34 @@ pub struct Cmd {
     )]
     pub verbosity: LevelFilter,
 }
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum Command {
+    Discover(DiscoverCmd),
+}
+
+#[derive(FromArgs)]
+#[argh(
+    subcommand,
+    name = "discover",
+    description = "discover Tapo devices on the local network and print a configuration snippet for them"
+)]
+pub struct DiscoverCmd {
+    #[argh(
+        option,
+        short = 't',
+        long = "target",
+        description = "address to send the discovery probe to (broadcast address by default)",
+        default = "DEFAULT_DISCOVERY_TARGET.parse().unwrap()"
+    )]
+    pub target: IpAddr,
+
+    #[argh(
+        option,
+        long = "timeout",
+        description = "time to wait for devices to respond, in seconds (3 by default)",
+        default = "DEFAULT_DISCOVERY_TIMEOUT_SECS"
+    )]
+    pub timeout: u64,
+}
@@ -1,33 +1,382 @@
-use std::net::IpAddr;
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
 
+use anyhow::{Context, Result, bail};
+
+use chrono::NaiveTime;
 use serde::{Deserialize, Serialize};
+use tapo::requests::{Color, LightingEffect, LightingEffectPreset};
+
+use crate::server::{TapoDeviceType, ThresholdDirection};
+
+/// Format of the configuration file, detected from its extension
+#[derive(Clone, Copy)]
+pub enum ConfigFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// Detect the format of a configuration file, which is JSON for files without an extension
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let Some(ext) = path.extension() else {
+            return Ok(Self::Json);
+        };
+
+        match ext.to_string_lossy().to_lowercase().as_str() {
+            "json" => Ok(Self::Json),
+            "toml" => Ok(Self::Toml),
+            "yaml" | "yml" => Ok(Self::Yaml),
+            ext => bail!(
+                "Unsupported configuration file extension '.{ext}' (expected '.json', '.toml', '.yaml' or '.yml')"
+            ),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Json => "JSON",
+            Self::Toml => "TOML",
+            Self::Yaml => "YAML",
+        }
+    }
+
+    /// Parse a configuration, with errors pointing to the line and column of the problem
+    pub fn parse(self, config_str: &str) -> Result<Config> {
+        let config = match self {
+            Self::Json => serde_json::from_str(config_str).map_err(anyhow::Error::from),
+            Self::Toml => toml::from_str(config_str).map_err(anyhow::Error::from),
+            // Enums are written as single-key maps, like in JSON and TOML, rather than YAML tags
+            Self::Yaml => serde_yaml_ng::with::singleton_map_recursive::deserialize(
+                serde_yaml_ng::Deserializer::from_str(config_str),
+            )
+            .map_err(anyhow::Error::from),
+        };
 
-use crate::server::TapoDeviceType;
+        config.with_context(|| format!("Failed to parse the {} configuration", self.name()))
+    }
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct Config {
     pub tapo_credentials: TapoCredentials,
     pub devices: Vec<TapoConnectionInfos>,
+    #[serde(default)]
+    pub groups: Vec<DeviceGroup>,
+    #[serde(default)]
+    pub scenes: Vec<Scene>,
+    #[serde(default)]
+    pub schedules: Vec<Schedule>,
+    /// Coordinates used to compute sunrise and sunset times
+    #[serde(default)]
+    pub location: Option<Location>,
+    #[serde(default)]
+    pub events: EventsConfig,
+    #[serde(default)]
+    pub webhooks: Vec<Webhook>,
+    #[serde(default)]
+    pub mqtt: Option<MqttConfig>,
+    #[serde(default)]
+    pub energy_history: Option<EnergyHistoryConfig>,
+    #[serde(default)]
+    pub tariffs: Option<TariffsConfig>,
     pub server: ServerConfig,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct TapoCredentials {
     pub email: String,
     pub password: String,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct TapoConnectionInfos {
     pub name: String,
     pub device_type: TapoDeviceType,
-    pub ip_addr: IpAddr,
+    /// Address of the device, resolved from its host name or MAC address if omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip_addr: Option<IpAddr>,
+    /// Host name (or IP address) of the device, resolved when connecting to it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// MAC address of the device, used to find its new IP address when it changes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DeviceGroup {
+    pub name: String,
+    pub devices: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Scene {
+    pub name: String,
+    pub devices: Vec<SceneDeviceState>,
+}
+
+/// Target state of a device in a scene
+///
+/// Properties that are not provided are left untouched when applying the scene.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SceneDeviceState {
+    pub device: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub brightness: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<Color>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hue_saturation: Option<HueSaturation>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color_temperature: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lighting_effect: Option<SceneLightingEffect>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct HueSaturation {
+    pub hue: u16,
+    pub saturation: u8,
+}
+
+/// Either the name of a preset, or a full lighting effect (e.g. captured from a device)
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum SceneLightingEffect {
+    Preset(LightingEffectPreset),
+    Custom(Box<LightingEffect>),
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Schedule {
+    pub name: String,
+    pub when: ScheduleTrigger,
+    pub run: ScheduleTarget,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ScheduleTrigger {
+    /// Cron expression, evaluated in the server's local timezone
+    Cron(String),
+
+    /// Sunrise, computed from the configured location
+    Sunrise {
+        #[serde(default)]
+        offset_minutes: i32,
+    },
+
+    /// Sunset, computed from the configured location
+    Sunset {
+        #[serde(default)]
+        offset_minutes: i32,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum ScheduleTarget {
+    /// Run an action on a single device
+    Device {
+        name: String,
+        action: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        params: Option<serde_json::Value>,
+    },
+
+    /// Run an action on all devices of a group
+    Group {
+        name: String,
+        action: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        params: Option<serde_json::Value>,
+    },
+
+    /// Apply a scene
+    Scene(String),
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct EventsConfig {
+    /// Delay between two polls of the devices' state, in seconds
+    #[serde(default = "default_poll_interval_secs")]
+    pub poll_interval_secs: u64,
+
+    /// Current power values (in watts) that trigger an event when crossed
+    #[serde(default)]
+    pub power_thresholds: Vec<PowerThreshold>,
+}
+
+impl Default for EventsConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval_secs: default_poll_interval_secs(),
+            power_thresholds: vec![],
+        }
+    }
+}
+
+fn default_poll_interval_secs() -> u64 {
+    10
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PowerThreshold {
+    pub device: String,
+    pub watts: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Webhook {
+    pub name: String,
+    pub url: String,
+
+    /// Event types to send (defaults to all)
+    #[serde(default)]
+    pub events: Vec<String>,
+
+    /// Devices to send the events of (defaults to all)
+    #[serde(default)]
+    pub devices: Vec<String>,
+
+    /// Only send power threshold events when the current power crosses the threshold in this direction
+    #[serde(default)]
+    pub threshold_direction: Option<ThresholdDirection>,
+
+    /// Secret used to sign the payloads with HMAC-SHA256
+    #[serde(default)]
+    pub secret: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+
+    /// Prefix of all topics the devices' state is published to and commands are received from
+    #[serde(default = "default_mqtt_base_topic")]
+    pub base_topic: String,
+
+    /// Publish Home Assistant discovery payloads
+    #[serde(default = "default_enabled")]
+    pub home_assistant_discovery: bool,
+
+    /// Prefix of Home Assistant discovery topics
+    #[serde(default = "default_mqtt_discovery_prefix")]
+    pub discovery_prefix: String,
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_mqtt_client_id() -> String {
+    "tapo-rest".to_owned()
+}
+
+fn default_mqtt_base_topic() -> String {
+    "tapo".to_owned()
+}
+
+fn default_mqtt_discovery_prefix() -> String {
+    "homeassistant".to_owned()
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct EnergyHistoryConfig {
+    /// Path to the `SQLite` database, created if it doesn't exist yet
+    pub database: PathBuf,
+
+    /// Delay between two collections of energy data, in seconds
+    #[serde(default = "default_collect_interval_secs")]
+    pub collect_interval_secs: u64,
+}
+
+fn default_collect_interval_secs() -> u64 {
+    300
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TariffsConfig {
+    pub currency: String,
+    pub plans: Vec<TariffPlan>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TariffPlan {
+    pub name: String,
+
+    /// Price per kWh outside of any period
+    pub rate: f64,
+
+    /// Periods with a different price, the first matching one is used
+    #[serde(default)]
+    pub periods: Vec<TariffPeriod>,
+
+    /// Devices billed with this plan, the plan without any device applies to all other devices
+    #[serde(default)]
+    pub devices: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TariffPeriod {
+    pub name: String,
+
+    /// Price per kWh during this period
+    pub rate: f64,
+
+    /// Local time the period starts at, the period covers the whole day if it's the same as its end
+    pub start: NaiveTime,
+
+    /// Local time the period ends at, may be before its start to span over midnight
+    pub end: NaiveTime,
+
+    #[serde(default)]
+    pub days: TariffDays,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TariffDays {
+    #[default]
+    All,
+    Weekdays,
+    Weekends,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct ServerConfig {
     pub password: String,
     pub api_keys: Vec<ServerApiKey>,
+
+    /// Allow calling actions that change a device's state with `GET` instead of `POST`
+    #[serde(default)]
+    pub legacy_get_actions: bool,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -35,3 +384,153 @@ pub struct ServerApiKey {
     pub name: String,
     pub key: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JSON: &str = r#"{
+        "tapo_credentials": { "email": "user@example.com", "password": "secret" },
+        "devices": [{ "name": "plug", "device_type": "P110", "ip_addr": "192.168.1.10" }],
+        "schedules": [
+            { "name": "morning", "when": { "cron": "0 7 * * *" }, "run": { "device": { "name": "plug", "action": "on" } } }
+        ],
+        "server": { "password": "secret", "api_keys": [] }
+    }"#;
+
+    const TOML: &str = r#"
+        [tapo_credentials]
+        email = "user@example.com"
+        password = "secret"
+
+        [[devices]]
+        name = "plug"
+        device_type = "P110"
+        ip_addr = "192.168.1.10"
+
+        [[schedules]]
+        name = "morning"
+        when = { cron = "0 7 * * *" }
+        run = { device = { name = "plug", action = "on" } }
+
+        [server]
+        password = "secret"
+        api_keys = []
+    "#;
+
+    const YAML: &str = r#"
+tapo_credentials:
+  email: user@example.com
+  password: secret
+
+devices:
+  - name: plug
+    device_type: P110
+    ip_addr: 192.168.1.10
+
+schedules:
+  - name: morning
+    when: { cron: "0 7 * * *" }
+    run:
+      device: { name: plug, action: on }
+
+server:
+  password: secret
+  api_keys: []
+"#;
+
+    fn parse_error(format: ConfigFormat, config_str: &str) -> String {
+        format!("{:#}", format.parse(config_str).err().unwrap())
+    }
+
+    #[test]
+    fn format_from_extension() {
+        let format = |path: &str| ConfigFormat::from_path(Path::new(path)).unwrap().name();
+
+        assert_eq!(format("devices"), "JSON");
+        assert_eq!(format("/app/devices.json"), "JSON");
+        assert_eq!(format("devices.toml"), "TOML");
+        assert_eq!(format("devices.yaml"), "YAML");
+        assert_eq!(format("devices.YML"), "YAML");
+    }
+
+    #[test]
+    fn unsupported_extension() {
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("devices.ini"))
+                .err()
+                .unwrap()
+                .to_string(),
+            "Unsupported configuration file extension '.ini' (expected '.json', '.toml', '.yaml' or '.yml')"
+        );
+    }
+
+    #[test]
+    fn same_configuration_in_all_formats() {
+        for (format, config_str) in [
+            (ConfigFormat::Json, JSON),
+            (ConfigFormat::Toml, TOML),
+            (ConfigFormat::Yaml, YAML),
+        ] {
+            let config = format.parse(config_str).unwrap();
+
+            assert_eq!(config.devices[0].name, "plug", "{}", format.name());
+            assert!(
+                config.schedules[0].when == ScheduleTrigger::Cron("0 7 * * *".to_owned()),
+                "{}",
+                format.name()
+            );
+
+            let ScheduleTarget::Device { name, action, .. } = &config.schedules[0].run else {
+                panic!("{}: the schedule should target a device", format.name());
+            };
+
+            assert_eq!((name.as_str(), action.as_str()), ("plug", "on"));
+        }
+    }
+
+    #[test]
+    fn json_errors_point_to_their_location() {
+        assert_eq!(
+            parse_error(
+                ConfigFormat::Json,
+                "{\n  \"tapo_credentials\": {\n    \"email\": 1\n  }\n}"
+            ),
+            "Failed to parse the JSON configuration: invalid type: integer `1`, expected a string at line 3 column 14"
+        );
+    }
+
+    #[test]
+    fn toml_errors_point_to_their_location() {
+        let error = parse_error(
+            ConfigFormat::Toml,
+            "[tapo_credentials]\nemail = \"a\"\npassword = 3\n",
+        );
+
+        assert!(
+            error.starts_with(
+                "Failed to parse the TOML configuration: TOML parse error at line 3, column 12"
+            ),
+            "{error}"
+        );
+        assert!(
+            error.contains("invalid type: integer `3`, expected a string"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn yaml_errors_point_to_their_location() {
+        assert_eq!(
+            parse_error(
+                ConfigFormat::Yaml,
+                "tapo_credentials:\n  email: a\n  password: [1]\n"
+            ),
+            "Failed to parse the YAML configuration: tapo_credentials.password: invalid type: sequence, expected a string at line 3 column 13"
+        );
+        assert_eq!(
+            parse_error(ConfigFormat::Yaml, "devices: [\n"),
+            "Failed to parse the YAML configuration: did not find expected node content at line 2 column 1, while parsing a flow node"
+        );
+    }
+}
@@ -1,16 +1,28 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
 
-use anyhow::{Result, anyhow};
-use log::debug;
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use serde::Serialize;
 use tapo::{
-    ApiClient, ColorLightHandler, LightHandler, PlugEnergyMonitoringHandler, PlugHandler,
-    PowerStripEnergyMonitoringHandler, PowerStripHandler, RgbLightStripHandler,
-    RgbicLightStripHandler,
+    ApiClient, ColorLightHandler, HubHandler, LightHandler, Plug, PlugEnergyMonitoringHandler,
+    PlugHandler, PowerStripEnergyMonitoringHandler, PowerStripHandler,
+    PowerStripPlugEnergyMonitoringHandler, RgbLightStripHandler, RgbicLightStripHandler,
+    TapoResponseError, responses::PowerStripPlugEnergyMonitoringResult,
+};
+use tokio::{
+    net::lookup_host,
+    sync::{Mutex, RwLock, RwLockWriteGuard},
 };
-use tokio::sync::RwLock;
 
 use crate::{
     config::{TapoConnectionInfos, TapoCredentials},
+    discovery::resolve_mac,
     server::TapoDeviceType,
 };
 
@@ -18,6 +30,9 @@ pub struct TapoDevice {
     conn_infos: TapoConnectionInfos,
     credentials: Arc<TapoCredentials>,
     client: RwLock<Option<TapoDeviceInner>>,
+    health: RwLock<DeviceHealth>,
+    /// Address last resolved from the device's host name or MAC address
+    resolved_ip_addr: RwLock<Option<IpAddr>>,
 }
 
 impl TapoDevice {
@@ -26,6 +41,8 @@ impl TapoDevice {
             conn_infos,
             credentials,
             client: RwLock::new(None),
+            health: RwLock::new(DeviceHealth::default()),
+            resolved_ip_addr: RwLock::new(None),
         }
     }
 
@@ -33,6 +50,18 @@ impl TapoDevice {
         &self.conn_infos
     }
 
+    pub async fn health(&self) -> DeviceHealth {
+        self.health.read().await.clone()
+    }
+
+    /// Address the device is reached at, either resolved from its host name or MAC address, or configured
+    pub async fn ip_addr(&self) -> Option<IpAddr> {
+        self.resolved_ip_addr
+            .read()
+            .await
+            .or(self.conn_infos.ip_addr)
+    }
+
     // pub async fn is_connected(&self) -> bool {
     //     self.client.read().await.is_some()
     // }
@@ -41,28 +70,76 @@ impl TapoDevice {
         self.with_client(async |_| {}).await
     }
 
-    pub async fn with_client<T>(&self, func: impl AsyncFnOnce(&TapoDeviceInner) -> T) -> Result<T> {
-        {
-            if let Some(conn) = &*self.client.read().await {
-                return Ok(func(conn).await);
+    pub async fn with_client<T: SessionExpiry>(
+        &self,
+        func: impl AsyncFnOnce(&TapoDeviceInner) -> T + Clone,
+    ) -> Result<T> {
+        let (expired, out) = match &*self.client.read().await {
+            Some(conn) => {
+                let out = func.clone()(conn).await;
+                (out.is_session_expired(), Some(out))
+            }
+
+            None => (false, None),
+        };
+
+        match out {
+            Some(out) if !expired => {
+                if out.is_connection_lost() {
+                    self.drop_client(self.client.write().await);
+                }
+
+                Ok(out)
+            }
+
+            _ => {
+                self.with_client_mut_inner(expired, async move |client| func(&*client).await)
+                    .await
             }
         }
+    }
 
-        self.with_client_mut(async move |client| func(&*client).await)
-            .await
+    pub async fn with_client_mut<T: SessionExpiry>(
+        &self,
+        func: impl AsyncFnOnce(&mut TapoDeviceInner) -> T + Clone,
+    ) -> Result<T> {
+        self.with_client_mut_inner(false, func).await
     }
 
-    pub async fn with_client_mut<T>(
+    async fn with_client_mut_inner<T: SessionExpiry>(
         &self,
-        func: impl AsyncFnOnce(&mut TapoDeviceInner) -> T,
+        expired: bool,
+        func: impl AsyncFnOnce(&mut TapoDeviceInner) -> T + Clone,
     ) -> Result<T> {
         let mut conn_lock = self.client.write().await;
 
-        if let Some(conn) = conn_lock.as_mut() {
-            return Ok(func(conn).await);
+        if let Some(conn) = conn_lock.as_mut().filter(|_| !expired) {
+            let out = func.clone()(conn).await;
+
+            if !out.is_session_expired() {
+                if out.is_connection_lost() {
+                    self.drop_client(conn_lock);
+                }
+
+                return Ok(out);
+            }
         }
 
-        let mut conn = self.establish_conn().await?;
+        // Either no connection was established yet, or the session expired
+        // In the latter case, drop the old client so it isn't used again if reconnecting fails
+        if conn_lock.take().is_some() {
+            warn!(
+                "Session with device '{}' expired, re-establishing connection...",
+                self.conn_infos.name
+            );
+        }
+
+        self.health.write().await.status = DeviceStatus::Reconnecting;
+
+        let conn = self.establish_conn().await;
+        self.record_health(conn.as_ref().map(|_| ())).await;
+
+        let mut conn = conn?;
 
         debug!(
             "Established a connection with device '{}'!",
@@ -70,10 +147,27 @@ impl TapoDevice {
         );
 
         let out = func(&mut conn).await;
-        *conn_lock = Some(conn);
+
+        if !out.is_connection_lost() {
+            *conn_lock = Some(conn);
+        }
+
         Ok(out)
     }
 
+    /// Drop the client after the device couldn't be reached
+    ///
+    /// The connection is then re-established on the next request, resolving the device's address again if needed
+    /// (e.g. after its DHCP lease expired), instead of reusing a client bound to an address that may be stale.
+    fn drop_client(&self, mut conn_lock: RwLockWriteGuard<'_, Option<TapoDeviceInner>>) {
+        if conn_lock.take().is_some() {
+            warn!(
+                "Lost connection with device '{}', it will be re-established on the next request",
+                self.conn_infos.name
+            );
+        }
+    }
+
     pub async fn refresh_session(&self) -> Result<()> {
         self.with_client_mut(async |conn| -> Result<()> {
             // Call '.refresh_session()' on the device client
@@ -92,7 +186,8 @@ impl TapoDevice {
                     L610, L630,
                     L900, L920, L930,
                     P100, P105, P110, P110M, P115,
-                    P300, P304, P304M, P316
+                    P300, P304, P304M, P316,
+                    H100, H200
             );
 
             Ok(())
@@ -100,11 +195,116 @@ impl TapoDevice {
         .await?
     }
 
+    /// Check if the device is still reachable, (re-)connecting to it if required
+    pub async fn probe(&self) -> Result<()> {
+        // Connection failures are recorded when establishing the connection
+        let result = self
+            .with_client(async |conn| conn.probe().await)
+            .await?
+            .map_err(anyhow::Error::from);
+
+        self.record_health(result.as_ref().copied()).await;
+
+        result
+    }
+
+    async fn record_health(&self, result: Result<(), &anyhow::Error>) {
+        let mut health = self.health.write().await;
+
+        match result {
+            Ok(()) => {
+                health.status = DeviceStatus::Connected;
+                health.last_error = None;
+                health.last_seen = Some(Utc::now());
+                health.consecutive_failures = 0;
+            }
+
+            Err(err) => {
+                health.status = DeviceStatus::Unreachable;
+                health.last_error = Some(format!("{err:#}"));
+                health.consecutive_failures += 1;
+            }
+        }
+    }
+
     async fn establish_conn(&self) -> Result<TapoDeviceInner> {
+        let TapoConnectionInfos { host, mac, .. } = &self.conn_infos;
+
+        if host.is_none() && mac.is_none() {
+            // Checked when loading the configuration
+            let ip_addr = self
+                .conn_infos
+                .ip_addr
+                .context("Device has no IP address")?;
+            return self.connect(ip_addr).await;
+        }
+
+        let ip_addr = match self.ip_addr().await {
+            Some(ip_addr) => ip_addr,
+            None => self.resolve_ip_addr(None).await?,
+        };
+
+        match self.connect(ip_addr).await {
+            Ok(conn) => Ok(conn),
+
+            // The device may have been assigned a new address, e.g. after its DHCP lease expired
+            Err(err) => {
+                debug!(
+                    "Failed to connect to device '{}' at {ip_addr}, re-resolving its address...",
+                    self.conn_infos.name
+                );
+
+                match self.resolve_ip_addr(Some(ip_addr)).await {
+                    Ok(new_ip_addr) if new_ip_addr != ip_addr => self.connect(new_ip_addr).await,
+                    _ => Err(err),
+                }
+            }
+        }
+    }
+
+    /// Resolve the device's address from its host name or MAC address, and cache it
+    ///
+    /// When both are provided, the MAC address is only used if the host name can't be resolved
+    /// or still points to the `stale` address.
+    async fn resolve_ip_addr(&self, stale: Option<IpAddr>) -> Result<IpAddr> {
+        let TapoConnectionInfos {
+            name, host, mac, ..
+        } = &self.conn_infos;
+
+        let resolved = match (host, mac) {
+            (Some(host), None) => resolve_host(host).await,
+
+            (Some(host), Some(mac)) => match resolve_host(host).await {
+                Ok(ip_addr) if Some(ip_addr) != stale => Ok(ip_addr),
+                Ok(_) => resolve_mac(mac, stale).await,
+                Err(err) => {
+                    debug!("{err:#}, falling back to the MAC address of device '{name}'");
+                    resolve_mac(mac, stale).await
+                }
+            },
+
+            (None, Some(mac)) => resolve_mac(mac, stale).await,
+
+            (None, None) => bail!("Device has neither a host name nor a MAC address"),
+        };
+
+        let ip_addr = resolved
+            .with_context(|| format!("Failed to resolve the IP address of device '{name}'"))?;
+
+        let mut resolved_ip_addr = self.resolved_ip_addr.write().await;
+
+        if *resolved_ip_addr != Some(ip_addr) {
+            info!("Resolved the address of device '{name}' to {ip_addr}");
+        }
+
+        *resolved_ip_addr = Some(ip_addr);
+
+        Ok(ip_addr)
+    }
+
+    async fn connect(&self, ip_addr: IpAddr) -> Result<TapoDeviceInner> {
         let TapoConnectionInfos {
-            name,
-            device_type,
-            ip_addr,
+            name, device_type, ..
         } = &self.conn_infos;
 
         let TapoCredentials { email, password } = &*self.credentials;
@@ -129,21 +329,48 @@ impl TapoDevice {
             TapoDeviceType::P110M => tapo_client.p110(ip_addr).await.map(TapoDeviceInner::P110M),
             TapoDeviceType::P115 => tapo_client.p115(ip_addr).await.map(TapoDeviceInner::P115),
             TapoDeviceType::P300 => tapo_client.p300(ip_addr).await.map(TapoDeviceInner::P300),
-            TapoDeviceType::P304 => tapo_client.p304(ip_addr).await.map(TapoDeviceInner::P304),
-            TapoDeviceType::P304M => tapo_client.p304(ip_addr).await.map(TapoDeviceInner::P304M),
-            TapoDeviceType::P316 => tapo_client.p316(ip_addr).await.map(TapoDeviceInner::P316),
+            TapoDeviceType::P304 => tapo_client
+                .p304(ip_addr)
+                .await
+                .map(|device| TapoDeviceInner::P304(device.into())),
+            TapoDeviceType::P304M => tapo_client
+                .p304(ip_addr)
+                .await
+                .map(|device| TapoDeviceInner::P304M(device.into())),
+            TapoDeviceType::P316 => tapo_client
+                .p316(ip_addr)
+                .await
+                .map(|device| TapoDeviceInner::P316(device.into())),
+            TapoDeviceType::H100 => tapo_client.h100(ip_addr).await.map(TapoDeviceInner::H100),
+            TapoDeviceType::H200 => tapo_client.h100(ip_addr).await.map(TapoDeviceInner::H200),
         };
 
         conn.map_err(|err| {
-            anyhow!(
-                "Failed to connect to {} {} '{name}': {err}",
+            anyhow::Error::new(err).context(format!(
+                "Failed to connect to {} {} '{name}'",
                 device_type.type_name(),
                 device_type.type_description()
-            )
+            ))
         })
     }
 }
 
+/// Resolve a host name (or an IP address) using the system's resolver
+async fn resolve_host(host: &str) -> Result<IpAddr> {
+    // The port is required by the resolver, but isn't used
+    let mut addrs = lookup_host((host, 80))
+        .await
+        .with_context(|| format!("Failed to resolve host name '{host}'"))?;
+
+    let addr = addrs
+        .next()
+        .with_context(|| format!("Host name '{host}' doesn't resolve to any address"))?;
+
+    debug!("Resolved host name '{host}' to {}", addr.ip());
+
+    Ok(addr.ip())
+}
+
 pub enum TapoDeviceInner {
     L510(LightHandler),
     L520(LightHandler),
@@ -160,12 +387,30 @@ pub enum TapoDeviceInner {
     P110M(PlugEnergyMonitoringHandler),
     P115(PlugEnergyMonitoringHandler),
     P300(PowerStripHandler),
-    P304(PowerStripEnergyMonitoringHandler),
-    P304M(PowerStripEnergyMonitoringHandler),
-    P316(PowerStripEnergyMonitoringHandler),
+    P304(EnergyMonitoringPowerStrip),
+    P304M(EnergyMonitoringPowerStrip),
+    P316(EnergyMonitoringPowerStrip),
+    H100(HubHandler),
+    H200(HubHandler),
 }
 
 impl TapoDeviceInner {
+    /// Fetch the device's informations to ensure it is still reachable
+    pub async fn probe(&self) -> Result<(), tapo::Error> {
+        macro_rules! probe {
+            ($($enum_variant: ident),+) => {{
+                match self {
+                    $(Self::$enum_variant(device) => device.get_device_info().await.map(|_| ()),)+
+                }
+            }}
+        }
+
+        probe!(
+            L510, L520, L530, L535, L610, L630, L900, L920, L930, P100, P105, P110, P110M, P115,
+            P300, P304, P304M, P316, H100, H200
+        )
+    }
+
     pub fn type_name(&self) -> &'static str {
         match self {
             TapoDeviceInner::L510(_) => "L510",
@@ -186,6 +431,323 @@ impl TapoDeviceInner {
             TapoDeviceInner::P304(_) => "P304",
             TapoDeviceInner::P304M(_) => "P304M",
             TapoDeviceInner::P316(_) => "P316",
+            TapoDeviceInner::H100(_) => "H100",
+            TapoDeviceInner::H200(_) => "H200",
+        }
+    }
+}
+
+/// Power strip whose sockets monitor their energy usage
+///
+/// Getting a handler for a socket fetches the list of all sockets, so handlers are kept for the whole session
+/// to avoid fetching it once per socket.
+pub struct EnergyMonitoringPowerStrip {
+    handler: PowerStripEnergyMonitoringHandler,
+    sockets: Mutex<HashMap<String, Arc<PowerStripPlugEnergyMonitoringHandler>>>,
+}
+
+impl EnergyMonitoringPowerStrip {
+    /// State of each socket, along with a handler to control it
+    pub async fn sockets(
+        &self,
+    ) -> Result<
+        Vec<(
+            PowerStripPlugEnergyMonitoringResult,
+            Arc<PowerStripPlugEnergyMonitoringHandler>,
+        )>,
+        tapo::Error,
+    > {
+        let children = self.handler.get_child_device_list().await?;
+
+        let mut handlers = self.sockets.lock().await;
+        handlers.retain(|device_id, _| children.iter().any(|child| &child.device_id == device_id));
+
+        let mut sockets = vec![];
+
+        for child in children {
+            let handler = if let Some(handler) = handlers.get(&child.device_id) {
+                Arc::clone(handler)
+            } else {
+                let handler = Arc::new(
+                    self.handler
+                        .plug(Plug::ByDeviceId(child.device_id.clone()))
+                        .await?,
+                );
+
+                handlers.insert(child.device_id.clone(), Arc::clone(&handler));
+                handler
+            };
+
+            sockets.push((child, handler));
+        }
+
+        Ok(sockets)
+    }
+}
+
+impl From<PowerStripEnergyMonitoringHandler> for EnergyMonitoringPowerStrip {
+    fn from(handler: PowerStripEnergyMonitoringHandler) -> Self {
+        Self {
+            handler,
+            sockets: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Deref for EnergyMonitoringPowerStrip {
+    type Target = PowerStripEnergyMonitoringHandler;
+
+    fn deref(&self) -> &Self::Target {
+        &self.handler
+    }
+}
+
+impl DerefMut for EnergyMonitoringPowerStrip {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.handler
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct DeviceHealth {
+    pub status: DeviceStatus,
+    pub last_error: Option<String>,
+    pub last_seen: Option<DateTime<Utc>>,
+    pub consecutive_failures: u32,
+}
+
+impl Default for DeviceHealth {
+    fn default() -> Self {
+        Self {
+            status: DeviceStatus::Reconnecting,
+            last_error: None,
+            last_seen: None,
+            consecutive_failures: 0,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceStatus {
+    /// The last connection attempt or probe succeeded
+    Connected,
+
+    /// A connection attempt is in progress
+    Reconnecting,
+
+    /// The last connection attempt or probe failed
+    Unreachable,
+}
+
+/// Results that may indicate the session with a device has expired, or that the device couldn't be reached
+///
+/// When the session expired, the connection is re-established and the action is retried once.
+/// When the device couldn't be reached, the connection is dropped so the next action re-establishes it.
+pub trait SessionExpiry {
+    fn is_session_expired(&self) -> bool;
+
+    fn is_connection_lost(&self) -> bool;
+}
+
+impl SessionExpiry for () {
+    fn is_session_expired(&self) -> bool {
+        false
+    }
+
+    fn is_connection_lost(&self) -> bool {
+        false
+    }
+}
+
+impl<T, E: SessionExpiry> SessionExpiry for Result<T, E> {
+    fn is_session_expired(&self) -> bool {
+        self.as_ref().is_err_and(E::is_session_expired)
+    }
+
+    fn is_connection_lost(&self) -> bool {
+        self.as_ref().is_err_and(E::is_connection_lost)
+    }
+}
+
+impl SessionExpiry for tapo::Error {
+    fn is_session_expired(&self) -> bool {
+        matches!(
+            self,
+            tapo::Error::Tapo(TapoResponseError::Unauthorized {
+                kind: "SESSION_TIMEOUT" | "SESSION_EXPIRED",
+                description: _
+            })
+        )
+    }
+
+    fn is_connection_lost(&self) -> bool {
+        // HTTP error statuses are reported as Tapo errors, so these are transport errors (e.g. connection or timeout)
+        matches!(self, tapo::Error::Http(_))
+    }
+}
+
+impl SessionExpiry for anyhow::Error {
+    fn is_session_expired(&self) -> bool {
+        self.downcast_ref::<tapo::Error>()
+            .is_some_and(tapo::Error::is_session_expired)
+    }
+
+    fn is_connection_lost(&self) -> bool {
+        self.downcast_ref::<tapo::Error>()
+            .is_some_and(tapo::Error::is_connection_lost)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unauthorized(kind: &'static str) -> tapo::Error {
+        tapo::Error::Tapo(TapoResponseError::Unauthorized {
+            kind,
+            description: "Unauthorized".to_owned(),
+        })
+    }
+
+    #[test]
+    fn expired_sessions_are_detected() {
+        assert!(unauthorized("SESSION_TIMEOUT").is_session_expired());
+        assert!(unauthorized("SESSION_EXPIRED").is_session_expired());
+    }
+
+    #[test]
+    fn other_errors_are_not_session_expiries() {
+        assert!(!unauthorized("HASH_MISMATCH").is_session_expired());
+        assert!(
+            !tapo::Error::Tapo(TapoResponseError::DeviceError {
+                code: -1012,
+                kind: "ERROR"
+            })
+            .is_session_expired()
+        );
+        assert!(
+            !tapo::Error::Validation {
+                field: "brightness".to_owned(),
+                message: "must be between 1 and 100".to_owned(),
+            }
+            .is_session_expired()
+        );
+        assert!(!tapo::Error::DeviceNotFound.is_session_expired());
+    }
+
+    #[test]
+    fn results_and_wrapped_errors() {
+        assert!(!Ok::<(), tapo::Error>(()).is_session_expired());
+        assert!(Err::<(), _>(unauthorized("SESSION_TIMEOUT")).is_session_expired());
+
+        let wrapped =
+            anyhow::Error::new(unauthorized("SESSION_EXPIRED")).context("Failed to turn on");
+        assert!(wrapped.is_session_expired());
+        assert!(Err::<(), _>(wrapped).is_session_expired());
+
+        assert!(!anyhow::anyhow!("Something else failed").is_session_expired());
+        assert!(!().is_session_expired());
+    }
+
+    async fn transport_error() -> tapo::Error {
+        // Nothing listens on this port, so connecting to it fails right away
+        reqwest::Client::new()
+            .get("http://127.0.0.1:1")
+            .send()
+            .await
+            .unwrap_err()
+            .into()
+    }
+
+    #[tokio::test]
+    async fn transport_errors_drop_the_connection() {
+        let err = transport_error().await;
+        assert!(err.is_connection_lost());
+        assert!(!err.is_session_expired());
+
+        let wrapped = anyhow::Error::new(transport_error().await).context("Failed to turn on");
+        assert!(wrapped.is_connection_lost());
+        assert!(Err::<(), _>(wrapped).is_connection_lost());
+    }
+
+    #[test]
+    fn device_errors_keep_the_connection() {
+        assert!(!unauthorized("SESSION_TIMEOUT").is_connection_lost());
+        assert!(
+            !tapo::Error::Tapo(TapoResponseError::HttpError {
+                status_code: 500,
+                description: "Internal Server Error".to_owned()
+            })
+            .is_connection_lost()
+        );
+        assert!(!tapo::Error::DeviceNotFound.is_connection_lost());
+        assert!(!Ok::<(), tapo::Error>(()).is_connection_lost());
+        assert!(!().is_connection_lost());
+    }
+
+    fn device(host: &str, mac: Option<&str>) -> TapoDevice {
+        TapoDevice::new(
+            TapoConnectionInfos {
+                name: "plug".to_owned(),
+                device_type: TapoDeviceType::P100,
+                ip_addr: None,
+                host: Some(host.to_owned()),
+                mac: mac.map(str::to_owned),
+            },
+            Arc::new(TapoCredentials {
+                email: "user@example.com".to_owned(),
+                password: "secret".to_owned(),
+            }),
+        )
+    }
+
+    #[tokio::test]
+    async fn host_name_is_resolved_and_cached() {
+        let device = device("localhost", None);
+        assert!(device.ip_addr().await.is_none());
+
+        let ip_addr = device.resolve_ip_addr(None).await.unwrap();
+
+        assert!(ip_addr.is_loopback());
+        assert_eq!(device.ip_addr().await, Some(ip_addr));
+    }
+
+    #[tokio::test]
+    async fn host_name_takes_precedence_over_mac_address() {
+        let device = device("127.0.0.1", Some("02:00:00:00:00:01"));
+
+        assert_eq!(
+            device.resolve_ip_addr(None).await.unwrap(),
+            IpAddr::from([127, 0, 0, 1])
+        );
+    }
+
+    #[tokio::test]
+    async fn unresolvable_host_name() {
+        let device = device("unknown.invalid", None);
+
+        let err = device.resolve_ip_addr(None).await.unwrap_err();
+
+        assert!(format!("{err:#}").contains("Failed to resolve host name 'unknown.invalid'"));
+        assert!(device.ip_addr().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn mac_address_used_when_host_name_fails_or_is_stale() {
+        let stale = IpAddr::from([127, 0, 0, 1]);
+
+        for (host, stale) in [("unknown.invalid", None), ("127.0.0.1", Some(stale))] {
+            let device = device(host, Some("02:00:00:00:00:01"));
+
+            // No device has this MAC address, so it is the failure of its resolution which is reported
+            let err = device.resolve_ip_addr(stale).await.unwrap_err();
+
+            assert!(
+                !format!("{err:#}").contains("Failed to resolve host name"),
+                "{err:#}"
+            );
+            assert!(device.ip_addr().await.is_none());
         }
     }
 }
@@ -0,0 +1,320 @@
+use std::net::IpAddr;
+
+use anyhow::{Context, Result, bail};
+use futures_util::StreamExt;
+use log::{debug, warn};
+use serde::Serialize;
+use serde_json::Value;
+use tapo::{ApiClient, DiscoveryRawResult};
+use tokio::fs;
+
+use crate::{config::TapoConnectionInfos, server::TapoDeviceType};
+
+/// Address the discovery probe is sent to by default, reaching all devices of the local network
+pub const DEFAULT_DISCOVERY_TARGET: &str = "255.255.255.255";
+
+/// Time to wait for devices to respond by default, in seconds
+pub const DEFAULT_DISCOVERY_TIMEOUT_SECS: u64 = 3;
+
+/// Path to the kernel's IPv4 neighbour (ARP) table
+const ARP_TABLE_PATH: &str = "/proc/net/arp";
+
+/// Device that responded to the discovery probe
+#[derive(Serialize, Clone)]
+pub struct DiscoveredDevice {
+    pub ip_addr: IpAddr,
+
+    /// Model reported by the device, including its region (e.g. `P110(EU)`)
+    pub model: Option<String>,
+
+    /// Type to use in the configuration, if the device is supported
+    pub device_type: Option<TapoDeviceType>,
+
+    /// MAC address, in the `AA:BB:CC:DD:EE:FF` format
+    pub mac: Option<String>,
+
+    /// Encryption scheme used by the device (`KLAP` or `AES`)
+    pub encryption: Option<String>,
+}
+
+impl DiscoveredDevice {
+    fn from_response(response: &DiscoveryRawResult) -> Self {
+        let result = response.message.get("result");
+
+        let field = |name: &str| {
+            result
+                .and_then(|result| result.get(name))
+                .and_then(Value::as_str)
+                .map(str::to_owned)
+        };
+
+        let model = field("device_model");
+
+        // Models are suffixed with their region, e.g. 'P110(EU)'
+        let device_type = model.as_deref().and_then(|model| {
+            let base = model.split('(').next().unwrap_or(model).trim();
+            serde_json::from_value(Value::String(base.to_owned())).ok()
+        });
+
+        let encryption = result
+            .and_then(|result| result.get("mgt_encrypt_schm"))
+            .and_then(|scheme| scheme.get("encrypt_type"))
+            .and_then(Value::as_str)
+            .map(str::to_owned);
+
+        Self {
+            ip_addr: response.ip,
+            model,
+            device_type,
+            mac: field("mac").map(|mac| normalize_mac(&mac)),
+            encryption,
+        }
+    }
+
+    /// Entry of the configuration's `devices` list for this device, if it is supported
+    pub fn config_entry(&self) -> Option<TapoConnectionInfos> {
+        let device_type = self.device_type?;
+
+        // Suffix names with the end of the MAC address to tell devices of a same model apart
+        let suffix = self.mac.as_deref().map_or_else(
+            || self.ip_addr.to_string().replace(['.', ':'], "-"),
+            |mac| {
+                let digits = mac.replace(':', "").to_lowercase();
+                digits[digits.len().saturating_sub(4)..].to_owned()
+            },
+        );
+
+        Some(TapoConnectionInfos {
+            name: format!("{}-{suffix}", device_type.type_name().to_lowercase()),
+            device_type,
+            ip_addr: Some(self.ip_addr),
+            host: None,
+            mac: self.mac.clone(),
+        })
+    }
+}
+
+/// Broadcast the Tapo discovery probe and collect the devices that respond to it
+///
+/// `target` may be a broadcast address to discover all devices of a network, or the address of a single device.
+pub async fn discover_devices(target: IpAddr, timeout_secs: u64) -> Result<Vec<DiscoveredDevice>> {
+    let mut responses = ApiClient::discover_devices_raw(target.to_string(), timeout_secs)
+        .await
+        .context("Failed to start discovery")?;
+
+    let mut devices = vec![];
+
+    while let Some(response) = responses.next().await {
+        match response {
+            Ok(response) => {
+                debug!("Discovered a device at {}", response.ip);
+                devices.push(DiscoveredDevice::from_response(&response));
+            }
+
+            Err(err) => warn!("! Failed to discover device at {}: {}", err.ip, err.source),
+        }
+    }
+
+    devices.sort_by_key(|device| device.ip_addr);
+
+    Ok(devices)
+}
+
+/// Normalize a MAC address to the `AA:BB:CC:DD:EE:FF` format
+pub fn normalize_mac(mac: &str) -> String {
+    mac.replace('-', ":").to_uppercase()
+}
+
+/// Validate a MAC address written as six pairs of hexadecimal digits, and normalize it
+pub fn parse_mac(mac: &str) -> Result<String> {
+    let mac = normalize_mac(mac);
+
+    let valid = mac.split(':').count() == 6
+        && mac
+            .split(':')
+            .all(|pair| pair.len() == 2 && pair.chars().all(|c| c.is_ascii_hexdigit()));
+
+    if !valid {
+        bail!("'{mac}' is not a valid MAC address (expected format: AA:BB:CC:DD:EE:FF)");
+    }
+
+    Ok(mac)
+}
+
+/// Find the current IP address of the device with the provided (normalized) MAC address
+///
+/// The neighbour table is looked up first, then a discovery probe is broadcast on the local network.
+/// `stale` is an address the device is known not to use anymore, which is ignored if still cached by the neighbour table.
+pub async fn resolve_mac(mac: &str, stale: Option<IpAddr>) -> Result<IpAddr> {
+    if let Some(ip_addr) = lookup_neighbour_table(mac)
+        .await
+        .into_iter()
+        .find(|ip_addr| Some(*ip_addr) != stale)
+    {
+        debug!("Resolved MAC address {mac} to {ip_addr} from the neighbour table");
+        return Ok(ip_addr);
+    }
+
+    let target = DEFAULT_DISCOVERY_TARGET.parse()?;
+
+    discover_devices(target, DEFAULT_DISCOVERY_TIMEOUT_SECS)
+        .await?
+        .into_iter()
+        .find(|device| device.mac.as_deref() == Some(mac))
+        .map(|device| {
+            debug!(
+                "Resolved MAC address {mac} to {} using discovery",
+                device.ip_addr
+            );
+            device.ip_addr
+        })
+        .with_context(|| format!("No device with MAC address {mac} was found on the local network"))
+}
+
+/// Addresses associated to a MAC address in the kernel's neighbour table, if it is available
+async fn lookup_neighbour_table(mac: &str) -> Vec<IpAddr> {
+    let Ok(table) = fs::read_to_string(ARP_TABLE_PATH).await else {
+        return vec![];
+    };
+
+    // Columns: IP address, HW type, Flags, HW address, Mask, Device
+    table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let columns = line.split_whitespace().collect::<Vec<_>>();
+
+            match columns.as_slice() {
+                // A '0x0' flag denotes an incomplete entry
+                [ip_addr, _, flags, hw_addr, ..]
+                    if *flags != "0x0" && normalize_mac(hw_addr) == mac =>
+                {
+                    ip_addr.parse().ok()
+                }
+
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn response(message: Value) -> DiscoveryRawResult {
+        DiscoveryRawResult {
+            ip: "192.168.1.42".parse().unwrap(),
+            message,
+        }
+    }
+
+    fn p110_response() -> DiscoveryRawResult {
+        response(json!({
+            "error_code": 0,
+            "result": {
+                "device_id": "8022A1B2C3D4",
+                "device_type": "SMART.TAPOPLUG",
+                "device_model": "P110(EU)",
+                "ip": "192.168.1.42",
+                "mac": "3c-52-a1-0b-7e-f4",
+                "mgt_encrypt_schm": { "is_support_https": false, "encrypt_type": "KLAP", "http_port": 80 }
+            }
+        }))
+    }
+
+    #[test]
+    fn parse_supported_device() {
+        let device = DiscoveredDevice::from_response(&p110_response());
+
+        assert_eq!(device.ip_addr.to_string(), "192.168.1.42");
+        assert_eq!(device.model.as_deref(), Some("P110(EU)"));
+        assert_eq!(
+            device.device_type.as_ref().map(TapoDeviceType::type_name),
+            Some("P110")
+        );
+        assert_eq!(device.mac.as_deref(), Some("3C:52:A1:0B:7E:F4"));
+        assert_eq!(device.encryption.as_deref(), Some("KLAP"));
+    }
+
+    #[test]
+    fn parse_unsupported_or_incomplete_responses() {
+        let device = DiscoveredDevice::from_response(&response(json!({
+            "result": { "device_model": "C200(EU)" }
+        })));
+
+        assert_eq!(device.model.as_deref(), Some("C200(EU)"));
+        assert!(device.device_type.is_none());
+        assert!(device.config_entry().is_none());
+
+        let device = DiscoveredDevice::from_response(&response(Value::Null));
+
+        assert!(device.model.is_none());
+        assert!(device.mac.is_none());
+        assert!(device.encryption.is_none());
+    }
+
+    #[test]
+    fn config_entry_named_after_the_mac_address() {
+        let entry = DiscoveredDevice::from_response(&p110_response())
+            .config_entry()
+            .unwrap();
+
+        assert_eq!(
+            serde_json::to_value(entry).unwrap(),
+            json!({
+                "name": "p110-7ef4",
+                "device_type": "P110",
+                "ip_addr": "192.168.1.42",
+                "mac": "3C:52:A1:0B:7E:F4"
+            })
+        );
+    }
+
+    #[test]
+    fn config_entry_named_after_the_address_without_mac() {
+        let mut device = DiscoveredDevice::from_response(&p110_response());
+        device.mac = None;
+
+        assert_eq!(device.config_entry().unwrap().name, "p110-192-168-1-42");
+    }
+
+    #[test]
+    fn mac_addresses_are_normalized() {
+        assert_eq!(normalize_mac("3c-52-a1-0b-7e-f4"), "3C:52:A1:0B:7E:F4");
+        assert_eq!(normalize_mac("3C:52:A1:0B:7E:F4"), "3C:52:A1:0B:7E:F4");
+    }
+
+    #[test]
+    fn valid_mac_addresses_are_normalized() {
+        assert_eq!(parse_mac("aa:bb:cc:dd:ee:ff").unwrap(), "AA:BB:CC:DD:EE:FF");
+        assert_eq!(parse_mac("01-23-45-67-89-Ab").unwrap(), "01:23:45:67:89:AB");
+        assert_eq!(parse_mac("01:23:45:67:89:AB").unwrap(), "01:23:45:67:89:AB");
+    }
+
+    #[test]
+    fn invalid_mac_addresses() {
+        for mac in [
+            "",
+            "AA:BB:CC:DD:EE",
+            "AA:BB:CC:DD:EE:FF:00",
+            "AA:BB:CC:DD:EE:F",
+            "AA:BB:CC:DD:EE:FFF",
+            "AA:BB:CC:DD:EE:GG",
+            "AABBCCDDEEFF",
+            "AA:BB:CC:DD::EE",
+        ] {
+            assert!(parse_mac(mac).is_err(), "{mac}");
+        }
+    }
+
+    #[test]
+    fn invalid_mac_error_shows_expected_format() {
+        assert_eq!(
+            parse_mac("aa:bb").unwrap_err().to_string(),
+            "'AA:BB' is not a valid MAC address (expected format: AA:BB:CC:DD:EE:FF)"
+        );
+    }
+}
@@ -33,18 +33,23 @@
     clippy::similar_names
 )]
 
-use std::process::ExitCode;
+use std::{net::IpAddr, process::ExitCode};
 
 use anyhow::{Result, bail};
-use log::{error, info};
+use log::{error, info, warn};
+use serde_json::json;
 
-use crate::cmd::Cmd;
+use crate::{
+    cmd::{Cmd, Command, DiscoverCmd},
+    discovery::{DiscoveredDevice, discover_devices},
+};
 
 use self::{logger::Logger, server::ServeOptions};
 
 mod cmd;
 mod config;
 mod devices;
+mod discovery;
 mod logger;
 mod server;
 
@@ -61,14 +66,24 @@ async fn main() -> ExitCode {
 
 async fn inner_main() -> Result<()> {
     let Cmd {
+        command,
         config_path,
         port,
+        watch_config,
         verbosity,
     } = argh::from_env::<Cmd>();
 
     // Set up the logger
     Logger::new(verbosity).init().unwrap();
 
+    if let Some(Command::Discover(DiscoverCmd { target, timeout })) = command {
+        return discover(target, timeout).await;
+    }
+
+    let (Some(config_path), Some(port)) = (config_path, port) else {
+        bail!("A configuration file and a port (--port) are required to run the server");
+    };
+
     if !config_path.is_file() {
         bail!(
             "Configuration was not found at path {}",
@@ -78,5 +93,47 @@ async fn inner_main() -> Result<()> {
 
     info!("Now launching server...");
 
-    server::serve(ServeOptions { config_path, port }).await
+    server::serve(ServeOptions {
+        config_path,
+        port,
+        watch_config,
+    })
+    .await
+}
+
+/// Discover devices on the local network, and print a configuration snippet for them
+async fn discover(target: IpAddr, timeout: u64) -> Result<()> {
+    info!("Discovering devices for {timeout} seconds...");
+
+    let devices = discover_devices(target, timeout).await?;
+
+    if devices.is_empty() {
+        warn!("No device responded to the discovery probe");
+        return Ok(());
+    }
+
+    for device in &devices {
+        let model = device.model.as_deref().unwrap_or("unknown model");
+        let mac = device.mac.as_deref().unwrap_or("unknown MAC");
+
+        match device.device_type {
+            Some(_) => info!("Found a {model} at {} ({mac})", device.ip_addr),
+            None => warn!(
+                "Found an unsupported {model} at {} ({mac}), it won't be included in the configuration",
+                device.ip_addr
+            ),
+        }
+    }
+
+    let entries = devices
+        .iter()
+        .filter_map(DiscoveredDevice::config_entry)
+        .collect::<Vec<_>>();
+
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&json!({ "devices": entries }))?
+    );
+
+    Ok(())
 }
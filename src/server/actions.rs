@@ -1,17 +1,41 @@
+use axum::{
+    Json, Router,
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::{Method, Uri},
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use tapo::Plug;
+
+use super::{ApiError, ApiResult, ErrorCode, SharedState, openapi::OpenApiBuilder};
+
+/// Features a device may support
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    OnOff,
+    Brightness,
+    Color,
+    ColorTemperature,
+    LightingEffects,
+    EnergyMonitoring,
+    SocketEnergyMonitoring,
+    ChildPlugs,
+    HubChildren,
+}
+
 macro_rules! build_router {
     (use mod { $($prelude:item)* }
-     $($device_name:ident $(,$alias_device_name:ident)* ($description: expr) {
-        $(async fn $action_name:ident(&$state_var:ident, &$client_var:ident $(,$(#[$param_meta:meta])? $param_name:ident : $param_type:ty)*) -> $ret_type:ty $fn_inner:block)+
+     $($device_name:ident $(,$alias_device_name:ident)* ($description: expr; $($capability:ident),*) {
+        $($http_method:ident async fn $action_name:ident(&$state_var:ident, &$client_var:ident $(,$(#[$param_meta:meta])? $param_name:ident : $param_type:ty)*) -> $ret_type:ty $fn_inner:block)+
     })+) => {
-        use axum::routing::{get, Router};
-        use serde::{Serialize, Deserialize};
-        use super::SharedState;
-
         mod prelude {
             $( $prelude )*
         }
 
-        #[derive(Serialize, Deserialize, Clone, Copy)]
+        #[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
         pub enum TapoDeviceType {
             $(
                 $device_name,
@@ -34,6 +58,34 @@ macro_rules! build_router {
                     $( Self::$device_name $(| Self::$alias_device_name)* => $description ),+
                 }
             }
+
+            pub fn capabilities(&self) -> &'static [Capability] {
+                match self {
+                    $( Self::$device_name $(| Self::$alias_device_name)* => &[$( Capability::$capability ),*] ),+
+                }
+            }
+
+            /// Whether the energy usage of the device can be retrieved, directly or by adding up its sockets'
+            pub fn monitors_energy(&self) -> bool {
+                self.capabilities().iter().any(|capability| {
+                    matches!(capability, Capability::EnergyMonitoring | Capability::SocketEnergyMonitoring)
+                })
+            }
+
+            /// HTTP method an action (in its URI form) is declared with, if this type of device supports it
+            pub fn action_method(&self, action: &str) -> Option<Method> {
+                match self {
+                    $( Self::$device_name $(| Self::$alias_device_name)* => {
+                        $(
+                            if action == stringify!($action_name).replace("_", "-") {
+                                return Some(::paste::paste! { Method::[<$http_method:upper>] });
+                            }
+                        )+
+
+                        None
+                    } ),+
+                }
+            }
         }
 
         pub fn make_actions_router() -> (Router<SharedState>, Vec<String>) {
@@ -47,9 +99,10 @@ macro_rules! build_router {
                 ] {
                     $(
                         let uri = format!("/{device_name}/{}", stringify!($action_name).replace("_", "-"));
-                        router = router.route(&uri, get(self::$device_name::$action_name));
+                        let handler = self::$device_name::$action_name;
+                        router = router.route(&uri, get(handler).post(handler));
 
-                        route_uris.push(uri);
+                        route_uris.push(format!("{} {uri}", ::paste::paste! { Method::[<$http_method:upper>] }));
                     )+
                 }
             )+
@@ -57,18 +110,107 @@ macro_rules! build_router {
             (router, route_uris)
         }
 
+        /// Describe all action routes in the `OpenAPI` specification
+        pub fn add_actions_to_openapi(spec: &mut OpenApiBuilder) {
+            use self::prelude::*;
+
+            $(
+                let device_names = [stringify!($device_name) $(, stringify!($alias_device_name))*];
+
+                $(
+                let params = [$( spec.param::<$param_type>(stringify!($param_name)) ),*];
+                let response = spec.response::<$ret_type>();
+                let method = ::paste::paste! { Method::[<$http_method:upper>] };
+                let action = stringify!($action_name).replace("_", "-");
+
+                for device_name in device_names {
+                    spec.add_action(
+                        &format!("/actions/{}/{action}", device_name.to_lowercase()),
+                        &method,
+                        &format!("Run '{action}' on a {device_name} {}", $description),
+                        false,
+                        &params,
+                        response.clone(),
+                    );
+                }
+
+                // Device-agnostic routes are shared by all device types supporting the action
+                spec.add_action(
+                    &format!("/devices/{{name}}/{action}"),
+                    &method,
+                    &format!("Run '{action}' on a device"),
+                    true,
+                    &params,
+                    // Responses of actions may differ from one type of device to another
+                    None,
+                );
+                )+
+            )+
+        }
+
+        /// Run an action on a device, without requiring the client to know its type
+        pub async fn run_device_action(
+            State(state): State<SharedState>,
+            Path((device, action)): Path<(String, String)>,
+            method: Method,
+            uri: Uri,
+            body: Bytes,
+        ) -> ApiResult<ActionOutput> {
+            dispatch_action(&state, &device, &action, &method, &uri, &body).await
+        }
+
+        /// Run the action with the provided name (in its URI form) on a device, based on its actual type
+        pub async fn dispatch_action(
+            state: &SharedState,
+            device: &str,
+            action: &str,
+            method: &Method,
+            uri: &Uri,
+            body: &[u8],
+        ) -> ApiResult<ActionOutput> {
+            let device_type = state
+                .devices
+                .read()
+                .await
+                .get(device)
+                .map(|device| device.conn_infos().device_type)
+                .ok_or_else(|| ApiError::device_not_found(device))?;
+
+            let output = match device_type {
+                $(
+                    TapoDeviceType::$device_name $(| TapoDeviceType::$alias_device_name)* => {
+                        self::$device_name::dispatch(state, device, action, method, uri, body).await
+                    }
+                )+
+            };
+
+            output.unwrap_or_else(|| {
+                Err(ApiError::new(
+                    ErrorCode::UnsupportedAction,
+                    format!(
+                        "Action '{action}' is not supported by {} {} devices",
+                        device_type.type_name(),
+                        device_type.type_description()
+                    ),
+                )
+                .with_device(device))
+            })
+        }
+
         $( #[allow(non_snake_case)]
            mod $device_name {
             use paste::paste;
             use serde::Deserialize;
             use axum::{
-                extract::{Query, State},
-                http::StatusCode,
+                body::Bytes,
+                extract::State,
+                http::{Method, Uri},
             };
             use crate::{
-                server::{ApiResult, ApiError, SharedState},
+                server::{ApiResult, ApiError, ErrorCode, SharedState},
                 devices::TapoDeviceInner
             };
+            use super::{check_method, parse_params, ActionOutput, IntoActionOutput};
 
             macro_rules! validate_client_type {
                 ($client: expr) => {
@@ -88,6 +230,38 @@ macro_rules! build_router {
                 $( stringify!($alias_device_name) ),*
             ];
 
+            fn wrong_device_type(type_name: &str) -> ApiError {
+                ApiError::new(
+                    ErrorCode::WrongDeviceType,
+                    format!(
+                        "This route is reserved to {} devices, but the provided name refers to a {type_name} device",
+                        DEVICE_NAME.join(", "),
+                    )
+                )
+            }
+
+            /// Run the action with the provided name (in its URI form) on a device
+            /// Returns `None` if this type of device does not support the action
+            pub(super) async fn dispatch(
+                state: &SharedState,
+                device: &str,
+                action: &str,
+                method: &Method,
+                uri: &Uri,
+                body: &[u8],
+            ) -> Option<ApiResult<ActionOutput>> {
+                $(
+                    if action == stringify!($action_name).replace("_", "-") {
+                        return Some(
+                            paste! { [<dispatch_ $action_name>](state, device, method, uri, body).await }
+                                .and_then(IntoActionOutput::into_output)
+                        );
+                    }
+                )+
+
+                None
+            }
+
             $(
                 paste! {
                     #[derive(Deserialize)]
@@ -95,51 +269,196 @@ macro_rules! build_router {
                         device: String,
                         $( $(#[$param_meta])? $param_name: $param_type ),*
                     }
+
+                    #[derive(Deserialize)]
+                    pub struct [<$action_name:camel Args>] {
+                        $( $(#[$param_meta])? $param_name: $param_type ),*
+                    }
                 }
 
                 pub(super) async fn $action_name(
-                    Query(query): Query<paste! { [<$action_name:camel Params>] }>,
-                    State(state): State<SharedState>
+                    State(state): State<SharedState>,
+                    method: Method,
+                    uri: Uri,
+                    body: Bytes,
                 ) -> ApiResult<$ret_type> {
-                    paste! { let [<$action_name:camel Params>] { device $(, $param_name)* } = query; };
-
-                    // TODO: session expiration, etc.?
-
-                    let devices = state.devices.read().await;
-
-                    let device = devices.get(&device).ok_or(ApiError::new(
-                        StatusCode::NOT_FOUND,
-                        "Provided device name was not found",
-                    ))?;
-
-                    #[allow(unused_variables)]
-                    let $state_var = &state;
-
-                    device
-                        .with_client(async move |client| {
-                            let client = validate_client_type!(client).ok_or_else(|| {
-                                ApiError::new(
-                                    StatusCode::BAD_REQUEST,
-                                    format!(
-                                        "This route is reserved to {} devices, but the provided name refers to a {} device",
-                                        DEVICE_NAME.join(", "),
-                                        client.type_name()
-                                    )
-                                )
-                            })?;
-
-                            let $client_var = client;
-
-                            $fn_inner
-                        })
-                        .await
-                        .map_err(ApiError::from)?
+                    check_method(&state, &method, &paste! { Method::[<$http_method:upper>] }).await?;
+
+                    paste! { let [<$action_name:camel Params>] { device $(, $param_name)* } = parse_params(&uri, &body)?; };
+
+                    paste! { [<run_ $action_name>](&state, &device $(, $param_name)*).await }
+                }
+
+                paste! {
+                    async fn [<dispatch_ $action_name>](
+                        state: &SharedState,
+                        device: &str,
+                        method: &Method,
+                        uri: &Uri,
+                        body: &[u8],
+                    ) -> ApiResult<$ret_type> {
+                        check_method(state, method, &Method::[<$http_method:upper>]).await?;
+
+                        let [<$action_name:camel Args>] { $($param_name),* } = parse_params(uri, body)?;
+
+                        [<run_ $action_name>](state, device $(, $param_name)*).await
+                    }
+                }
+
+                paste! {
+                    // Each parameter of the action is passed separately
+                    #[allow(clippy::too_many_arguments)]
+                    async fn [<run_ $action_name>](
+                        state: &SharedState,
+                        name: &str
+                        $(, $param_name: $param_type)*
+                    ) -> ApiResult<$ret_type> {
+                        let devices = state.devices.read().await;
+
+                        let device = devices.get(name).ok_or_else(|| ApiError::device_not_found(name))?;
+
+                        // Check the device's type before connecting to it
+                        let device_type = device.conn_infos().device_type.type_name();
+
+                        if !DEVICE_NAME.contains(&device_type) {
+                            return Err(wrong_device_type(device_type).with_device(name));
+                        }
+
+                        #[allow(unused_variables)]
+                        let $state_var = state;
+
+                        device
+                            .with_client(async move |client| {
+                                let client = validate_client_type!(client)
+                                    .ok_or_else(|| wrong_device_type(client.type_name()))?;
+
+                                let $client_var = client;
+
+                                $fn_inner
+                            })
+                            .await
+                            .map_err(ApiError::from)
+                            .flatten()
+                            .map_err(|err| err.with_device(name))
+                    }
                 }
             )+
         }) +
     };
 }
 
+/// Output of an action, independently of its type
+pub enum ActionOutput {
+    Empty,
+    Json(serde_json::Value),
+}
+
+impl ActionOutput {
+    pub fn into_value(self) -> Option<serde_json::Value> {
+        match self {
+            Self::Empty => None,
+            Self::Json(value) => Some(value),
+        }
+    }
+}
+
+impl IntoResponse for ActionOutput {
+    fn into_response(self) -> Response {
+        match self {
+            Self::Empty => ().into_response(),
+            Self::Json(value) => Json(value).into_response(),
+        }
+    }
+}
+
+/// Types that can be returned by an action
+pub trait IntoActionOutput {
+    fn into_output(self) -> ApiResult<ActionOutput>;
+}
+
+impl IntoActionOutput for () {
+    fn into_output(self) -> ApiResult<ActionOutput> {
+        Ok(ActionOutput::Empty)
+    }
+}
+
+impl<T: Serialize> IntoActionOutput for Json<T> {
+    fn into_output(self) -> ApiResult<ActionOutput> {
+        serde_json::to_value(self.0)
+            .map(ActionOutput::Json)
+            .map_err(|err| {
+                ApiError::new(
+                    ErrorCode::InternalError,
+                    format!("Failed to serialize action's output: {err}"),
+                )
+            })
+    }
+}
+
+/// Ensure an action is called with the HTTP method it was declared with
+///
+/// Actions changing a device's state may still be called with `GET` if legacy calls are allowed in the configuration
+pub async fn check_method(
+    state: &SharedState,
+    method: &Method,
+    expected: &Method,
+) -> ApiResult<()> {
+    if method == expected
+        || (*method == Method::GET && state.config.read().await.server.legacy_get_actions)
+    {
+        return Ok(());
+    }
+
+    Err(ApiError::new(
+        ErrorCode::MethodNotAllowed,
+        format!("This action must be called with the {expected} method"),
+    ))
+}
+
+/// Parse an action's parameters from the request's JSON body, or from its query string if the body is empty
+pub fn parse_params<T: DeserializeOwned>(uri: &Uri, body: &[u8]) -> ApiResult<T> {
+    if body.is_empty() {
+        Query::try_from_uri(uri)
+            .map(|Query(params)| params)
+            .map_err(|err| ApiError::new(ErrorCode::InvalidParameter, err.body_text()))
+    } else {
+        serde_json::from_slice(body).map_err(|err| {
+            ApiError::new(
+                ErrorCode::InvalidParameter,
+                format!("Failed to deserialize JSON body: {err}"),
+            )
+        })
+    }
+}
+
+/// Identify a power strip's socket from the parameters of an action, exactly one of which must be provided
+pub fn socket_identifier(
+    device_id: Option<String>,
+    nickname: Option<String>,
+    position: Option<u8>,
+) -> ApiResult<Plug> {
+    match (device_id, nickname, position) {
+        (Some(device_id), None, None) => Ok(Plug::ByDeviceId(device_id)),
+        (None, Some(nickname), None) => Ok(Plug::ByNickname(nickname)),
+        (None, None, Some(position)) => Ok(Plug::ByPosition(position)),
+        _ => Err(ApiError::new(
+            ErrorCode::InvalidParameter,
+            "Exactly one of 'device_id', 'nickname' or 'position' must be provided to identify the socket",
+        )),
+    }
+}
+
+/// Report child devices that couldn't be found with a dedicated error code
+pub fn child_device_error(err: tapo::Error) -> ApiError {
+    match err {
+        tapo::Error::DeviceNotFound => ApiError::new(
+            ErrorCode::ChildDeviceNotFound,
+            "No child device matches the provided identifier",
+        ),
+        err => err.into(),
+    }
+}
+
 build_router! {
     use mod {
         pub use axum::Json;
@@ -157,217 +476,599 @@ build_router! {
                 DeviceUsageEnergyMonitoringResult,
                 DeviceUsageResult,
                 EnergyUsageResult,
-                EnergyDataResult,
                 PowerStripPlugResult,
-                PowerStripPlugEnergyMonitoringResult
+                PowerStripPlugEnergyMonitoringResult,
+                DeviceInfoHubResult,
+                ChildDeviceHubResult,
+                TemperatureHumidityRecords,
+                TemperatureUnitKE100
             }
         };
         pub use chrono::NaiveDate;
+        pub use crate::server::{
+            actions::{child_device_error, socket_identifier},
+            energy::EnergyInterval,
+            export::{EnergyDataOutput, ExportFormat},
+            hubs::{TriggerLogs, get_hub_child, get_hub_trigger_logs, hub_child_identifier},
+        };
     }
 
-    L510, L520, L610 ("bulb") {
-        async fn on(&state, &client) -> () {
+    L510, L520, L610 ("bulb"; OnOff, Brightness) {
+        post async fn on(&state, &client) -> () {
             client.on().await.map_err(Into::into)
         }
 
-        async fn off(&state, &client) -> () {
+        post async fn off(&state, &client) -> () {
             client.off().await.map_err(Into::into)
         }
 
-        async fn set_brightness(&state, &client, level: u8) -> () {
+        post async fn set_brightness(&state, &client, level: u8) -> () {
             client.set_brightness(level).await.map_err(Into::into)
         }
 
-        async fn get_device_info(&state, &client) -> Json<DeviceInfoLightResult> {
+        get async fn get_device_info(&state, &client) -> Json<DeviceInfoLightResult> {
             Ok(Json(client.get_device_info().await?))
         }
 
-        async fn get_device_usage(&state, &client) -> Json<DeviceUsageEnergyMonitoringResult> {
+        get async fn get_device_usage(&state, &client) -> Json<DeviceUsageEnergyMonitoringResult> {
             Ok(Json(client.get_device_usage().await?))
         }
     }
 
-    L530, L535, L630 ("bulb") {
-        async fn on(&state, &client) -> () {
+    L530, L535, L630 ("bulb"; OnOff, Brightness, Color, ColorTemperature) {
+        post async fn on(&state, &client) -> () {
             client.on().await.map_err(Into::into)
         }
 
-        async fn off(&state, &client) -> () {
+        post async fn off(&state, &client) -> () {
             client.off().await.map_err(Into::into)
         }
 
-        async fn set_brightness(&state, &client, level: u8) -> () {
+        post async fn set_brightness(&state, &client, level: u8) -> () {
             client.set_brightness(level).await.map_err(Into::into)
         }
 
-        async fn set_color(&state, &client, color: Color) -> () {
+        post async fn set_color(&state, &client, color: Color) -> () {
             client.set_color(color).await.map_err(Into::into)
         }
 
-        async fn set_hue_saturation(&state, &client, hue: u16, saturation: u8) -> () {
+        post async fn set_hue_saturation(&state, &client, hue: u16, saturation: u8) -> () {
             client.set_hue_saturation(hue, saturation).await.map_err(Into::into)
         }
 
-        async fn set_color_temperature(&state, &client, color_temperature: u16) -> () {
+        post async fn set_color_temperature(&state, &client, color_temperature: u16) -> () {
             client.set_color_temperature(color_temperature).await.map_err(Into::into)
         }
 
-        async fn get_device_info(&state, &client) -> Json<DeviceInfoColorLightResult> {
+        get async fn get_device_info(&state, &client) -> Json<DeviceInfoColorLightResult> {
             Ok(Json(client.get_device_info().await?))
         }
 
-        async fn get_device_usage(&state, &client) -> Json<DeviceUsageEnergyMonitoringResult> {
+        get async fn get_device_usage(&state, &client) -> Json<DeviceUsageEnergyMonitoringResult> {
             Ok(Json(client.get_device_usage().await?))
         }
     }
 
-    L900 ("light strip") {
-        async fn on(&state, &client) -> () {
+    L900 ("light strip"; OnOff, Brightness, Color, ColorTemperature) {
+        post async fn on(&state, &client) -> () {
             client.on().await.map_err(Into::into)
         }
 
-        async fn off(&state, &client) -> () {
+        post async fn off(&state, &client) -> () {
             client.off().await.map_err(Into::into)
         }
 
-        async fn set_brightness(&state, &client, level: u8) -> () {
+        post async fn set_brightness(&state, &client, level: u8) -> () {
             client.set_brightness(level).await.map_err(Into::into)
         }
 
-        async fn set_color(&state, &client, color: Color) -> () {
+        post async fn set_color(&state, &client, color: Color) -> () {
             client.set_color(color).await.map_err(Into::into)
         }
 
-        async fn set_hue_saturation(&state, &client, hue: u16, saturation: u8) -> () {
+        post async fn set_hue_saturation(&state, &client, hue: u16, saturation: u8) -> () {
             client.set_hue_saturation(hue, saturation).await.map_err(Into::into)
         }
 
-        async fn set_color_temperature(&state, &client, color_temperature: u16) -> () {
+        post async fn set_color_temperature(&state, &client, color_temperature: u16) -> () {
             client.set_color_temperature(color_temperature).await.map_err(Into::into)
         }
 
-        async fn get_device_info(&state, &client) -> Json<DeviceInfoRgbLightStripResult> {
+        get async fn get_device_info(&state, &client) -> Json<DeviceInfoRgbLightStripResult> {
             Ok(Json(client.get_device_info().await?))
         }
 
-        async fn get_device_usage(&state, &client) -> Json<DeviceUsageEnergyMonitoringResult> {
+        get async fn get_device_usage(&state, &client) -> Json<DeviceUsageEnergyMonitoringResult> {
             Ok(Json(client.get_device_usage().await?))
         }
     }
 
-    L920, L930 ("light strip") {
-        async fn on(&state, &client) -> () {
+    L920, L930 ("light strip"; OnOff, Brightness, Color, ColorTemperature, LightingEffects) {
+        post async fn on(&state, &client) -> () {
             client.on().await.map_err(Into::into)
         }
 
-        async fn off(&state, &client) -> () {
+        post async fn off(&state, &client) -> () {
             client.off().await.map_err(Into::into)
         }
 
-        async fn set_brightness(&state, &client, level: u8) -> () {
+        post async fn set_brightness(&state, &client, level: u8) -> () {
             client.set_brightness(level).await.map_err(Into::into)
         }
 
-        async fn set_color(&state, &client, color: Color) -> () {
+        post async fn set_color(&state, &client, color: Color) -> () {
             client.set_color(color).await.map_err(Into::into)
         }
 
-        async fn set_hue_saturation(&state, &client, hue: u16, saturation: u8) -> () {
+        post async fn set_hue_saturation(&state, &client, hue: u16, saturation: u8) -> () {
             client.set_hue_saturation(hue, saturation).await.map_err(Into::into)
         }
 
-        async fn set_color_temperature(&state, &client, color_temperature: u16) -> () {
+        post async fn set_color_temperature(&state, &client, color_temperature: u16) -> () {
             client.set_color_temperature(color_temperature).await.map_err(Into::into)
         }
 
-        async fn set_lighting_effect(&state, &client, lighting_effect: LightingEffectPreset) -> () {
+        post async fn set_lighting_effect(&state, &client, lighting_effect: LightingEffectPreset) -> () {
             client.set_lighting_effect(lighting_effect).await.map_err(Into::into)
         }
 
-        async fn get_device_info(&state, &client) -> Json<DeviceInfoRgbicLightStripResult> {
+        get async fn get_device_info(&state, &client) -> Json<DeviceInfoRgbicLightStripResult> {
             Ok(Json(client.get_device_info().await?))
         }
 
-        async fn get_device_usage(&state, &client) -> Json<DeviceUsageEnergyMonitoringResult> {
+        get async fn get_device_usage(&state, &client) -> Json<DeviceUsageEnergyMonitoringResult> {
             Ok(Json(client.get_device_usage().await?))
         }
     }
 
-    P100, P105 ("plug") {
-        async fn on(&state, &client) -> () {
+    P100, P105 ("plug"; OnOff) {
+        post async fn on(&state, &client) -> () {
             client.on().await.map_err(Into::into)
         }
 
-        async fn off(&state, &client) -> () {
+        post async fn off(&state, &client) -> () {
             client.off().await.map_err(Into::into)
         }
 
-        async fn get_device_info(&state, &client) -> Json<DeviceInfoPlugResult> {
+        get async fn get_device_info(&state, &client) -> Json<DeviceInfoPlugResult> {
             Ok(Json(client.get_device_info().await?))
         }
 
-        async fn get_device_usage(&state, &client) -> Json<DeviceUsageResult> {
+        get async fn get_device_usage(&state, &client) -> Json<DeviceUsageResult> {
             Ok(Json(client.get_device_usage().await?))
         }
     }
 
-    P110, P110M, P115 ("plug") {
-        async fn on(&state, &client) -> () {
+    P110, P110M, P115 ("plug"; OnOff, EnergyMonitoring) {
+        post async fn on(&state, &client) -> () {
             client.on().await.map_err(Into::into)
         }
 
-        async fn off(&state, &client) -> () {
+        post async fn off(&state, &client) -> () {
             client.off().await.map_err(Into::into)
         }
 
-        async fn get_device_info(&state, &client) -> Json<DeviceInfoPlugEnergyMonitoringResult> {
+        get async fn get_device_info(&state, &client) -> Json<DeviceInfoPlugEnergyMonitoringResult> {
             Ok(Json(client.get_device_info().await?))
         }
 
-        async fn get_device_usage(&state, &client) -> Json<DeviceUsageEnergyMonitoringResult> {
+        get async fn get_device_usage(&state, &client) -> Json<DeviceUsageEnergyMonitoringResult> {
             Ok(Json(client.get_device_usage().await?))
         }
 
-        async fn get_energy_usage(&state, &client) -> Json<EnergyUsageResult> {
+        get async fn get_energy_usage(&state, &client) -> Json<EnergyUsageResult> {
             Ok(Json(client.get_energy_usage().await?))
         }
 
-        async fn get_hourly_energy_data(&state, &client, start_date: NaiveDate, end_date: Option<NaiveDate>) -> Json<EnergyDataResult> {
+        get async fn get_hourly_energy_data(&state, &client, start_date: NaiveDate, end_date: Option<NaiveDate>, format: Option<ExportFormat>) -> EnergyDataOutput {
             let end_date = end_date.unwrap_or(start_date);
+            let data = client.get_energy_data(EnergyDataInterval::Hourly { start_date, end_date }).await?;
 
-            Ok(Json(client.get_energy_data(EnergyDataInterval::Hourly { start_date, end_date }).await?))
+            Ok(EnergyDataOutput::new(data, EnergyInterval::Hourly, format))
         }
 
-        async fn get_daily_energy_data(&state, &client, start_date: NaiveDate) -> Json<EnergyDataResult> {
-            Ok(Json(client.get_energy_data(EnergyDataInterval::Daily { start_date }).await?))
+        get async fn get_daily_energy_data(&state, &client, start_date: NaiveDate, format: Option<ExportFormat>) -> EnergyDataOutput {
+            let data = client.get_energy_data(EnergyDataInterval::Daily { start_date }).await?;
+
+            Ok(EnergyDataOutput::new(data, EnergyInterval::Daily, format))
         }
 
-        async fn get_monthly_energy_data(&state, &client, start_date: NaiveDate) -> Json<EnergyDataResult> {
-            Ok(Json(client.get_energy_data(EnergyDataInterval::Monthly { start_date }).await?))
+        get async fn get_monthly_energy_data(&state, &client, start_date: NaiveDate, format: Option<ExportFormat>) -> EnergyDataOutput {
+            let data = client.get_energy_data(EnergyDataInterval::Monthly { start_date }).await?;
+
+            Ok(EnergyDataOutput::new(data, EnergyInterval::Monthly, format))
         }
 
-        async fn get_current_power(&state, &client) -> Json<CurrentPowerResult> {
+        get async fn get_current_power(&state, &client) -> Json<CurrentPowerResult> {
             Ok(Json(client.get_current_power().await?))
         }
     }
 
-    P300 ("power strip") {
-        async fn get_device_info(&state, &client) -> Json<DeviceInfoPowerStripResult> {
+    P300 ("power strip"; ChildPlugs) {
+        get async fn get_device_info(&state, &client) -> Json<DeviceInfoPowerStripResult> {
+            Ok(Json(client.get_device_info().await?))
+        }
+
+        get async fn get_child_device_list(&state, &client) -> Json<Vec<PowerStripPlugResult>> {
+            Ok(Json(client.get_child_device_list().await?))
+        }
+
+        post async fn socket_on(&state, &client, device_id: Option<String>, nickname: Option<String>, position: Option<u8>) -> () {
+            let socket = client.plug(socket_identifier(device_id, nickname, position)?).await.map_err(child_device_error)?;
+
+            socket.on().await.map_err(Into::into)
+        }
+
+        post async fn socket_off(&state, &client, device_id: Option<String>, nickname: Option<String>, position: Option<u8>) -> () {
+            let socket = client.plug(socket_identifier(device_id, nickname, position)?).await.map_err(child_device_error)?;
+
+            socket.off().await.map_err(Into::into)
+        }
+
+        get async fn get_socket_info(&state, &client, device_id: Option<String>, nickname: Option<String>, position: Option<u8>) -> Json<PowerStripPlugResult> {
+            let socket = client.plug(socket_identifier(device_id, nickname, position)?).await.map_err(child_device_error)?;
+
+            Ok(Json(socket.get_device_info().await?))
+        }
+    }
+
+    P304, P304M, P316 ("energy monitoring power strip"; ChildPlugs, SocketEnergyMonitoring) {
+        get async fn get_device_info(&state, &client) -> Json<DeviceInfoPowerStripResult> {
             Ok(Json(client.get_device_info().await?))
         }
 
-        async fn get_child_device_list(&state, &client) -> Json<Vec<PowerStripPlugResult>> {
+        get async fn get_child_device_list(&state, &client) -> Json<Vec<PowerStripPlugEnergyMonitoringResult>> {
             Ok(Json(client.get_child_device_list().await?))
         }
+
+        post async fn socket_on(&state, &client, device_id: Option<String>, nickname: Option<String>, position: Option<u8>) -> () {
+            let socket = client.plug(socket_identifier(device_id, nickname, position)?).await.map_err(child_device_error)?;
+
+            socket.on().await.map_err(Into::into)
+        }
+
+        post async fn socket_off(&state, &client, device_id: Option<String>, nickname: Option<String>, position: Option<u8>) -> () {
+            let socket = client.plug(socket_identifier(device_id, nickname, position)?).await.map_err(child_device_error)?;
+
+            socket.off().await.map_err(Into::into)
+        }
+
+        get async fn get_socket_info(&state, &client, device_id: Option<String>, nickname: Option<String>, position: Option<u8>) -> Json<PowerStripPlugEnergyMonitoringResult> {
+            let socket = client.plug(socket_identifier(device_id, nickname, position)?).await.map_err(child_device_error)?;
+
+            Ok(Json(socket.get_device_info().await?))
+        }
+
+        get async fn get_socket_device_usage(&state, &client, device_id: Option<String>, nickname: Option<String>, position: Option<u8>) -> Json<DeviceUsageEnergyMonitoringResult> {
+            let socket = client.plug(socket_identifier(device_id, nickname, position)?).await.map_err(child_device_error)?;
+
+            Ok(Json(socket.get_device_usage().await?))
+        }
+
+        get async fn get_socket_energy_usage(&state, &client, device_id: Option<String>, nickname: Option<String>, position: Option<u8>) -> Json<EnergyUsageResult> {
+            let socket = client.plug(socket_identifier(device_id, nickname, position)?).await.map_err(child_device_error)?;
+
+            Ok(Json(socket.get_energy_usage().await?))
+        }
+
+        get async fn get_socket_current_power(&state, &client, device_id: Option<String>, nickname: Option<String>, position: Option<u8>) -> Json<CurrentPowerResult> {
+            let socket = client.plug(socket_identifier(device_id, nickname, position)?).await.map_err(child_device_error)?;
+
+            Ok(Json(socket.get_current_power().await?))
+        }
+
+        get async fn get_socket_hourly_energy_data(&state, &client, device_id: Option<String>, nickname: Option<String>, position: Option<u8>, start_date: NaiveDate, end_date: Option<NaiveDate>, format: Option<ExportFormat>) -> EnergyDataOutput {
+            let socket = client.plug(socket_identifier(device_id, nickname, position)?).await.map_err(child_device_error)?;
+
+            let end_date = end_date.unwrap_or(start_date);
+            let data = socket.get_energy_data(EnergyDataInterval::Hourly { start_date, end_date }).await?;
+
+            Ok(EnergyDataOutput::new(data, EnergyInterval::Hourly, format))
+        }
+
+        get async fn get_socket_daily_energy_data(&state, &client, device_id: Option<String>, nickname: Option<String>, position: Option<u8>, start_date: NaiveDate, format: Option<ExportFormat>) -> EnergyDataOutput {
+            let socket = client.plug(socket_identifier(device_id, nickname, position)?).await.map_err(child_device_error)?;
+
+            let data = socket.get_energy_data(EnergyDataInterval::Daily { start_date }).await?;
+
+            Ok(EnergyDataOutput::new(data, EnergyInterval::Daily, format))
+        }
+
+        get async fn get_socket_monthly_energy_data(&state, &client, device_id: Option<String>, nickname: Option<String>, position: Option<u8>, start_date: NaiveDate, format: Option<ExportFormat>) -> EnergyDataOutput {
+            let socket = client.plug(socket_identifier(device_id, nickname, position)?).await.map_err(child_device_error)?;
+
+            let data = socket.get_energy_data(EnergyDataInterval::Monthly { start_date }).await?;
+
+            Ok(EnergyDataOutput::new(data, EnergyInterval::Monthly, format))
+        }
     }
 
-    P304, P304M, P316 ("energy monitoring power strip") {
-        async fn get_device_info(&state, &client) -> Json<DeviceInfoPowerStripResult> {
+    H100, H200 ("hub"; HubChildren) {
+        get async fn get_device_info(&state, &client) -> Json<DeviceInfoHubResult> {
             Ok(Json(client.get_device_info().await?))
         }
 
-        async fn get_child_device_list(&state, &client) -> Json<Vec<PowerStripPlugEnergyMonitoringResult>> {
+        get async fn get_child_device_list(&state, &client) -> Json<Vec<ChildDeviceHubResult>> {
             Ok(Json(client.get_child_device_list().await?))
         }
+
+        get async fn get_child_info(&state, &client, device_id: Option<String>, nickname: Option<String>) -> Json<ChildDeviceHubResult> {
+            Ok(Json(get_hub_child(client, hub_child_identifier(device_id, nickname)?).await?))
+        }
+
+        get async fn get_temperature_humidity_records(&state, &client, device_id: Option<String>, nickname: Option<String>) -> Json<TemperatureHumidityRecords> {
+            let sensor = client.t31x(hub_child_identifier(device_id, nickname)?).await.map_err(child_device_error)?;
+
+            Ok(Json(sensor.get_temperature_humidity_records().await?))
+        }
+
+        get async fn get_trigger_logs(&state, &client, device_id: Option<String>, nickname: Option<String>, page_size: Option<u64>, start_id: Option<u64>) -> Json<TriggerLogs> {
+            Ok(Json(get_hub_trigger_logs(client, hub_child_identifier(device_id, nickname)?, page_size, start_id).await?))
+        }
+
+        post async fn set_target_temperature(&state, &client, device_id: Option<String>, nickname: Option<String>, temperature: u8) -> () {
+            let valve = client.ke100(hub_child_identifier(device_id, nickname)?).await.map_err(child_device_error)?;
+
+            valve.set_target_temperature(temperature, TemperatureUnitKE100::Celsius).await.map_err(Into::into)
+        }
+
+        post async fn set_frost_protection(&state, &client, device_id: Option<String>, nickname: Option<String>, enabled: bool) -> () {
+            let valve = client.ke100(hub_child_identifier(device_id, nickname)?).await.map_err(child_device_error)?;
+
+            valve.set_frost_protection(enabled).await.map_err(Into::into)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::to_bytes, http::StatusCode};
+    use serde_json::json;
+
+    use super::*;
+    use crate::server::state::StateData;
+
+    async fn run(
+        state: &SharedState,
+        method: Method,
+        device: &str,
+        action: &str,
+        uri: &str,
+    ) -> (StatusCode, serde_json::Value) {
+        let response = run_device_action(
+            State(SharedState::clone(state)),
+            Path((device.to_owned(), action.to_owned())),
+            method,
+            Uri::try_from(uri).unwrap(),
+            Bytes::new(),
+        )
+        .await
+        .into_response();
+
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn unknown_device() {
+        let state = StateData::for_tests(json!({})).await;
+
+        let (status, body) = run(&state, Method::POST, "desk", "on", "/devices/desk/on").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["code"], "device_not_found");
+        assert_eq!(body["device"], "desk");
+    }
+
+    #[tokio::test]
+    async fn action_unsupported_by_device_type() {
+        let state = StateData::for_tests(json!({
+            "devices": [{ "name": "plug", "device_type": "P100", "ip_addr": "127.0.0.1" }]
+        }))
+        .await;
+
+        let (status, body) = run(
+            &state,
+            Method::POST,
+            "plug",
+            "set-brightness",
+            "/devices/plug/set-brightness?level=50",
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "unsupported_action");
+        assert_eq!(
+            body["detail"],
+            "Action 'set-brightness' is not supported by P100 plug devices"
+        );
+    }
+
+    #[test]
+    fn capabilities_follow_the_device_type() {
+        assert_eq!(
+            serde_json::to_value(TapoDeviceType::L530.capabilities()).unwrap(),
+            json!(["on_off", "brightness", "color", "color_temperature"])
+        );
+        assert_eq!(
+            serde_json::to_value(TapoDeviceType::P110M.capabilities()).unwrap(),
+            json!(["on_off", "energy_monitoring"])
+        );
+        assert!(
+            TapoDeviceType::L930
+                .capabilities()
+                .contains(&Capability::LightingEffects)
+        );
+        assert!(
+            !TapoDeviceType::P300
+                .capabilities()
+                .contains(&Capability::OnOff)
+        );
+    }
+
+    async fn legacy_state(legacy_get_actions: bool) -> SharedState {
+        StateData::for_tests(json!({
+            "server": {
+                "password": "secret",
+                "api_keys": [],
+                "legacy_get_actions": legacy_get_actions
+            }
+        }))
+        .await
+    }
+
+    fn status(result: ApiResult<()>) -> StatusCode {
+        result.map_or_else(|err| err.into_response().status(), |()| StatusCode::OK)
+    }
+
+    #[tokio::test]
+    async fn actions_require_their_declared_method() {
+        let state = legacy_state(false).await;
+
+        assert_eq!(
+            status(check_method(&state, &Method::POST, &Method::POST).await),
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(check_method(&state, &Method::GET, &Method::GET).await),
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(check_method(&state, &Method::GET, &Method::POST).await),
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+        assert_eq!(
+            status(check_method(&state, &Method::POST, &Method::GET).await),
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+    }
+
+    #[tokio::test]
+    async fn legacy_get_actions() {
+        let state = legacy_state(true).await;
+
+        assert_eq!(
+            status(check_method(&state, &Method::GET, &Method::POST).await),
+            StatusCode::OK
+        );
+
+        // Only allows GET in place of POST, not the other way around
+        assert_eq!(
+            status(check_method(&state, &Method::POST, &Method::GET).await),
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+        assert_eq!(
+            status(check_method(&state, &Method::PUT, &Method::POST).await),
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+    }
+
+    #[test]
+    fn action_methods_follow_the_device_type() {
+        assert_eq!(TapoDeviceType::L530.action_method("on"), Some(Method::POST));
+        assert_eq!(
+            TapoDeviceType::L530.action_method("set-brightness"),
+            Some(Method::POST)
+        );
+        assert_eq!(
+            TapoDeviceType::L530.action_method("get-device-info"),
+            Some(Method::GET)
+        );
+
+        // Actions are matched in their URI form, and only for devices supporting them
+        assert_eq!(TapoDeviceType::L530.action_method("set_brightness"), None);
+        assert_eq!(TapoDeviceType::P100.action_method("set-brightness"), None);
+        assert_eq!(TapoDeviceType::P100.action_method("dance"), None);
+    }
+
+    #[derive(Deserialize, PartialEq, Debug)]
+    struct Params {
+        level: u8,
+    }
+
+    #[test]
+    fn params_from_query_or_json_body() {
+        let uri = Uri::from_static("/devices/bulb/set-brightness?level=30");
+
+        assert_eq!(
+            parse_params::<Params>(&uri, b"").ok(),
+            Some(Params { level: 30 })
+        );
+
+        // The body takes precedence over the query string
+        assert_eq!(
+            parse_params::<Params>(&uri, br#"{"level": 60}"#).ok(),
+            Some(Params { level: 60 })
+        );
+    }
+
+    #[test]
+    fn invalid_params() {
+        let uri = Uri::from_static("/devices/bulb/set-brightness");
+
+        for body in [&b""[..], b"{", br#"{"level": "high"}"#] {
+            let err = parse_params::<Params>(&uri, body).err().unwrap();
+            assert_eq!(err.into_response().status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[test]
+    fn socket_identified_by_exactly_one_parameter() {
+        assert!(matches!(
+            socket_identifier(Some("8022".to_owned()), None, None),
+            Ok(Plug::ByDeviceId(device_id)) if device_id == "8022"
+        ));
+        assert!(matches!(
+            socket_identifier(None, Some("Fan".to_owned()), None),
+            Ok(Plug::ByNickname(nickname)) if nickname == "Fan"
+        ));
+        assert!(matches!(
+            socket_identifier(None, None, Some(2)),
+            Ok(Plug::ByPosition(2))
+        ));
+    }
+
+    #[test]
+    fn socket_identifier_is_required_and_unique() {
+        for (device_id, nickname, position) in [
+            (None, None, None),
+            (Some("8022"), Some("Fan"), None),
+            (None, Some("Fan"), Some(2)),
+            (Some("8022"), Some("Fan"), Some(2)),
+        ] {
+            let err = socket_identifier(
+                device_id.map(str::to_owned),
+                nickname.map(str::to_owned),
+                position,
+            )
+            .err()
+            .unwrap();
+
+            assert_eq!(err.into_response().status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    async fn error_code(err: ApiError) -> serde_json::Value {
+        let body = to_bytes(err.into_response().into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        serde_json::from_slice::<serde_json::Value>(&body).unwrap()["code"].take()
+    }
+
+    #[tokio::test]
+    async fn missing_child_devices_have_their_own_error() {
+        assert_eq!(
+            error_code(child_device_error(tapo::Error::DeviceNotFound)).await,
+            "child_device_not_found"
+        );
+        assert_eq!(
+            error_code(child_device_error(tapo::Error::Validation {
+                field: "position".to_owned(),
+                message: "out of range".to_owned(),
+            }))
+            .await,
+            "invalid_parameter"
+        );
     }
 }
@@ -2,7 +2,6 @@ use std::sync::Arc;
 
 use axum::{
     extract::{Request, State},
-    http::StatusCode,
     middleware::Next,
     response::Response,
 };
@@ -12,27 +11,40 @@ use axum_extra::{
 };
 use log::error;
 
-use super::{ApiError, state::StateData};
+use super::{ApiError, ErrorCode, state::StateData};
 
 // TODO: fail2ban? rate limiting?
 pub async fn auth_middleware(
     State(state): State<Arc<StateData>>,
-    TypedHeader(auth_header): TypedHeader<Authorization<Bearer>>,
+    auth_header: Option<TypedHeader<Authorization<Bearer>>>,
     request: Request,
     next: Next,
 ) -> Result<Response, ApiError> {
-    let api_key = auth_header.0.token();
+    let Some(TypedHeader(auth_header)) = auth_header else {
+        return Err(ApiError::new(
+            ErrorCode::MissingApiKey,
+            "Missing bearer token in the 'Authorization' header",
+        ));
+    };
 
-    let config = state.config.read().await;
+    let api_key = auth_header.token();
 
-    if !config
+    // Don't keep the configuration locked while handling the request, as the handler may need to modify it
+    let is_valid_key = state
+        .config
+        .read()
+        .await
         .server
         .api_keys
         .iter()
-        .any(|api_key_entry| api_key_entry.key == api_key)
-    {
+        .any(|api_key_entry| api_key_entry.key == api_key);
+
+    if !is_valid_key {
         error!("Provided invalid API key (bearer token): {api_key}");
-        return Err(ApiError::new(StatusCode::FORBIDDEN, "Invalid bearer token"));
+        return Err(ApiError::new(
+            ErrorCode::InvalidApiKey,
+            "Invalid bearer token",
+        ));
     }
 
     Ok(next.run(request).await)
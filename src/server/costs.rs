@@ -0,0 +1,742 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Result, bail};
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::{Method, Uri},
+};
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveDateTime, NaiveTime, Utc, Weekday};
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::json;
+use tokio::task::JoinSet;
+
+use crate::{
+    config::{Config, TariffDays, TariffPeriod, TariffPlan},
+    devices::TapoDeviceInner,
+};
+
+use super::{
+    ApiError, ApiResult, ErrorCode, SharedState,
+    actions::parse_params,
+    energy::{EnergyInterval, energy_requests, fetch_energy_data},
+    groups::ActionReport,
+    openapi::OpenApiBuilder,
+};
+
+/// Maximum number of days costs can be computed for at once
+const MAX_RANGE_DAYS: i64 = 92;
+
+/// Name of the rate used outside of any tariff period
+const STANDARD_RATE: &str = "standard";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CostInterval {
+    Daily,
+    Monthly,
+}
+
+#[derive(Deserialize)]
+pub struct CostParams {
+    /// First day to compute costs for, defaults to the first day of the current month
+    #[serde(default)]
+    from: Option<NaiveDate>,
+
+    /// Last day to compute costs for (inclusive), defaults to today
+    #[serde(default)]
+    to: Option<NaiveDate>,
+
+    /// Length of the periods costs are broken down into
+    #[serde(default)]
+    interval: Option<CostInterval>,
+}
+
+/// Energy used and its cost
+#[derive(Serialize, Clone, Copy, Default)]
+pub struct CostTotals {
+    /// Energy in watt-hours
+    energy: u64,
+    #[serde(serialize_with = "serialize_cost")]
+    cost: f64,
+}
+
+impl CostTotals {
+    fn add(&mut self, other: Self) {
+        self.energy += other.energy;
+        self.cost += other.cost;
+    }
+}
+
+#[derive(Serialize)]
+pub struct RateCosts {
+    rate: String,
+    #[serde(flatten)]
+    totals: CostTotals,
+}
+
+#[derive(Serialize)]
+pub struct PeriodCosts {
+    start: NaiveDate,
+    #[serde(flatten)]
+    totals: CostTotals,
+}
+
+/// Expected energy usage and cost at the end of the month, based on the usage so far
+#[derive(Serialize, Clone, Copy, Default)]
+pub struct CostProjection {
+    /// Energy in watt-hours
+    #[serde(serialize_with = "serialize_energy")]
+    energy: f64,
+    #[serde(serialize_with = "serialize_cost")]
+    cost: f64,
+}
+
+#[derive(Serialize)]
+pub struct DeviceCosts {
+    device: String,
+    tariff: String,
+    currency: String,
+    from: NaiveDate,
+    to: NaiveDate,
+    #[serde(flatten)]
+    totals: CostTotals,
+    /// Costs for each rate of the tariff plan
+    rates: Vec<RateCosts>,
+    /// Costs for each day or month
+    periods: Vec<PeriodCosts>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    projection: Option<CostProjection>,
+}
+
+#[derive(Serialize)]
+pub struct GroupCosts {
+    group: String,
+    currency: String,
+    from: NaiveDate,
+    to: NaiveDate,
+    /// Totals of the devices whose costs could be computed
+    #[serde(flatten)]
+    totals: CostTotals,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    projection: Option<CostProjection>,
+    #[serde(flatten)]
+    report: ActionReport,
+}
+
+/// Range of days costs are computed for
+#[derive(Clone, Copy)]
+struct CostRange {
+    from: NaiveDate,
+    to: NaiveDate,
+    interval: CostInterval,
+}
+
+impl CostRange {
+    fn from_uri(uri: &Uri) -> ApiResult<Self> {
+        let CostParams { from, to, interval } = parse_params(uri, &[])?;
+
+        let today = Local::now().date_naive();
+
+        let range = Self {
+            from: from.unwrap_or_else(|| month_start(today)),
+            to: to.unwrap_or(today),
+            interval: interval.unwrap_or(CostInterval::Daily),
+        };
+
+        if range.from > range.to {
+            return Err(ApiError::new(
+                ErrorCode::InvalidParameter,
+                "First day must not be after the last one",
+            ));
+        }
+
+        if (range.to - range.from).num_days() >= MAX_RANGE_DAYS {
+            return Err(ApiError::new(
+                ErrorCode::InvalidParameter,
+                format!("Costs can't be computed for more than {MAX_RANGE_DAYS} days at once"),
+            ));
+        }
+
+        Ok(range)
+    }
+
+    /// Check if the range covers the current month up to today
+    fn is_month_to_date(self) -> bool {
+        let today = Local::now().date_naive();
+        self.from == month_start(today) && self.to == today
+    }
+}
+
+/// Compute the energy costs of a device
+pub async fn get_device_costs(
+    State(state): State<SharedState>,
+    Path(device): Path<String>,
+    uri: Uri,
+) -> ApiResult<Json<DeviceCosts>> {
+    let range = CostRange::from_uri(&uri)?;
+
+    let device_type = state
+        .config
+        .read()
+        .await
+        .devices
+        .iter()
+        .find(|infos| infos.name == device)
+        .map(|infos| infos.device_type)
+        .ok_or_else(|| ApiError::device_not_found(&device))?;
+
+    if !device_type.monitors_energy() {
+        return Err(ApiError::new(
+            ErrorCode::UnsupportedAction,
+            format!(
+                "Energy costs are not supported by {} {} devices",
+                device_type.type_name(),
+                device_type.type_description()
+            ),
+        )
+        .with_device(&device));
+    }
+
+    compute_device_costs(&state, &device, range).await.map(Json)
+}
+
+/// Compute the energy costs of all energy-monitoring devices of a group
+pub async fn get_group_costs(
+    State(state): State<SharedState>,
+    Path(group): Path<String>,
+    uri: Uri,
+) -> ApiResult<Json<GroupCosts>> {
+    let range = CostRange::from_uri(&uri)?;
+
+    let (members, currency) = {
+        let config = state.config.read().await;
+
+        let group_devices = config
+            .groups
+            .iter()
+            .find(|candidate| candidate.name == group)
+            .ok_or_else(|| {
+                ApiError::new(
+                    ErrorCode::GroupNotFound,
+                    format!("Group '{group}' was not found"),
+                )
+            })?;
+
+        // Devices without energy monitoring don't consume anything as far as we know
+        let members = group_devices
+            .devices
+            .iter()
+            .filter(|device| {
+                config
+                    .devices
+                    .iter()
+                    .any(|infos| &infos.name == *device && infos.device_type.monitors_energy())
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let currency = config
+            .tariffs
+            .as_ref()
+            .map(|tariffs| tariffs.currency.clone())
+            .unwrap_or_default();
+
+        (members, currency)
+    };
+
+    if members.is_empty() {
+        return Err(ApiError::new(
+            ErrorCode::UnsupportedAction,
+            format!("Group '{group}' doesn't contain any energy-monitoring device"),
+        ));
+    }
+
+    let mut tasks = JoinSet::new();
+
+    for (index, device) in members.into_iter().enumerate() {
+        let state = SharedState::clone(&state);
+
+        tasks.spawn(async move {
+            let result = compute_device_costs(&state, &device, range).await;
+            (index, device, result)
+        });
+    }
+
+    let mut results = tasks.join_all().await;
+
+    // Keep the same order as in the group's configuration
+    results.sort_by_key(|(index, _, _)| *index);
+
+    let mut totals = CostTotals::default();
+    let mut projection = range.is_month_to_date().then(CostProjection::default);
+
+    for (_, _, result) in &results {
+        if let Ok(costs) = result {
+            totals.add(costs.totals);
+
+            if let (Some(projection), Some(device_projection)) = (&mut projection, costs.projection)
+            {
+                projection.energy += device_projection.energy;
+                projection.cost += device_projection.cost;
+            }
+        }
+    }
+
+    let report = ActionReport::from_results(results.into_iter().map(|(_, device, result)| {
+        let output = result.and_then(|costs| {
+            serde_json::to_value(costs)
+                .map(Some)
+                .map_err(|err| ApiError::new(ErrorCode::InternalError, format!("{err}")))
+        });
+
+        (device, output)
+    }));
+
+    Ok(Json(GroupCosts {
+        group,
+        currency,
+        from: range.from,
+        to: range.to,
+        totals,
+        projection,
+        report,
+    }))
+}
+
+async fn compute_device_costs(
+    state: &SharedState,
+    name: &str,
+    range: CostRange,
+) -> ApiResult<DeviceCosts> {
+    let (plan, currency) = {
+        let config = state.config.read().await;
+
+        let tariffs = config.tariffs.as_ref();
+
+        let plan = tariffs
+            .and_then(|tariffs| find_plan(&tariffs.plans, name))
+            .cloned()
+            .ok_or_else(|| {
+                ApiError::new(
+                    ErrorCode::TariffNotFound,
+                    "No tariff plan applies to device",
+                )
+                .with_device(name)
+            })?;
+
+        (plan, tariffs.map(|tariffs| tariffs.currency.clone()))
+    };
+
+    let device = state
+        .devices
+        .read()
+        .await
+        .get(name)
+        .cloned()
+        .ok_or_else(|| ApiError::device_not_found(name))?;
+
+    let entries = device
+        .with_client(async |client| fetch_hourly_energy(client, range.from, range.to).await)
+        .await
+        .map_err(ApiError::from)
+        .flatten()
+        .map_err(|err| err.with_device(name))?;
+
+    let mut totals = CostTotals::default();
+    let mut rates = Vec::<RateCosts>::new();
+    let mut periods = BTreeMap::<NaiveDate, CostTotals>::new();
+
+    for (start, energy) in entries {
+        let start = start.with_timezone(&Local).naive_local();
+        let (rate_name, rate) = rate_at(&plan, start);
+
+        let cost = CostTotals {
+            energy,
+            cost: energy_to_f64(energy) / 1000.0 * rate,
+        };
+
+        totals.add(cost);
+
+        match rates
+            .iter_mut()
+            .find(|candidate| candidate.rate == rate_name)
+        {
+            Some(rate) => rate.totals.add(cost),
+            None => rates.push(RateCosts {
+                rate: rate_name.to_owned(),
+                totals: cost,
+            }),
+        }
+
+        let period = match range.interval {
+            CostInterval::Daily => start.date(),
+            CostInterval::Monthly => month_start(start.date()),
+        };
+
+        periods.entry(period).or_default().add(cost);
+    }
+
+    Ok(DeviceCosts {
+        device: name.to_owned(),
+        tariff: plan.name,
+        currency: currency.unwrap_or_default(),
+        from: range.from,
+        to: range.to,
+        totals,
+        rates,
+        periods: periods
+            .into_iter()
+            .map(|(start, totals)| PeriodCosts { start, totals })
+            .collect(),
+        projection: if range.is_month_to_date() {
+            project_month(totals, Local::now().naive_local())
+        } else {
+            None
+        },
+    })
+}
+
+/// Fetch the hourly energy usage of a device between two days (inclusive)
+async fn fetch_hourly_energy(
+    client: &TapoDeviceInner,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> ApiResult<Vec<(DateTime<Utc>, u64)>> {
+    let now = Utc::now();
+    let mut entries = vec![];
+
+    for (start, end) in energy_requests(EnergyInterval::Hourly, from, to) {
+        entries.extend(
+            fetch_energy_data(client, EnergyInterval::Hourly, start, end)
+                .await?
+                .into_iter()
+                // Hours that didn't start yet are always empty
+                .filter(|entry| entry.start_date_time <= now)
+                .map(|entry| (entry.start_date_time, entry.energy)),
+        );
+    }
+
+    Ok(entries)
+}
+
+/// Find the tariff plan applying to a device
+fn find_plan<'a>(plans: &'a [TariffPlan], device: &str) -> Option<&'a TariffPlan> {
+    plans
+        .iter()
+        .find(|plan| plan.devices.iter().any(|candidate| candidate == device))
+        .or_else(|| plans.iter().find(|plan| plan.devices.is_empty()))
+}
+
+/// Get the rate applying at a given local time
+fn rate_at(plan: &TariffPlan, time: NaiveDateTime) -> (&str, f64) {
+    plan.periods
+        .iter()
+        .find(|period| period_contains(period, time))
+        .map_or((STANDARD_RATE, plan.rate), |period| {
+            (period.name.as_str(), period.rate)
+        })
+}
+
+fn period_contains(period: &TariffPeriod, time: NaiveDateTime) -> bool {
+    let is_weekend = matches!(time.weekday(), Weekday::Sat | Weekday::Sun);
+
+    let day_matches = match period.days {
+        TariffDays::All => true,
+        TariffDays::Weekdays => !is_weekend,
+        TariffDays::Weekends => is_weekend,
+    };
+
+    let TariffPeriod { start, end, .. } = *period;
+    let time = time.time();
+
+    day_matches
+        && match start.cmp(&end) {
+            std::cmp::Ordering::Equal => true,
+            std::cmp::Ordering::Less => start <= time && time < end,
+            // The period spans over midnight
+            std::cmp::Ordering::Greater => time >= start || time < end,
+        }
+}
+
+/// Extrapolate the usage of the current month so far to the whole month
+fn project_month(totals: CostTotals, now: NaiveDateTime) -> Option<CostProjection> {
+    let month_start = month_start(now.date());
+    let next_month_start = month_start.checked_add_months(chrono::Months::new(1))?;
+
+    let elapsed = (now - month_start.and_time(NaiveTime::MIN)).num_minutes();
+    let month = (next_month_start - month_start).num_minutes();
+
+    let elapsed = f64::from(i32::try_from(elapsed).ok()?.max(1));
+    let month = f64::from(i32::try_from(month).ok()?);
+
+    Some(CostProjection {
+        energy: energy_to_f64(totals.energy) * month / elapsed,
+        cost: totals.cost * month / elapsed,
+    })
+}
+
+fn month_start(date: NaiveDate) -> NaiveDate {
+    date.with_day(1).unwrap_or(date)
+}
+
+fn energy_to_f64(energy: u64) -> f64 {
+    // Energy usage over a few months can't realistically exceed what a `u32` can hold
+    f64::from(u32::try_from(energy).unwrap_or(u32::MAX))
+}
+
+#[allow(clippy::trivially_copy_pass_by_ref)] // Signature required by serde
+fn serialize_cost<S: Serializer>(cost: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64((cost * 10_000.0).round() / 10_000.0)
+}
+
+#[allow(clippy::trivially_copy_pass_by_ref)] // Signature required by serde
+fn serialize_energy<S: Serializer>(energy: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(energy.round())
+}
+
+/// Ensure tariff plans are valid, and that each device is billed with a single plan
+pub fn validate_tariffs(config: &Config) -> Result<()> {
+    let Some(tariffs) = &config.tariffs else {
+        return Ok(());
+    };
+
+    if tariffs.currency.is_empty() {
+        bail!("Tariffs' currency cannot be empty");
+    }
+
+    let is_valid_rate = |rate: f64| rate.is_finite() && rate >= 0.0;
+
+    for (i, plan) in tariffs.plans.iter().enumerate() {
+        let name = &plan.name;
+
+        if tariffs.plans[..i].iter().any(|other| &other.name == name) {
+            bail!("Tariff plan '{name}' is defined multiple times");
+        }
+
+        if plan.devices.is_empty()
+            && tariffs.plans[..i]
+                .iter()
+                .any(|other| other.devices.is_empty())
+        {
+            bail!(
+                "Tariff plan '{name}' doesn't list any device, but another plan already applies to all devices"
+            );
+        }
+
+        if !is_valid_rate(plan.rate) {
+            bail!("Tariff plan '{name}' has an invalid rate");
+        }
+
+        for period in &plan.periods {
+            if !is_valid_rate(period.rate) {
+                bail!(
+                    "Period '{}' of tariff plan '{name}' has an invalid rate",
+                    period.name
+                );
+            }
+        }
+
+        for device in &plan.devices {
+            let Some(infos) = config
+                .devices
+                .iter()
+                .find(|candidate| &candidate.name == device)
+            else {
+                bail!("Tariff plan '{name}' refers to unknown device '{device}'");
+            };
+
+            if !infos.device_type.monitors_energy() {
+                bail!(
+                    "Tariff plan '{name}' refers to device '{device}', which doesn't support energy monitoring"
+                );
+            }
+
+            if let Some(other) = tariffs.plans[..i]
+                .iter()
+                .find(|other| other.devices.contains(device))
+            {
+                bail!(
+                    "Device '{device}' is billed with both tariff plans '{}' and '{name}'",
+                    other.name
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Document the cost routes in the `OpenAPI` specification
+pub fn add_costs_to_openapi(spec: &mut OpenApiBuilder) {
+    let query_params = json!([
+        {
+            "name": "from",
+            "in": "query",
+            "required": false,
+            "description": "First day to compute costs for, defaults to the first day of the current month",
+            "schema": { "type": "string", "format": "date" }
+        },
+        {
+            "name": "to",
+            "in": "query",
+            "required": false,
+            "description": "Last day to compute costs for (inclusive), defaults to today",
+            "schema": { "type": "string", "format": "date" }
+        },
+        {
+            "name": "interval",
+            "in": "query",
+            "required": false,
+            "schema": { "type": "string", "enum": ["daily", "monthly"], "default": "daily" }
+        }
+    ]);
+
+    for (kind, summary, not_found) in [
+        (
+            "devices",
+            "Compute the energy costs of a device, broken down by rate and period",
+            "Device or tariff plan not found",
+        ),
+        (
+            "groups",
+            "Compute the energy costs of all energy-monitoring devices of a group",
+            "Group not found",
+        ),
+    ] {
+        let mut parameters = vec![json!({
+            "name": "name",
+            "in": "path",
+            "required": true,
+            "schema": { "type": "string" }
+        })];
+
+        parameters.extend(query_params.as_array().into_iter().flatten().cloned());
+
+        spec.add_route(
+            &format!("/costs/{kind}/{{name}}"),
+            &Method::GET,
+            json!({
+                "summary": summary,
+                "parameters": parameters,
+                "responses": {
+                    "200": { "description": "Success" },
+                    "400": OpenApiBuilder::error_response("Invalid parameter, or energy monitoring not supported"),
+                    "404": OpenApiBuilder::error_response(not_found)
+                }
+            }),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn period(start: &str, end: &str, days: TariffDays) -> TariffPeriod {
+        TariffPeriod {
+            name: "test".to_owned(),
+            rate: 0.2,
+            start: start.parse().unwrap(),
+            end: end.parse().unwrap(),
+            days,
+        }
+    }
+
+    /// 2024-06-14 is a Friday, 2024-06-15 a Saturday
+    fn at(date: &str, time: &str) -> NaiveDateTime {
+        format!("{date}T{time}").parse().unwrap()
+    }
+
+    #[test]
+    fn daytime_period() {
+        let day = period("07:00:00", "22:00:00", TariffDays::All);
+
+        assert!(!period_contains(&day, at("2024-06-14", "06:59:59")));
+        assert!(period_contains(&day, at("2024-06-14", "07:00:00")));
+        assert!(period_contains(&day, at("2024-06-14", "21:59:59")));
+        assert!(!period_contains(&day, at("2024-06-14", "22:00:00")));
+    }
+
+    #[test]
+    fn period_spanning_midnight() {
+        let night = period("22:00:00", "07:00:00", TariffDays::All);
+
+        assert!(period_contains(&night, at("2024-06-14", "22:00:00")));
+        assert!(period_contains(&night, at("2024-06-14", "23:59:59")));
+        assert!(period_contains(&night, at("2024-06-14", "00:00:00")));
+        assert!(period_contains(&night, at("2024-06-14", "06:59:59")));
+        assert!(!period_contains(&night, at("2024-06-14", "07:00:00")));
+        assert!(!period_contains(&night, at("2024-06-14", "12:00:00")));
+    }
+
+    #[test]
+    fn whole_day_period() {
+        let all_day = period("00:00:00", "00:00:00", TariffDays::All);
+
+        assert!(period_contains(&all_day, at("2024-06-14", "00:00:00")));
+        assert!(period_contains(&all_day, at("2024-06-14", "23:59:59")));
+    }
+
+    #[test]
+    fn weekday_and_weekend_periods() {
+        let weekdays = period("00:00:00", "00:00:00", TariffDays::Weekdays);
+        let weekends = period("00:00:00", "00:00:00", TariffDays::Weekends);
+
+        let friday = at("2024-06-14", "12:00:00");
+        let saturday = at("2024-06-15", "12:00:00");
+        let sunday = at("2024-06-16", "12:00:00");
+
+        assert!(period_contains(&weekdays, friday));
+        assert!(!period_contains(&weekdays, saturday));
+        assert!(!period_contains(&weekdays, sunday));
+
+        assert!(!period_contains(&weekends, friday));
+        assert!(period_contains(&weekends, saturday));
+        assert!(period_contains(&weekends, sunday));
+    }
+
+    #[test]
+    fn weekend_night_is_matched_by_its_own_day() {
+        let weekend_nights = period("22:00:00", "07:00:00", TariffDays::Weekends);
+
+        // The early hours of Monday belong to a weekday
+        assert!(period_contains(
+            &weekend_nights,
+            at("2024-06-16", "23:00:00")
+        ));
+        assert!(!period_contains(
+            &weekend_nights,
+            at("2024-06-17", "01:00:00")
+        ));
+    }
+
+    #[test]
+    fn month_projection() {
+        let totals = CostTotals {
+            energy: 1_000,
+            cost: 0.5,
+        };
+
+        // 10 days out of the 30 days of June
+        let projection = project_month(totals, at("2024-06-11", "00:00:00")).unwrap();
+        assert!((projection.energy - 3_000.0).abs() < 1e-6);
+        assert!((projection.cost - 1.5).abs() < 1e-6);
+
+        // 7 days out of the 28 days of February 2023
+        let projection = project_month(totals, at("2023-02-08", "00:00:00")).unwrap();
+        assert!((projection.energy - 4_000.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn month_projection_at_month_start() {
+        let totals = CostTotals {
+            energy: 10,
+            cost: 0.01,
+        };
+
+        // Less than a minute into the month is counted as one minute
+        let projection = project_month(totals, at("2024-01-01", "00:00:30")).unwrap();
+        assert!((projection.energy - 10.0 * 31.0 * 24.0 * 60.0).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,192 @@
+use std::net::IpAddr;
+
+use axum::{
+    Json,
+    extract::State,
+    http::{Method, Uri},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::discovery::{
+    DEFAULT_DISCOVERY_TARGET, DEFAULT_DISCOVERY_TIMEOUT_SECS, DiscoveredDevice, discover_devices,
+};
+
+use super::{
+    ApiError, ApiResult, ErrorCode, SharedState, actions::parse_params, openapi::OpenApiBuilder,
+};
+
+/// Longest time clients can wait for devices to respond, in seconds
+const MAX_DISCOVERY_TIMEOUT_SECS: u64 = 60;
+
+#[derive(Deserialize)]
+pub struct DiscoverParams {
+    #[serde(default)]
+    target: Option<IpAddr>,
+
+    #[serde(default)]
+    timeout: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct DiscoveredDeviceStatus {
+    #[serde(flatten)]
+    device: DiscoveredDevice,
+
+    /// Name of the configured device at the same address, if any
+    configured_as: Option<String>,
+}
+
+/// Discover the devices of the local network
+pub async fn discover(
+    State(state): State<SharedState>,
+    uri: Uri,
+) -> ApiResult<Json<Vec<DiscoveredDeviceStatus>>> {
+    let DiscoverParams { target, timeout } = parse_params(&uri, &[])?;
+
+    let target = target.unwrap_or_else(|| DEFAULT_DISCOVERY_TARGET.parse().unwrap());
+    let timeout = timeout.unwrap_or(DEFAULT_DISCOVERY_TIMEOUT_SECS);
+
+    if !(1..=MAX_DISCOVERY_TIMEOUT_SECS).contains(&timeout) {
+        return Err(ApiError::new(
+            ErrorCode::InvalidParameter,
+            format!("Timeout must be between 1 and {MAX_DISCOVERY_TIMEOUT_SECS} seconds"),
+        ));
+    }
+
+    let devices = discover_devices(target, timeout).await?;
+
+    // Compare with the address devices are currently reached at, which may have been resolved
+    let mut configured = vec![];
+
+    for (name, device) in state.devices.read().await.iter() {
+        configured.push((
+            name.clone(),
+            device.conn_infos().mac.clone(),
+            device.ip_addr().await,
+        ));
+    }
+
+    let devices = devices
+        .into_iter()
+        .map(|device| DiscoveredDeviceStatus {
+            configured_as: configured
+                .iter()
+                .find(|(_, mac, ip_addr)| match (mac, &device.mac) {
+                    (Some(mac), Some(discovered)) => mac == discovered,
+                    _ => *ip_addr == Some(device.ip_addr),
+                })
+                .map(|(name, _, _)| name.clone()),
+            device,
+        })
+        .collect();
+
+    Ok(Json(devices))
+}
+
+/// Document the discovery route in the `OpenAPI` specification
+pub fn add_discover_to_openapi(spec: &mut OpenApiBuilder) {
+    spec.add_route(
+        "/discover",
+        &Method::GET,
+        json!({
+            "summary": "Discover the Tapo devices of the local network",
+            "parameters": [
+                {
+                    "name": "target",
+                    "in": "query",
+                    "required": false,
+                    "description": "Address to send the discovery probe to, defaults to the broadcast address",
+                    "schema": { "type": "string", "default": DEFAULT_DISCOVERY_TARGET }
+                },
+                {
+                    "name": "timeout",
+                    "in": "query",
+                    "required": false,
+                    "description": "Time to wait for devices to respond, in seconds",
+                    "schema": {
+                        "type": "integer",
+                        "minimum": 1,
+                        "maximum": MAX_DISCOVERY_TIMEOUT_SECS,
+                        "default": DEFAULT_DISCOVERY_TIMEOUT_SECS
+                    }
+                }
+            ],
+            "responses": {
+                "200": { "description": "Success" },
+                "400": OpenApiBuilder::error_response("Invalid parameter")
+            }
+        }),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::UdpSocket;
+
+    use super::*;
+    use crate::server::state::StateData;
+
+    async fn run(state: &SharedState, uri: &'static str) -> ApiResult<serde_json::Value> {
+        let Json(devices) =
+            discover(State(SharedState::clone(state)), Uri::from_static(uri)).await?;
+
+        Ok(serde_json::to_value(devices).unwrap())
+    }
+
+    #[tokio::test]
+    async fn timeout_must_be_in_range() {
+        let state = StateData::for_tests(json!({})).await;
+
+        for uri in ["/discover?timeout=0", "/discover?timeout=61"] {
+            assert!(run(&state, uri).await.is_err(), "{uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn discover_device_on_loopback() {
+        // Answer the probe like a device would, with a 16-byte header followed by the JSON payload
+        let socket = UdpSocket::bind("127.0.0.1:20002").await.unwrap();
+
+        tokio::spawn(async move {
+            let mut probe = [0; 2048];
+            let (_, addr) = socket.recv_from(&mut probe).await.unwrap();
+
+            let mut response = vec![0; 16];
+            response.extend(
+                json!({
+                    "error_code": 0,
+                    "result": {
+                        "device_model": "L530(EU)",
+                        "mac": "3C-52-A1-0B-7E-F4",
+                        "mgt_encrypt_schm": { "encrypt_type": "KLAP" }
+                    }
+                })
+                .to_string()
+                .into_bytes(),
+            );
+
+            socket.send_to(&response, addr).await.unwrap();
+        });
+
+        let state = StateData::for_tests(json!({
+            "devices": [{ "name": "bulb", "device_type": "L530", "ip_addr": "127.0.0.1" }]
+        }))
+        .await;
+
+        assert_eq!(
+            run(&state, "/discover?target=127.0.0.1&timeout=5")
+                .await
+                .ok()
+                .unwrap(),
+            json!([{
+                "ip_addr": "127.0.0.1",
+                "model": "L530(EU)",
+                "device_type": "L530",
+                "mac": "3C:52:A1:0B:7E:F4",
+                "encryption": "KLAP",
+                "configured_as": "bulb"
+            }])
+        );
+    }
+}
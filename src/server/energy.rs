@@ -0,0 +1,847 @@
+use std::{
+    path::Path as FsPath,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::{Method, Uri},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Datelike, Days, Local, Months, NaiveDate, TimeDelta, Utc};
+use log::{debug, warn};
+use rusqlite::{Connection, params};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tapo::{requests::EnergyDataInterval, responses::EnergyDataIntervalResult};
+use tokio::task::JoinSet;
+
+use crate::devices::{DeviceStatus, TapoDeviceInner};
+
+use super::{
+    ApiError, ApiResult, ErrorCode, SharedState,
+    actions::parse_params,
+    export::{EnergyRow, ExportFormat},
+    openapi::OpenApiBuilder,
+};
+
+/// Number of days of hourly energy data the Tapo API returns at once
+const HOURLY_DATA_MAX_DAYS: u64 = 8;
+
+/// Number of days of hourly data fetched at each collection, to fill gaps if the server was stopped
+const HOURLY_LOOKBACK_DAYS: u64 = HOURLY_DATA_MAX_DAYS - 1;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EnergyInterval {
+    Hourly,
+    Daily,
+    Monthly,
+}
+
+impl EnergyInterval {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Hourly => "hourly",
+            Self::Daily => "daily",
+            Self::Monthly => "monthly",
+        }
+    }
+
+    /// End of the period starting at the provided time, accounting for month lengths and DST changes
+    pub fn period_end(self, start: DateTime<Utc>) -> DateTime<Utc> {
+        let start_local = start.with_timezone(&Local);
+
+        let end = match self {
+            Self::Hourly => return start + TimeDelta::hours(1),
+            Self::Daily => start_local.checked_add_days(Days::new(1)),
+            Self::Monthly => start_local.checked_add_months(Months::new(1)),
+        };
+
+        end.map_or(start, |end| end.with_timezone(&Utc))
+    }
+
+    /// First day of the request returning the data of the provided day
+    ///
+    /// Daily data is returned a quarter at once, and monthly data a year at once.
+    pub fn request_start(self, date: NaiveDate) -> NaiveDate {
+        match self {
+            Self::Hourly => date,
+            Self::Daily => {
+                NaiveDate::from_ymd_opt(date.year(), date.month0() / 3 * 3 + 1, 1).unwrap_or(date)
+            }
+            Self::Monthly => NaiveDate::from_ymd_opt(date.year(), 1, 1).unwrap_or(date),
+        }
+    }
+
+    /// Request for the energy data starting on a day, the end day (inclusive) only being used for hourly data
+    fn request(self, start_date: NaiveDate, end_date: NaiveDate) -> EnergyDataInterval {
+        match self {
+            Self::Hourly => EnergyDataInterval::Hourly {
+                start_date,
+                end_date,
+            },
+            Self::Daily => EnergyDataInterval::Daily { start_date },
+            Self::Monthly => EnergyDataInterval::Monthly { start_date },
+        }
+    }
+
+    /// Range of history returned when no start date is provided
+    fn default_range(self) -> TimeDelta {
+        match self {
+            Self::Hourly => TimeDelta::days(1),
+            Self::Daily => TimeDelta::days(30),
+            Self::Monthly => TimeDelta::days(365),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct EnergyEntry {
+    start: DateTime<Utc>,
+    /// Energy used in watt-hours
+    energy: u64,
+}
+
+#[derive(Serialize)]
+pub struct PowerReading {
+    timestamp: DateTime<Utc>,
+    /// Power in watts
+    power: u64,
+}
+
+/// Long-term storage of energy data, in an `SQLite` database
+pub struct EnergyHistory {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl EnergyHistory {
+    pub fn open(path: &FsPath) -> Result<Self> {
+        let connection = Connection::open(path).with_context(|| {
+            format!(
+                "Failed to open energy history database at '{}'",
+                path.display()
+            )
+        })?;
+
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS energy (
+                    device TEXT NOT NULL,
+                    interval TEXT NOT NULL,
+                    start INTEGER NOT NULL,
+                    energy INTEGER NOT NULL,
+                    PRIMARY KEY (device, interval, start)
+                ) WITHOUT ROWID;
+
+                CREATE TABLE IF NOT EXISTS power (
+                    device TEXT NOT NULL,
+                    timestamp INTEGER NOT NULL,
+                    power INTEGER NOT NULL,
+                    PRIMARY KEY (device, timestamp)
+                ) WITHOUT ROWID;",
+            )
+            .context("Failed to initialize energy history database")?;
+
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Run a query without blocking the runtime
+    async fn run<T: Send + 'static>(
+        &self,
+        query: impl FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let connection = Arc::clone(&self.connection);
+
+        tokio::task::spawn_blocking(move || query(&mut connection.lock().unwrap()))
+            .await
+            .context("Energy history query panicked")?
+            .context("Energy history query failed")
+    }
+
+    async fn record(
+        &self,
+        device: String,
+        power: PowerReading,
+        energy: Vec<(EnergyInterval, Vec<EnergyEntry>)>,
+    ) -> Result<()> {
+        self.run(move |connection| {
+            let transaction = connection.transaction()?;
+
+            transaction.execute(
+                "INSERT OR REPLACE INTO power (device, timestamp, power) VALUES (?1, ?2, ?3)",
+                params![device, power.timestamp.timestamp(), power.power],
+            )?;
+
+            for (interval, entries) in energy {
+                for entry in entries {
+                    // Entries of the ongoing period are updated until it ends
+                    transaction.execute(
+                        "INSERT OR REPLACE INTO energy (device, interval, start, energy) VALUES (?1, ?2, ?3, ?4)",
+                        params![device, interval.name(), entry.start.timestamp(), entry.energy],
+                    )?;
+                }
+            }
+
+            transaction.commit()
+        })
+        .await
+    }
+
+    async fn energy(
+        &self,
+        device: String,
+        interval: EnergyInterval,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<EnergyEntry>> {
+        self.run(move |connection| {
+            connection
+                .prepare(
+                    "SELECT start, energy FROM energy
+                    WHERE device = ?1 AND interval = ?2 AND start >= ?3 AND start < ?4
+                    ORDER BY start",
+                )?
+                .query_map(
+                    params![device, interval.name(), from.timestamp(), to.timestamp()],
+                    |row| {
+                        Ok(EnergyEntry {
+                            start: DateTime::from_timestamp(row.get(0)?, 0).unwrap_or_default(),
+                            energy: row.get(1)?,
+                        })
+                    },
+                )?
+                .collect()
+        })
+        .await
+    }
+
+    async fn power(
+        &self,
+        device: String,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<PowerReading>> {
+        self.run(move |connection| {
+            connection
+                .prepare(
+                    "SELECT timestamp, power FROM power
+                    WHERE device = ?1 AND timestamp >= ?2 AND timestamp < ?3
+                    ORDER BY timestamp",
+                )?
+                .query_map(params![device, from.timestamp(), to.timestamp()], |row| {
+                    Ok(PowerReading {
+                        timestamp: DateTime::from_timestamp(row.get(0)?, 0).unwrap_or_default(),
+                        power: row.get(1)?,
+                    })
+                })?
+                .collect()
+        })
+        .await
+    }
+}
+
+/// Periodically store the energy data of all energy-monitoring devices
+pub async fn collect_energy_history(state: SharedState) {
+    let Some(history) = &state.energy_history else {
+        return;
+    };
+
+    loop {
+        // Stop collecting if the history was disabled when reloading the configuration
+        let Some(collect_interval) = state
+            .config
+            .read()
+            .await
+            .energy_history
+            .as_ref()
+            .map(|config| config.collect_interval_secs)
+        else {
+            return;
+        };
+
+        // Don't keep the devices locked while collecting, as it may take a while
+        let devices = state
+            .devices
+            .read()
+            .await
+            .values()
+            .filter(|device| device.conn_infos().device_type.monitors_energy())
+            .map(Arc::clone)
+            .collect::<Vec<_>>();
+
+        let mut tasks = JoinSet::new();
+
+        for device in devices {
+            tasks.spawn(async move {
+                // Unreachable devices are reconnected to by the supervisor, with a backoff
+                if device.health().await.status == DeviceStatus::Unreachable {
+                    return None;
+                }
+
+                let result = device
+                    .with_client(async |client| fetch_energy(client).await)
+                    .await
+                    .and_then(|result| result.map_err(anyhow::Error::from));
+
+                Some((device.conn_infos().name.clone(), result))
+            });
+        }
+
+        for (device, result) in tasks.join_all().await.into_iter().flatten() {
+            let result = match result {
+                Ok(Some((power, energy))) => history.record(device.clone(), power, energy).await,
+                Ok(None) => continue,
+                Err(err) => Err(err),
+            };
+
+            match result {
+                Ok(()) => debug!("Stored energy data of device '{device}'"),
+                Err(err) => warn!("! Failed to collect energy data of device '{device}': {err:#}"),
+            }
+        }
+
+        tokio::time::sleep(Duration::from_secs(collect_interval)).await;
+    }
+}
+
+async fn fetch_energy(
+    client: &TapoDeviceInner,
+) -> Result<Option<(PowerReading, Vec<(EnergyInterval, Vec<EnergyEntry>)>)>, tapo::Error> {
+    let Some(power) = get_current_power(client).await? else {
+        return Ok(None);
+    };
+
+    let now = Utc::now();
+    let today = Local::now().date_naive();
+
+    let power = PowerReading {
+        timestamp: now,
+        power,
+    };
+
+    let requests = [
+        (
+            EnergyInterval::Hourly,
+            today
+                .checked_sub_days(Days::new(HOURLY_LOOKBACK_DAYS))
+                .unwrap_or(today),
+        ),
+        (
+            EnergyInterval::Daily,
+            EnergyInterval::Daily.request_start(today),
+        ),
+        (
+            EnergyInterval::Monthly,
+            EnergyInterval::Monthly.request_start(today),
+        ),
+    ];
+
+    let mut energy = Vec::with_capacity(requests.len());
+
+    for (interval, start_date) in requests {
+        let entries = get_energy_data(client, interval, start_date, today)
+            .await?
+            .unwrap_or_default()
+            .into_iter()
+            // Periods that didn't start yet are always empty
+            .filter(|entry| entry.start_date_time <= now)
+            .map(|entry| EnergyEntry {
+                start: entry.start_date_time,
+                energy: entry.energy,
+            })
+            .collect();
+
+        energy.push((interval, entries));
+    }
+
+    Ok(Some((power, energy)))
+}
+
+/// Current power of a device in watts, adding up the power of each socket for power strips
+///
+/// Returns `None` if the device doesn't support energy monitoring.
+async fn get_current_power(client: &TapoDeviceInner) -> Result<Option<u64>, tapo::Error> {
+    match client {
+        TapoDeviceInner::P110(device)
+        | TapoDeviceInner::P110M(device)
+        | TapoDeviceInner::P115(device) => {
+            Ok(Some(device.get_current_power().await?.current_power))
+        }
+
+        TapoDeviceInner::P304(device)
+        | TapoDeviceInner::P304M(device)
+        | TapoDeviceInner::P316(device) => {
+            let mut power = 0;
+
+            for (_, socket) in device.sockets().await? {
+                power += socket.get_current_power().await?.current_power;
+            }
+
+            Ok(Some(power))
+        }
+
+        _ => Ok(None),
+    }
+}
+
+/// Energy data of a device, adding up the data of each socket for power strips
+///
+/// Returns `None` if the device doesn't support energy monitoring.
+async fn get_energy_data(
+    client: &TapoDeviceInner,
+    interval: EnergyInterval,
+    start_date: NaiveDate,
+    end_date: NaiveDate,
+) -> Result<Option<Vec<EnergyDataIntervalResult>>, tapo::Error> {
+    match client {
+        TapoDeviceInner::P110(device)
+        | TapoDeviceInner::P110M(device)
+        | TapoDeviceInner::P115(device) => Ok(Some(
+            device
+                .get_energy_data(interval.request(start_date, end_date))
+                .await?
+                .entries,
+        )),
+
+        TapoDeviceInner::P304(device)
+        | TapoDeviceInner::P304M(device)
+        | TapoDeviceInner::P316(device) => {
+            let mut totals = Vec::<EnergyDataIntervalResult>::new();
+
+            for (_, socket) in device.sockets().await? {
+                let entries = socket
+                    .get_energy_data(interval.request(start_date, end_date))
+                    .await?
+                    .entries;
+
+                // All sockets report the same periods
+                for entry in entries {
+                    match totals
+                        .iter_mut()
+                        .find(|total| total.start_date_time == entry.start_date_time)
+                    {
+                        Some(total) => total.energy += entry.energy,
+                        None => totals.push(entry),
+                    }
+                }
+            }
+
+            Ok(Some(totals))
+        }
+
+        _ => Ok(None),
+    }
+}
+
+/// Ranges of days (inclusive) to request to cover a range of days, according to the limits of the Tapo API
+///
+/// Hourly data can only be fetched 8 days at once, daily data a quarter at once and monthly data a year at once.
+pub fn energy_requests(
+    interval: EnergyInterval,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> Vec<(NaiveDate, NaiveDate)> {
+    let step = match interval {
+        EnergyInterval::Hourly => {
+            let mut requests = vec![];
+            let mut start = from;
+
+            while start <= to {
+                let end = start
+                    .checked_add_days(Days::new(HOURLY_DATA_MAX_DAYS - 1))
+                    .map_or(to, |end| end.min(to));
+
+                requests.push((start, end));
+
+                let Some(next) = end.succ_opt() else {
+                    break;
+                };
+
+                start = next;
+            }
+
+            return requests;
+        }
+
+        EnergyInterval::Daily => Months::new(3),
+        EnergyInterval::Monthly => Months::new(12),
+    };
+
+    std::iter::successors(Some(interval.request_start(from)), |start| {
+        start.checked_add_months(step)
+    })
+    .take_while(|start| *start <= to)
+    .map(|start| (start, start))
+    .collect()
+}
+
+/// Fetch the energy data of a device for one of the ranges returned by `energy_requests`
+pub async fn fetch_energy_data(
+    client: &TapoDeviceInner,
+    interval: EnergyInterval,
+    start_date: NaiveDate,
+    end_date: NaiveDate,
+) -> ApiResult<Vec<EnergyDataIntervalResult>> {
+    get_energy_data(client, interval, start_date, end_date)
+        .await?
+        .ok_or_else(|| {
+            ApiError::new(
+                ErrorCode::UnsupportedAction,
+                "Device doesn't support energy monitoring",
+            )
+        })
+}
+
+#[derive(Deserialize)]
+pub struct EnergyHistoryParams {
+    /// Start of the history (inclusive), defaults to a range depending on the interval
+    #[serde(default)]
+    from: Option<DateTime<Utc>>,
+
+    /// End of the history (exclusive), defaults to now
+    #[serde(default)]
+    to: Option<DateTime<Utc>>,
+
+    #[serde(default)]
+    interval: Option<EnergyInterval>,
+
+    #[serde(default)]
+    format: Option<ExportFormat>,
+}
+
+#[derive(Serialize)]
+pub struct EnergyHistoryResponse {
+    device: String,
+    interval: EnergyInterval,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    /// Total energy used over the returned entries, in watt-hours
+    total_energy: u64,
+    entries: Vec<EnergyEntry>,
+}
+
+#[derive(Serialize)]
+pub struct PowerHistoryResponse {
+    device: String,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    readings: Vec<PowerReading>,
+}
+
+/// Get the stored energy usage of a device
+pub async fn get_energy_history(
+    State(state): State<SharedState>,
+    Path(device): Path<String>,
+    uri: Uri,
+) -> ApiResult<Response> {
+    let EnergyHistoryParams {
+        from,
+        to,
+        interval,
+        format,
+    } = parse_params(&uri, &[])?;
+
+    let interval = interval.unwrap_or(EnergyInterval::Hourly);
+    let (history, from, to) = check_history_query(&state, &device, from, to, interval).await?;
+
+    let entries = history
+        .energy(device.clone(), interval, from, to)
+        .await
+        .map_err(|err| ApiError::from(err).with_device(&device))?;
+
+    if let Some(format) = format {
+        let rows = entries
+            .iter()
+            .map(|entry| EnergyRow::new(Some(&device), interval, entry.start, entry.energy))
+            .collect::<Vec<_>>();
+
+        return Ok(format.response(format.render(true, &rows)));
+    }
+
+    Ok(Json(EnergyHistoryResponse {
+        total_energy: entries.iter().map(|entry| entry.energy).sum(),
+        device,
+        interval,
+        from,
+        to,
+        entries,
+    })
+    .into_response())
+}
+
+/// Get the stored power readings of a device
+pub async fn get_power_history(
+    State(state): State<SharedState>,
+    Path(device): Path<String>,
+    uri: Uri,
+) -> ApiResult<Json<PowerHistoryResponse>> {
+    let EnergyHistoryParams { from, to, .. } = parse_params(&uri, &[])?;
+
+    let (history, from, to) =
+        check_history_query(&state, &device, from, to, EnergyInterval::Hourly).await?;
+
+    let readings = history
+        .power(device.clone(), from, to)
+        .await
+        .map_err(|err| ApiError::from(err).with_device(&device))?;
+
+    Ok(Json(PowerHistoryResponse {
+        device,
+        from,
+        to,
+        readings,
+    }))
+}
+
+/// Ensure the history is enabled and supported by the device, and compute the queried range
+async fn check_history_query<'a>(
+    state: &'a SharedState,
+    device: &str,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    interval: EnergyInterval,
+) -> ApiResult<(&'a EnergyHistory, DateTime<Utc>, DateTime<Utc>)> {
+    let Some(history) = &state.energy_history else {
+        return Err(ApiError::new(
+            ErrorCode::EnergyHistoryDisabled,
+            "Energy history is not enabled in the configuration",
+        ));
+    };
+
+    // Devices may be offline, so only the configuration is checked
+    let config = state.config.read().await;
+
+    let infos = config
+        .devices
+        .iter()
+        .find(|infos| infos.name == device)
+        .ok_or_else(|| ApiError::device_not_found(device))?;
+
+    if !infos.device_type.monitors_energy() {
+        return Err(ApiError::new(
+            ErrorCode::UnsupportedAction,
+            format!(
+                "Energy history is not supported by {} {} devices",
+                infos.device_type.type_name(),
+                infos.device_type.type_description()
+            ),
+        )
+        .with_device(device));
+    }
+
+    let to = to.unwrap_or_else(Utc::now);
+    let from = from.unwrap_or(to - interval.default_range());
+
+    if from >= to {
+        return Err(ApiError::new(
+            ErrorCode::InvalidParameter,
+            "Start of the history must be before its end",
+        ));
+    }
+
+    Ok((history, from, to))
+}
+
+/// Document the energy history routes in the `OpenAPI` specification
+pub fn add_energy_to_openapi(spec: &mut OpenApiBuilder) {
+    let device_param = json!({
+        "name": "device",
+        "in": "path",
+        "required": true,
+        "schema": { "type": "string" }
+    });
+
+    let date_param = |name: &str, description: &str| {
+        json!({
+            "name": name,
+            "in": "query",
+            "required": false,
+            "description": description,
+            "schema": { "type": "string", "format": "date-time" }
+        })
+    };
+
+    let responses = json!({
+        "200": { "description": "Success" },
+        "400": OpenApiBuilder::error_response("Invalid parameter, or device doesn't support energy monitoring"),
+        "404": OpenApiBuilder::error_response("Device not found, or energy history is disabled")
+    });
+
+    spec.add_route(
+        "/energy/{device}",
+        &Method::GET,
+        json!({
+            "summary": "Get the stored energy usage of a device",
+            "parameters": [
+                device_param,
+                date_param("from", "Start of the history (inclusive), defaults to 1 day, 30 days or 365 days ago depending on the interval"),
+                date_param("to", "End of the history (exclusive), defaults to now"),
+                {
+                    "name": "interval",
+                    "in": "query",
+                    "required": false,
+                    "schema": { "type": "string", "enum": ["hourly", "daily", "monthly"], "default": "hourly" }
+                },
+                {
+                    "name": "format",
+                    "in": "query",
+                    "required": false,
+                    "description": "Export the entries as CSV or newline-delimited JSON instead of a JSON document",
+                    "schema": { "type": "string", "enum": ["csv", "ndjson"] }
+                }
+            ],
+            "responses": responses
+        }),
+    );
+
+    spec.add_route(
+        "/energy/{device}/power",
+        &Method::GET,
+        json!({
+            "summary": "Get the stored power readings of a device",
+            "parameters": [
+                device_param,
+                date_param("from", "Start of the history (inclusive), defaults to 1 day ago"),
+                date_param("to", "End of the history (exclusive), defaults to now")
+            ],
+            "responses": responses
+        }),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveTime, TimeZone};
+
+    use super::*;
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    fn local_midnight(date: NaiveDate) -> DateTime<Utc> {
+        Local
+            .from_local_datetime(&date.and_time(NaiveTime::MIN))
+            .earliest()
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn hourly_requests_are_split_every_8_days() {
+        assert_eq!(
+            energy_requests(EnergyInterval::Hourly, date(2024, 1, 1), date(2024, 1, 20)),
+            [
+                (date(2024, 1, 1), date(2024, 1, 8)),
+                (date(2024, 1, 9), date(2024, 1, 16)),
+                (date(2024, 1, 17), date(2024, 1, 20)),
+            ]
+        );
+        assert_eq!(
+            energy_requests(EnergyInterval::Hourly, date(2024, 1, 1), date(2024, 1, 1)),
+            [(date(2024, 1, 1), date(2024, 1, 1))]
+        );
+        assert!(
+            energy_requests(EnergyInterval::Hourly, date(2024, 1, 2), date(2024, 1, 1)).is_empty()
+        );
+    }
+
+    #[test]
+    fn daily_requests_start_on_quarters() {
+        assert_eq!(
+            energy_requests(EnergyInterval::Daily, date(2024, 2, 15), date(2024, 7, 1)),
+            [
+                (date(2024, 1, 1), date(2024, 1, 1)),
+                (date(2024, 4, 1), date(2024, 4, 1)),
+                (date(2024, 7, 1), date(2024, 7, 1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn monthly_requests_start_on_years() {
+        assert_eq!(
+            energy_requests(
+                EnergyInterval::Monthly,
+                date(2023, 12, 31),
+                date(2024, 3, 1)
+            ),
+            [
+                (date(2023, 1, 1), date(2023, 1, 1)),
+                (date(2024, 1, 1), date(2024, 1, 1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn request_start() {
+        assert_eq!(
+            EnergyInterval::Hourly.request_start(date(2024, 5, 17)),
+            date(2024, 5, 17)
+        );
+        assert_eq!(
+            EnergyInterval::Daily.request_start(date(2024, 3, 31)),
+            date(2024, 1, 1)
+        );
+        assert_eq!(
+            EnergyInterval::Daily.request_start(date(2024, 12, 31)),
+            date(2024, 10, 1)
+        );
+        assert_eq!(
+            EnergyInterval::Monthly.request_start(date(2024, 5, 17)),
+            date(2024, 1, 1)
+        );
+    }
+
+    #[test]
+    fn hourly_periods_last_one_hour() {
+        let start = local_midnight(date(2024, 3, 31));
+
+        assert_eq!(
+            EnergyInterval::Hourly.period_end(start),
+            start + TimeDelta::hours(1)
+        );
+    }
+
+    #[test]
+    fn daily_periods_end_at_next_local_midnight() {
+        // Includes the DST changes of Europe and North America
+        for day in [
+            date(2024, 3, 10),
+            date(2024, 3, 31),
+            date(2024, 10, 27),
+            date(2024, 11, 3),
+            date(2024, 6, 15),
+        ] {
+            assert_eq!(
+                EnergyInterval::Daily.period_end(local_midnight(day)),
+                local_midnight(day.succ_opt().unwrap()),
+                "{day}"
+            );
+        }
+    }
+
+    #[test]
+    fn monthly_periods_follow_month_lengths() {
+        for (month, next_month) in [
+            (date(2024, 1, 1), date(2024, 2, 1)),
+            (date(2024, 2, 1), date(2024, 3, 1)),
+            (date(2023, 2, 1), date(2023, 3, 1)),
+            (date(2024, 3, 1), date(2024, 4, 1)),
+            (date(2024, 10, 1), date(2024, 11, 1)),
+            (date(2024, 12, 1), date(2025, 1, 1)),
+        ] {
+            assert_eq!(
+                EnergyInterval::Monthly.period_end(local_midnight(month)),
+                local_midnight(next_month),
+                "{month}"
+            );
+        }
+    }
+}
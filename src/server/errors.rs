@@ -1,44 +1,349 @@
+use std::fmt;
+
 use axum::{
-    http::StatusCode,
+    Json,
+    http::{StatusCode, header},
     response::{IntoResponse, Response},
 };
+use serde::{Deserialize, Serialize, Serializer};
+use tapo::TapoResponseError;
+
+use crate::devices::SessionExpiry;
 
 pub type ApiResult<T> = Result<T, ApiError>;
 
 pub struct ApiError {
-    code: StatusCode,
+    code: ErrorCode,
     message: String,
+    device: Option<String>,
 }
 
 impl ApiError {
-    pub fn new(code: StatusCode, message: impl Into<String>) -> Self {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
         Self {
             code,
             message: message.into(),
+            device: None,
         }
     }
+
+    pub fn device_not_found(name: &str) -> Self {
+        Self::new(
+            ErrorCode::DeviceNotFound,
+            "Provided device name was not found",
+        )
+        .with_device(name)
+    }
+
+    /// Indicate which device the error relates to
+    pub fn with_device(mut self, name: &str) -> Self {
+        self.device = Some(name.to_owned());
+        self
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.device {
+            Some(device) => write!(f, "{} (device '{device}')", self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl SessionExpiry for ApiError {
+    fn is_session_expired(&self) -> bool {
+        self.code == ErrorCode::SessionExpired
+    }
+
+    fn is_connection_lost(&self) -> bool {
+        matches!(
+            self.code,
+            ErrorCode::DeviceUnreachable | ErrorCode::UpstreamTimeout
+        )
+    }
+}
+
+/// Stable, machine-readable identifier of an error
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// No bearer token was provided
+    MissingApiKey,
+
+    /// The provided bearer token doesn't match any API key
+    InvalidApiKey,
+
+    /// No device with the provided name exists
+    DeviceNotFound,
+
+    /// No child device (e.g. a power strip's socket) matches the provided identifier
+    ChildDeviceNotFound,
+
+    /// No group with the provided name exists
+    GroupNotFound,
+
+    /// No scene with the provided name exists
+    SceneNotFound,
+
+    /// No schedule with the provided name exists
+    ScheduleNotFound,
+
+    /// Energy history was requested, but isn't enabled in the configuration
+    EnergyHistoryDisabled,
+
+    /// No tariff plan applies to the device
+    TariffNotFound,
+
+    /// The device's type doesn't match the route's
+    WrongDeviceType,
+
+    /// The device doesn't support the requested action
+    UnsupportedAction,
+
+    /// The action was called with the wrong HTTP method
+    MethodNotAllowed,
+
+    /// A parameter is missing or invalid
+    InvalidParameter,
+
+    /// The device could not be reached
+    DeviceUnreachable,
+
+    /// The session with the device expired and could not be re-established
+    SessionExpired,
+
+    /// The device rejected the Tapo credentials
+    InvalidCredentials,
+
+    /// The device took too long to respond
+    UpstreamTimeout,
+
+    /// The device returned an error or an unexpected response
+    DeviceError,
+
+    /// Any other error
+    InternalError,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> StatusCode {
+        match self {
+            Self::MissingApiKey => StatusCode::UNAUTHORIZED,
+            Self::InvalidApiKey => StatusCode::FORBIDDEN,
+            Self::DeviceNotFound
+            | Self::ChildDeviceNotFound
+            | Self::GroupNotFound
+            | Self::SceneNotFound
+            | Self::ScheduleNotFound
+            | Self::EnergyHistoryDisabled
+            | Self::TariffNotFound => StatusCode::NOT_FOUND,
+            Self::WrongDeviceType | Self::UnsupportedAction | Self::InvalidParameter => {
+                StatusCode::BAD_REQUEST
+            }
+            Self::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            Self::DeviceUnreachable | Self::SessionExpired => StatusCode::SERVICE_UNAVAILABLE,
+            Self::InvalidCredentials | Self::DeviceError => StatusCode::BAD_GATEWAY,
+            Self::UpstreamTimeout => StatusCode::GATEWAY_TIMEOUT,
+            Self::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Error body, as described in RFC 7807
+#[derive(Serialize)]
+struct ProblemDetails<'a> {
+    r#type: &'static str,
+    title: &'static str,
+    status: u16,
+    detail: &'a str,
+    code: ErrorCode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device: Option<&'a str>,
+}
+
+impl ApiError {
+    fn problem_details(&self) -> ProblemDetails<'_> {
+        let status = self.code.status();
+
+        ProblemDetails {
+            r#type: "about:blank",
+            title: status.canonical_reason().unwrap_or_default(),
+            status: status.as_u16(),
+            detail: &self.message,
+            code: self.code,
+            device: self.device.as_deref(),
+        }
+    }
+}
+
+impl Serialize for ApiError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.problem_details().serialize(serializer)
+    }
 }
 
 impl IntoResponse for ApiError {
     fn into_response(self) -> Response {
-        (self.code, self.message).into_response()
+        (
+            self.code.status(),
+            [(header::CONTENT_TYPE, "application/problem+json")],
+            Json(self.problem_details()),
+        )
+            .into_response()
     }
 }
 
 impl From<tapo::Error> for ApiError {
     fn from(value: tapo::Error) -> Self {
-        Self::new(StatusCode::INTERNAL_SERVER_ERROR, format!("{value}"))
+        Self::new(tapo_error_code(&value), format!("{value}"))
     }
 }
 
 impl From<anyhow::Error> for ApiError {
     fn from(value: anyhow::Error) -> Self {
-        Self::new(StatusCode::INTERNAL_SERVER_ERROR, format!("{value}"))
+        let code = value
+            .downcast_ref::<tapo::Error>()
+            .map_or(ErrorCode::InternalError, tapo_error_code);
+
+        Self::new(code, format!("{value:#}"))
+    }
+}
+
+fn tapo_error_code(err: &tapo::Error) -> ErrorCode {
+    match err {
+        tapo::Error::Tapo(TapoResponseError::Unauthorized { .. }) => {
+            if err.is_session_expired() {
+                ErrorCode::SessionExpired
+            } else {
+                ErrorCode::InvalidCredentials
+            }
+        }
+
+        tapo::Error::Validation { .. } => ErrorCode::InvalidParameter,
+
+        tapo::Error::Http(err) => {
+            if err.is_timeout() {
+                ErrorCode::UpstreamTimeout
+            } else {
+                ErrorCode::DeviceUnreachable
+            }
+        }
+
+        tapo::Error::DeviceNotFound => ErrorCode::DeviceNotFound,
+
+        tapo::Error::Other(_) => ErrorCode::InternalError,
+
+        _ => ErrorCode::DeviceError,
     }
 }
 
-impl From<(StatusCode, String)> for ApiError {
-    fn from(value: (StatusCode, String)) -> Self {
-        Self::new(value.0, value.1)
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn error_codes_map_to_http_statuses() {
+        for (code, name, status) in [
+            (ErrorCode::MissingApiKey, "missing_api_key", 401),
+            (ErrorCode::InvalidApiKey, "invalid_api_key", 403),
+            (ErrorCode::DeviceNotFound, "device_not_found", 404),
+            (ErrorCode::WrongDeviceType, "wrong_device_type", 400),
+            (ErrorCode::UnsupportedAction, "unsupported_action", 400),
+            (ErrorCode::MethodNotAllowed, "method_not_allowed", 405),
+            (ErrorCode::InvalidParameter, "invalid_parameter", 400),
+            (ErrorCode::DeviceUnreachable, "device_unreachable", 503),
+            (ErrorCode::SessionExpired, "session_expired", 503),
+            (ErrorCode::InvalidCredentials, "invalid_credentials", 502),
+            (ErrorCode::UpstreamTimeout, "upstream_timeout", 504),
+            (ErrorCode::DeviceError, "device_error", 502),
+            (ErrorCode::InternalError, "internal_error", 500),
+        ] {
+            assert_eq!(serde_json::to_value(code).unwrap(), name);
+            assert_eq!(code.status().as_u16(), status, "{name}");
+        }
+    }
+
+    #[test]
+    fn unreachable_devices_drop_the_connection() {
+        let error = |code| ApiError::new(code, "Failed");
+
+        assert!(error(ErrorCode::DeviceUnreachable).is_connection_lost());
+        assert!(error(ErrorCode::UpstreamTimeout).is_connection_lost());
+        assert!(!error(ErrorCode::SessionExpired).is_connection_lost());
+        assert!(!error(ErrorCode::DeviceError).is_connection_lost());
+        assert!(!error(ErrorCode::InvalidParameter).is_connection_lost());
+    }
+
+    #[tokio::test]
+    async fn problem_details_body() {
+        let response = ApiError::new(
+            ErrorCode::UnsupportedAction,
+            "Action 'dim' is not supported",
+        )
+        .with_device("desk")
+        .into_response();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/problem+json"
+        );
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            json!({
+                "type": "about:blank",
+                "title": "Bad Request",
+                "status": 400,
+                "detail": "Action 'dim' is not supported",
+                "code": "unsupported_action",
+                "device": "desk"
+            })
+        );
+    }
+
+    fn code_of(err: tapo::Error) -> ErrorCode {
+        ApiError::from(err).code
+    }
+
+    #[test]
+    fn tapo_errors_map_to_error_codes() {
+        let unauthorized = |kind| {
+            tapo::Error::Tapo(TapoResponseError::Unauthorized {
+                kind,
+                description: "Unauthorized".to_owned(),
+            })
+        };
+
+        assert!(code_of(unauthorized("SESSION_TIMEOUT")) == ErrorCode::SessionExpired);
+        assert!(code_of(unauthorized("HASH_MISMATCH")) == ErrorCode::InvalidCredentials);
+        assert!(
+            code_of(tapo::Error::Validation {
+                field: "brightness".to_owned(),
+                message: "must be between 1 and 100".to_owned(),
+            }) == ErrorCode::InvalidParameter
+        );
+        assert!(code_of(tapo::Error::DeviceNotFound) == ErrorCode::DeviceNotFound);
+        assert!(
+            code_of(tapo::Error::Tapo(TapoResponseError::EmptyResult)) == ErrorCode::DeviceError
+        );
+    }
+
+    #[test]
+    fn wrapped_tapo_errors_keep_their_code() {
+        let err = anyhow::Error::new(tapo::Error::DeviceNotFound).context("Failed to get device");
+        let err = ApiError::from(err);
+
+        assert!(err.code == ErrorCode::DeviceNotFound);
+        assert_eq!(err.message, "Failed to get device: Device not found");
+
+        assert!(ApiError::from(anyhow::anyhow!("Disk full")).code == ErrorCode::InternalError);
     }
 }
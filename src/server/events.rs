@@ -0,0 +1,870 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use anyhow::{Result, bail};
+use axum::{
+    extract::{
+        State,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    http::{Method, Uri},
+    response::{
+        IntoResponse, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+};
+use chrono::{DateTime, Utc};
+use futures_util::stream;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::{
+    sync::{
+        broadcast::{self, error::RecvError},
+        watch,
+    },
+    task::JoinSet,
+};
+
+use crate::{
+    config::{Config, PowerThreshold},
+    devices::{DeviceStatus, EnergyMonitoringPowerStrip, TapoDeviceInner},
+};
+
+use super::{
+    ApiError, ApiResult, Capability, ErrorCode, SharedState, actions::parse_params,
+    openapi::OpenApiBuilder,
+};
+
+/// Number of events kept for subscribers that are lagging behind
+pub const EVENTS_CAPACITY: usize = 256;
+
+#[derive(Serialize, Clone)]
+pub struct DeviceEvent {
+    pub device: String,
+    pub timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    pub kind: DeviceEventKind,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DeviceEventKind {
+    /// The device was turned on or off
+    PowerChanged { on: bool },
+
+    /// The device's brightness changed
+    BrightnessChanged { brightness: u8 },
+
+    /// A socket of a power strip was turned on or off
+    SocketPowerChanged {
+        position: u8,
+        nickname: String,
+        on: bool,
+    },
+
+    /// The device could not be reached anymore
+    DeviceOffline { error: String },
+
+    /// The device can be reached again
+    DeviceOnline,
+
+    /// The device's current power crossed a configured threshold
+    PowerThresholdCrossed {
+        threshold: u64,
+        current_power: u64,
+        direction: ThresholdDirection,
+    },
+}
+
+impl DeviceEventKind {
+    pub const NAMES: &[&str] = &[
+        "power_changed",
+        "brightness_changed",
+        "socket_power_changed",
+        "device_offline",
+        "device_online",
+        "power_threshold_crossed",
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::PowerChanged { .. } => "power_changed",
+            Self::BrightnessChanged { .. } => "brightness_changed",
+            Self::SocketPowerChanged { .. } => "socket_power_changed",
+            Self::DeviceOffline { .. } => "device_offline",
+            Self::DeviceOnline => "device_online",
+            Self::PowerThresholdCrossed { .. } => "power_threshold_crossed",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ThresholdDirection {
+    Above,
+    Below,
+}
+
+/// State of a device, as observed by the poller
+#[derive(Serialize, Clone, PartialEq, Eq, Default)]
+pub struct DeviceSnapshot {
+    pub on: Option<bool>,
+    pub brightness: Option<u8>,
+    pub hue: Option<u16>,
+    pub saturation: Option<u16>,
+    /// Color temperature in Kelvin, `0` if the color is set using hue and saturation
+    pub color_temperature: Option<u16>,
+    /// Current power in watts
+    pub current_power: Option<u64>,
+    /// Current power in milliwatts, for consumers requiring more precision than whole watts
+    #[serde(skip)]
+    pub current_power_milliwatts: Option<u64>,
+    /// Energy used today in watt-hours
+    pub today_energy: Option<u64>,
+    /// Energy used this month in watt-hours
+    pub month_energy: Option<u64>,
+    /// Wi-Fi signal strength in dBm
+    pub rssi: Option<i16>,
+    /// State of each socket, for power strips
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sockets: Vec<SocketSnapshot>,
+}
+
+/// State of a power strip's socket, as observed by the poller
+#[derive(Serialize, Clone, PartialEq, Eq)]
+pub struct SocketSnapshot {
+    pub position: u8,
+    pub nickname: String,
+    pub on: bool,
+    /// Current power in watts
+    pub current_power: Option<u64>,
+    /// Current power in milliwatts, for consumers requiring more precision than whole watts
+    #[serde(skip)]
+    pub current_power_milliwatts: Option<u64>,
+    /// Energy used today in watt-hours
+    pub today_energy: Option<u64>,
+    /// Energy used this month in watt-hours
+    pub month_energy: Option<u64>,
+}
+
+/// Periodically fetch the state of all devices, and publish events describing what changed
+pub async fn poll_device_states(state: SharedState) {
+    let mut snapshots = HashMap::<String, DeviceSnapshot>::new();
+    let mut offline = HashMap::<String, bool>::new();
+
+    loop {
+        let (poll_interval, thresholds) = {
+            let config = state.config.read().await;
+
+            (
+                Duration::from_secs(config.events.poll_interval_secs),
+                config.events.power_thresholds.clone(),
+            )
+        };
+
+        tokio::time::sleep(poll_interval).await;
+
+        // Don't keep the devices locked while polling, as it may take a while
+        let devices = state
+            .devices
+            .read()
+            .await
+            .values()
+            .map(Arc::clone)
+            .collect::<Vec<_>>();
+
+        // Forget about devices that were removed from the configuration
+        let is_configured = |name: &String| {
+            devices
+                .iter()
+                .any(|device| &device.conn_infos().name == name)
+        };
+
+        snapshots.retain(|name, _| is_configured(name));
+        offline.retain(|name, _| is_configured(name));
+        state
+            .device_states
+            .write()
+            .await
+            .retain(|name, _| is_configured(name));
+
+        let mut tasks = JoinSet::new();
+
+        for device in devices {
+            tasks.spawn(async move {
+                let health = device.health().await;
+
+                // Unreachable devices are reconnected to by the supervisor, with a backoff
+                let result = if health.status == DeviceStatus::Unreachable {
+                    Err(health.last_error.unwrap_or_default())
+                } else {
+                    device
+                        .with_client(async |client| read_snapshot(client).await)
+                        .await
+                        .and_then(|result| result.map_err(anyhow::Error::from))
+                        .map_err(|err| format!("{err:#}"))
+                };
+
+                (device.conn_infos().name.clone(), result)
+            });
+        }
+
+        for (device, result) in tasks.join_all().await {
+            let mut events = vec![];
+
+            match result {
+                Ok(snapshot) => {
+                    state
+                        .device_states
+                        .write()
+                        .await
+                        .insert(device.clone(), snapshot.clone());
+
+                    if offline.insert(device.clone(), false) == Some(true) {
+                        events.push(DeviceEventKind::DeviceOnline);
+                    }
+
+                    if let Some(previous) = snapshots.insert(device.clone(), snapshot.clone()) {
+                        let thresholds = thresholds
+                            .iter()
+                            .filter(|threshold| threshold.device == device);
+
+                        events.extend(diff_snapshots(&previous, &snapshot, thresholds));
+                    }
+                }
+
+                Err(error) => {
+                    if offline.insert(device.clone(), true) != Some(true) {
+                        events.push(DeviceEventKind::DeviceOffline { error });
+                    }
+                }
+            }
+
+            for kind in events {
+                debug!("Device '{device}' event: {}", kind.name());
+
+                // Sending only fails if there are no subscribers
+                let _ = state.events.send(DeviceEvent {
+                    device: device.clone(),
+                    timestamp: Utc::now(),
+                    kind,
+                });
+            }
+        }
+    }
+}
+
+fn diff_snapshots<'a>(
+    previous: &DeviceSnapshot,
+    current: &DeviceSnapshot,
+    thresholds: impl Iterator<Item = &'a PowerThreshold>,
+) -> Vec<DeviceEventKind> {
+    let mut events = vec![];
+
+    if let Some(on) = current.on
+        && previous.on != current.on
+    {
+        events.push(DeviceEventKind::PowerChanged { on });
+    }
+
+    if let Some(brightness) = current.brightness
+        && previous.brightness != current.brightness
+    {
+        events.push(DeviceEventKind::BrightnessChanged { brightness });
+    }
+
+    for socket in &current.sockets {
+        if previous
+            .sockets
+            .iter()
+            .any(|other| other.position == socket.position && other.on != socket.on)
+        {
+            events.push(DeviceEventKind::SocketPowerChanged {
+                position: socket.position,
+                nickname: socket.nickname.clone(),
+                on: socket.on,
+            });
+        }
+    }
+
+    if let (Some(previous_power), Some(current_power)) =
+        (previous.current_power, current.current_power)
+    {
+        for threshold in thresholds {
+            let direction = if previous_power < threshold.watts && current_power >= threshold.watts
+            {
+                ThresholdDirection::Above
+            } else if previous_power >= threshold.watts && current_power < threshold.watts {
+                ThresholdDirection::Below
+            } else {
+                continue;
+            };
+
+            events.push(DeviceEventKind::PowerThresholdCrossed {
+                threshold: threshold.watts,
+                current_power,
+                direction,
+            });
+        }
+    }
+
+    events
+}
+
+/// Fetch the state of a device and update the cached one, without publishing any event
+pub async fn refresh_snapshot(state: &SharedState, name: &str) -> Result<()> {
+    let Some(device) = state.devices.read().await.get(name).map(Arc::clone) else {
+        bail!("Device '{name}' was not found");
+    };
+
+    let snapshot = device
+        .with_client(async |client| read_snapshot(client).await)
+        .await??;
+
+    state
+        .device_states
+        .write()
+        .await
+        .insert(name.to_owned(), snapshot);
+
+    Ok(())
+}
+
+async fn read_snapshot(client: &TapoDeviceInner) -> Result<DeviceSnapshot, tapo::Error> {
+    let mut snapshot = DeviceSnapshot::default();
+
+    match client {
+        TapoDeviceInner::L510(device)
+        | TapoDeviceInner::L520(device)
+        | TapoDeviceInner::L610(device) => {
+            let info = device.get_device_info().await?;
+            snapshot.on = Some(info.device_on);
+            snapshot.brightness = Some(info.brightness);
+            snapshot.rssi = Some(info.rssi);
+        }
+
+        TapoDeviceInner::L530(device)
+        | TapoDeviceInner::L535(device)
+        | TapoDeviceInner::L630(device) => {
+            let info = device.get_device_info().await?;
+            snapshot.on = Some(info.device_on);
+            snapshot.brightness = Some(info.brightness);
+            snapshot.hue = info.hue;
+            snapshot.saturation = info.saturation;
+            snapshot.color_temperature = Some(info.color_temp);
+            snapshot.rssi = Some(info.rssi);
+        }
+
+        TapoDeviceInner::L900(device) => {
+            let info = device.get_device_info().await?;
+            snapshot.on = Some(info.device_on);
+            snapshot.brightness = Some(info.brightness);
+            snapshot.hue = info.hue;
+            snapshot.saturation = info.saturation;
+            snapshot.color_temperature = Some(info.color_temp);
+            snapshot.rssi = Some(info.rssi);
+        }
+
+        TapoDeviceInner::L920(device) | TapoDeviceInner::L930(device) => {
+            let info = device.get_device_info().await?;
+            snapshot.on = Some(info.device_on);
+            snapshot.brightness = Some(info.brightness);
+            snapshot.hue = info.hue;
+            snapshot.saturation = info.saturation;
+            snapshot.color_temperature = Some(info.color_temp);
+            snapshot.rssi = Some(info.rssi);
+        }
+
+        TapoDeviceInner::P100(device) | TapoDeviceInner::P105(device) => {
+            let info = device.get_device_info().await?;
+            snapshot.on = Some(info.device_on);
+            snapshot.rssi = Some(info.rssi);
+        }
+
+        TapoDeviceInner::P110(device)
+        | TapoDeviceInner::P110M(device)
+        | TapoDeviceInner::P115(device) => {
+            let info = device.get_device_info().await?;
+            snapshot.on = Some(info.device_on);
+            snapshot.rssi = Some(info.rssi);
+
+            let usage = device.get_energy_usage().await?;
+            snapshot.today_energy = Some(usage.today_energy);
+            snapshot.month_energy = Some(usage.month_energy);
+
+            // Energy usage reports the current power in milliwatts
+            let current_power_milliwatts = match usage.current_power {
+                Some(current_power) => current_power,
+                None => device.get_current_power().await?.current_power * 1000,
+            };
+
+            snapshot.current_power = Some(current_power_milliwatts / 1000);
+            snapshot.current_power_milliwatts = Some(current_power_milliwatts);
+        }
+
+        TapoDeviceInner::P300(device) => {
+            snapshot.rssi = Some(device.get_device_info().await?.rssi);
+
+            for plug in device.get_child_device_list().await? {
+                snapshot.sockets.push(SocketSnapshot {
+                    position: plug.position,
+                    nickname: plug.nickname,
+                    on: plug.device_on,
+                    current_power: None,
+                    current_power_milliwatts: None,
+                    today_energy: None,
+                    month_energy: None,
+                });
+            }
+        }
+
+        TapoDeviceInner::P304(device)
+        | TapoDeviceInner::P304M(device)
+        | TapoDeviceInner::P316(device) => {
+            snapshot.rssi = Some(device.get_device_info().await?.rssi);
+
+            snapshot.sockets = read_energy_sockets(device).await?;
+        }
+
+        TapoDeviceInner::H100(device) | TapoDeviceInner::H200(device) => {
+            snapshot.rssi = Some(device.get_device_info().await?.rssi);
+        }
+    }
+
+    Ok(snapshot)
+}
+
+/// Read the state and energy usage of each socket of an energy-monitoring power strip
+async fn read_energy_sockets(
+    device: &EnergyMonitoringPowerStrip,
+) -> Result<Vec<SocketSnapshot>, tapo::Error> {
+    let mut sockets = vec![];
+
+    for (plug, handler) in device.sockets().await? {
+        let usage = handler.get_energy_usage().await?;
+
+        sockets.push(SocketSnapshot {
+            position: plug.position,
+            nickname: plug.nickname,
+            on: plug.device_on,
+            // Energy usage reports the current power in milliwatts
+            current_power: usage
+                .current_power
+                .map(|current_power| current_power / 1000),
+            current_power_milliwatts: usage.current_power,
+            today_energy: Some(usage.today_energy),
+            month_energy: Some(usage.month_energy),
+        });
+    }
+
+    Ok(sockets)
+}
+
+/// Ensure the events configuration only refers to existing devices, with the required capabilities
+pub fn validate_events_config(config: &Config) -> Result<()> {
+    if config.events.poll_interval_secs == 0 {
+        bail!("Events' poll interval must be at least 1 second");
+    }
+
+    for threshold in &config.events.power_thresholds {
+        let Some(device) = config
+            .devices
+            .iter()
+            .find(|device| device.name == threshold.device)
+        else {
+            bail!(
+                "Power threshold refers to unknown device '{}'",
+                threshold.device
+            );
+        };
+
+        if !device
+            .device_type
+            .capabilities()
+            .contains(&Capability::EnergyMonitoring)
+        {
+            bail!(
+                "Power threshold is set on device '{}', which doesn't support energy monitoring",
+                threshold.device
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Ensure an event type exists
+pub fn check_event_type(name: &str) -> Result<()> {
+    if !DeviceEventKind::NAMES.contains(&name) {
+        bail!(
+            "Unknown event type '{name}' (available: {})",
+            DeviceEventKind::NAMES.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct EventsParams {
+    /// Comma-separated list of devices to receive events from (defaults to all)
+    #[serde(default)]
+    devices: Option<String>,
+
+    /// Comma-separated list of event types to receive (defaults to all)
+    #[serde(default)]
+    types: Option<String>,
+}
+
+struct EventFilter {
+    devices: Option<Vec<String>>,
+    types: Option<Vec<String>>,
+}
+
+impl EventFilter {
+    fn from_uri(uri: &Uri) -> ApiResult<Self> {
+        let EventsParams { devices, types } = parse_params(uri, &[])?;
+
+        let split = |list: Option<String>| {
+            list.map(|list| {
+                list.split(',')
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                    .map(str::to_owned)
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        let filter = Self {
+            devices: split(devices),
+            types: split(types),
+        };
+
+        for name in filter.types.iter().flatten() {
+            check_event_type(name)
+                .map_err(|err| ApiError::new(ErrorCode::InvalidParameter, format!("{err}")))?;
+        }
+
+        Ok(filter)
+    }
+
+    fn matches(&self, event: &DeviceEvent) -> bool {
+        self.devices
+            .as_ref()
+            .is_none_or(|devices| devices.contains(&event.device))
+            && self
+                .types
+                .as_ref()
+                .is_none_or(|types| types.iter().any(|name| name == event.kind.name()))
+    }
+
+    /// Wait for the next event matching the filter
+    ///
+    /// Returns `None` once the server is shutting down.
+    async fn next_event(
+        &self,
+        receiver: &mut broadcast::Receiver<DeviceEvent>,
+        shutdown: &mut watch::Receiver<bool>,
+    ) -> Option<DeviceEvent> {
+        loop {
+            let result = tokio::select! {
+                result = receiver.recv() => result,
+                _ = shutdown.wait_for(|shutting_down| *shutting_down) => return None,
+            };
+
+            match result {
+                Ok(event) if self.matches(&event) => return Some(event),
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Events subscriber is lagging behind, skipped {skipped} event(s)");
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+/// Stream device events using Server-Sent Events
+pub async fn stream_events(State(state): State<SharedState>, uri: Uri) -> ApiResult<Response> {
+    let filter = EventFilter::from_uri(&uri)?;
+    let receiver = state.events.subscribe();
+    let shutdown = state.shutdown.subscribe();
+
+    let events = stream::unfold(
+        (filter, receiver, shutdown),
+        |(filter, mut receiver, mut shutdown)| async move {
+            let event = filter.next_event(&mut receiver, &mut shutdown).await?;
+            let sse_event = Event::default().event(event.kind.name()).json_data(&event);
+
+            Some((sse_event, (filter, receiver, shutdown)))
+        },
+    );
+
+    Ok(Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response())
+}
+
+/// Stream device events through a WebSocket
+pub async fn stream_events_ws(
+    State(state): State<SharedState>,
+    uri: Uri,
+    upgrade: WebSocketUpgrade,
+) -> ApiResult<Response> {
+    let filter = EventFilter::from_uri(&uri)?;
+    let receiver = state.events.subscribe();
+    let shutdown = state.shutdown.subscribe();
+
+    Ok(upgrade.on_upgrade(|socket| forward_events(socket, filter, receiver, shutdown)))
+}
+
+async fn forward_events(
+    mut socket: WebSocket,
+    filter: EventFilter,
+    mut receiver: broadcast::Receiver<DeviceEvent>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    loop {
+        tokio::select! {
+            event = filter.next_event(&mut receiver, &mut shutdown) => {
+                // The server is shutting down
+                let Some(event) = event else {
+                    let _ = socket.send(Message::Close(None)).await;
+                    break;
+                };
+
+                let message = match serde_json::to_string(&event) {
+                    Ok(message) => message,
+                    Err(err) => {
+                        warn!("Failed to serialize device event: {err}");
+                        continue;
+                    }
+                };
+
+                if socket.send(Message::Text(message.into())).await.is_err() {
+                    break;
+                }
+            }
+
+            // Incoming messages are ignored, the client only needs to close the connection when done
+            message = socket.recv() => {
+                if !matches!(message, Some(Ok(_))) {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Document the event routes in the `OpenAPI` specification
+pub fn add_events_to_openapi(spec: &mut OpenApiBuilder) {
+    let parameters = json!([
+        {
+            "name": "devices",
+            "in": "query",
+            "required": false,
+            "description": "Comma-separated list of devices to receive events from",
+            "schema": { "type": "string" }
+        },
+        {
+            "name": "types",
+            "in": "query",
+            "required": false,
+            "description": format!("Comma-separated list of event types to receive ({})", DeviceEventKind::NAMES.join(", ")),
+            "schema": { "type": "string" }
+        }
+    ]);
+
+    spec.add_route(
+        "/events",
+        &Method::GET,
+        json!({
+            "summary": "Stream device state changes using Server-Sent Events",
+            "parameters": parameters,
+            "responses": {
+                "200": {
+                    "description": "Stream of events",
+                    "content": { "text/event-stream": {} }
+                },
+                "400": OpenApiBuilder::error_response("Invalid filter")
+            }
+        }),
+    );
+
+    spec.add_route(
+        "/events/ws",
+        &Method::GET,
+        json!({
+            "summary": "Stream device state changes through a WebSocket, as JSON messages",
+            "parameters": parameters,
+            "responses": {
+                "101": { "description": "Switching to the WebSocket protocol" },
+                "400": OpenApiBuilder::error_response("Invalid filter")
+            }
+        }),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use super::*;
+
+    fn snapshot(on: bool, brightness: Option<u8>, current_power: Option<u64>) -> DeviceSnapshot {
+        DeviceSnapshot {
+            on: Some(on),
+            brightness,
+            current_power,
+            ..Default::default()
+        }
+    }
+
+    fn thresholds(watts: &[u64]) -> Vec<PowerThreshold> {
+        watts
+            .iter()
+            .map(|&watts| PowerThreshold {
+                device: "plug".to_owned(),
+                watts,
+            })
+            .collect()
+    }
+
+    fn diff(previous: &DeviceSnapshot, current: &DeviceSnapshot, watts: &[u64]) -> Value {
+        serde_json::to_value(diff_snapshots(previous, current, thresholds(watts).iter())).unwrap()
+    }
+
+    #[test]
+    fn unchanged_state_has_no_events() {
+        let state = snapshot(true, Some(50), Some(100));
+
+        assert_eq!(diff(&state, &state, &[50, 150]), json!([]));
+    }
+
+    #[test]
+    fn power_and_brightness_changes() {
+        assert_eq!(
+            diff(
+                &snapshot(false, Some(50), None),
+                &snapshot(true, Some(80), None),
+                &[]
+            ),
+            json!([
+                { "type": "power_changed", "on": true },
+                { "type": "brightness_changed", "brightness": 80 },
+            ])
+        );
+    }
+
+    fn socket(position: u8, on: bool) -> SocketSnapshot {
+        SocketSnapshot {
+            position,
+            nickname: format!("Socket {position}"),
+            on,
+            current_power: None,
+            current_power_milliwatts: None,
+            today_energy: None,
+            month_energy: None,
+        }
+    }
+
+    fn power_strip(sockets: Vec<SocketSnapshot>) -> DeviceSnapshot {
+        DeviceSnapshot {
+            sockets,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn socket_power_changes() {
+        assert_eq!(
+            diff(
+                &power_strip(vec![socket(1, false), socket(2, true), socket(3, true)]),
+                &power_strip(vec![socket(1, true), socket(2, true), socket(3, false)]),
+                &[]
+            ),
+            json!([
+                { "type": "socket_power_changed", "position": 1, "nickname": "Socket 1", "on": true },
+                { "type": "socket_power_changed", "position": 3, "nickname": "Socket 3", "on": false },
+            ])
+        );
+
+        // Sockets that weren't known before are not changes
+        assert_eq!(
+            diff(
+                &power_strip(vec![]),
+                &power_strip(vec![socket(1, true)]),
+                &[]
+            ),
+            json!([])
+        );
+    }
+
+    #[test]
+    fn missing_values_are_not_changes() {
+        let previous = snapshot(true, Some(50), Some(100));
+        let current = DeviceSnapshot::default();
+
+        assert_eq!(diff(&previous, &current, &[50]), json!([]));
+    }
+
+    #[test]
+    fn threshold_crossed_upwards() {
+        assert_eq!(
+            diff(
+                &snapshot(true, None, Some(99)),
+                &snapshot(true, None, Some(100)),
+                &[100]
+            ),
+            json!([{
+                "type": "power_threshold_crossed",
+                "threshold": 100,
+                "current_power": 100,
+                "direction": "above",
+            }])
+        );
+    }
+
+    #[test]
+    fn threshold_crossed_downwards() {
+        assert_eq!(
+            diff(
+                &snapshot(true, None, Some(100)),
+                &snapshot(true, None, Some(20)),
+                &[10, 50, 100]
+            ),
+            json!([
+                {
+                    "type": "power_threshold_crossed",
+                    "threshold": 50,
+                    "current_power": 20,
+                    "direction": "below",
+                },
+                {
+                    "type": "power_threshold_crossed",
+                    "threshold": 100,
+                    "current_power": 20,
+                    "direction": "below",
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn threshold_not_crossed() {
+        let thresholds = [100];
+
+        // Staying above or below a threshold doesn't cross it
+        for (previous, current) in [(150, 120), (100, 100), (20, 99), (99, 20)] {
+            assert_eq!(
+                diff(
+                    &snapshot(true, None, Some(previous)),
+                    &snapshot(true, None, Some(current)),
+                    &thresholds
+                ),
+                json!([]),
+                "{previous} W -> {current} W"
+            );
+        }
+    }
+}
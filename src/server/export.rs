@@ -0,0 +1,577 @@
+use std::{borrow::Cow, fmt::Write, io, sync::Arc};
+
+use axum::{
+    Json,
+    body::Body,
+    extract::State,
+    http::{Method, Uri, header},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Local, NaiveDate, NaiveTime, SecondsFormat, Utc};
+use futures_util::stream;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tapo::responses::{EnergyDataIntervalResult, EnergyDataResult};
+use tokio::sync::mpsc;
+
+use crate::devices::TapoDevice;
+
+use super::{
+    ApiError, ApiResult, ErrorCode, SharedState,
+    actions::{ActionOutput, IntoActionOutput, parse_params},
+    energy::{EnergyInterval, energy_requests, fetch_energy_data},
+    openapi::{OpenApiBuilder, ResponseSchema, Shape},
+};
+
+/// Maximum number of days exported at once for each interval, to bound the number of requests sent to each device
+///
+/// Hourly data is limited like costs, daily data to about 3 years and monthly data to about 10 years.
+fn max_range_days(interval: EnergyInterval) -> i64 {
+    match interval {
+        EnergyInterval::Hourly => 92,
+        EnergyInterval::Daily => 3 * 366,
+        EnergyInterval::Monthly => 10 * 366,
+    }
+}
+
+/// File formats energy data can be exported to
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    Csv,
+    Ndjson,
+}
+
+impl ExportFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            Self::Csv => "text/csv; charset=utf-8",
+            Self::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Ndjson => "ndjson",
+        }
+    }
+
+    /// Header of the file, if the format has one
+    fn header(self, with_device: bool) -> String {
+        match self {
+            Self::Csv if with_device => "device,interval,start,end,energy\n".to_owned(),
+            Self::Csv => "interval,start,end,energy\n".to_owned(),
+            Self::Ndjson => String::new(),
+        }
+    }
+
+    fn row(self, row: &EnergyRow) -> String {
+        match self {
+            Self::Csv => {
+                let mut line = row
+                    .device
+                    .map(|device| format!("{},", csv_field(device)))
+                    .unwrap_or_default();
+
+                let _ = writeln!(
+                    line,
+                    "{},{},{},{}",
+                    row.interval.name(),
+                    row.start.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+                    row.end.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+                    row.energy
+                );
+
+                line
+            }
+
+            // Serializing plain strings and numbers can't fail
+            Self::Ndjson => serde_json::to_string(row).unwrap() + "\n",
+        }
+    }
+
+    /// Render rows of energy data, header included
+    pub fn render(self, with_device: bool, rows: &[EnergyRow]) -> String {
+        let mut out = self.header(with_device);
+
+        for row in rows {
+            out.push_str(&self.row(row));
+        }
+
+        out
+    }
+
+    /// Build a response from a rendered export
+    pub fn response(self, body: impl Into<Body>) -> Response {
+        ([(header::CONTENT_TYPE, self.content_type())], body.into()).into_response()
+    }
+}
+
+/// Energy used by a device over a period, with both ends of the period materialized
+#[derive(Serialize)]
+pub struct EnergyRow<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device: Option<&'a str>,
+    interval: EnergyInterval,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    /// Energy used in watt-hours
+    energy: u64,
+}
+
+impl<'a> EnergyRow<'a> {
+    pub fn new(
+        device: Option<&'a str>,
+        interval: EnergyInterval,
+        start: DateTime<Utc>,
+        energy: u64,
+    ) -> Self {
+        Self {
+            device,
+            interval,
+            start,
+            end: interval.period_end(start),
+            energy,
+        }
+    }
+}
+
+fn csv_field(value: &str) -> Cow<'_, str> {
+    if value.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+/// Energy data returned by an action, either as JSON or in an export format
+pub struct EnergyDataOutput {
+    data: EnergyDataResult,
+    interval: EnergyInterval,
+    format: Option<ExportFormat>,
+}
+
+impl EnergyDataOutput {
+    pub fn new(
+        data: EnergyDataResult,
+        interval: EnergyInterval,
+        format: Option<ExportFormat>,
+    ) -> Self {
+        Self {
+            data,
+            interval,
+            format,
+        }
+    }
+}
+
+impl IntoResponse for EnergyDataOutput {
+    fn into_response(self) -> Response {
+        let Some(format) = self.format else {
+            return Json(self.data).into_response();
+        };
+
+        let rows = self
+            .data
+            .entries
+            .iter()
+            .map(|entry| EnergyRow::new(None, self.interval, entry.start_date_time, entry.energy))
+            .collect::<Vec<_>>();
+
+        format.response(format.render(false, &rows))
+    }
+}
+
+// Outputs of actions run through scenes or groups are always embedded in a JSON report
+impl IntoActionOutput for EnergyDataOutput {
+    fn into_output(self) -> ApiResult<ActionOutput> {
+        Json(self.data).into_output()
+    }
+}
+
+impl ResponseSchema for EnergyDataOutput {
+    fn shape() -> Option<Shape> {
+        Json::<EnergyDataResult>::shape()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct EnergyExportParams {
+    /// Comma-separated names of the devices to export
+    #[serde(default)]
+    devices: Option<String>,
+
+    /// Group whose energy-monitoring devices are exported
+    #[serde(default)]
+    group: Option<String>,
+
+    #[serde(default)]
+    interval: Option<EnergyInterval>,
+
+    /// First day of the export (inclusive)
+    from: NaiveDate,
+
+    /// Last day of the export (inclusive), defaults to today
+    #[serde(default)]
+    to: Option<NaiveDate>,
+
+    #[serde(default)]
+    format: Option<ExportFormat>,
+}
+
+/// Stream the energy data of multiple devices over a range of days into a single file
+///
+/// Data is fetched from the devices while the file is being sent, so large exports don't need to be held in memory.
+/// As the response has already started by then, devices failing to respond are skipped and the failure is logged.
+pub async fn export_energy(State(state): State<SharedState>, uri: Uri) -> ApiResult<Response> {
+    let EnergyExportParams {
+        devices,
+        group,
+        interval,
+        from,
+        to,
+        format,
+    } = parse_params(&uri, &[])?;
+
+    let interval = interval.unwrap_or(EnergyInterval::Hourly);
+    let format = format.unwrap_or(ExportFormat::Csv);
+    let to = to.unwrap_or_else(|| Local::now().date_naive());
+
+    if from > to {
+        return Err(ApiError::new(
+            ErrorCode::InvalidParameter,
+            "Start of the export must not be after its end",
+        ));
+    }
+
+    let max_days = max_range_days(interval);
+
+    if (to - from).num_days() >= max_days {
+        return Err(ApiError::new(
+            ErrorCode::InvalidParameter,
+            format!(
+                "{} energy data can't be exported for more than {max_days} days at once",
+                interval.name()
+            ),
+        ));
+    }
+
+    let devices = resolve_devices(&state, devices.as_deref(), group.as_deref()).await?;
+
+    let (sender, receiver) = mpsc::channel(16);
+
+    tokio::spawn(async move {
+        if sender.send(format.header(true)).await.is_err() {
+            return;
+        }
+
+        for device in devices {
+            let name = device.conn_infos().name.clone();
+
+            for (start, end) in energy_requests(interval, from, to) {
+                let result = device
+                    .with_client(async |client| {
+                        fetch_energy_data(client, interval, start, end).await
+                    })
+                    .await
+                    .map_err(ApiError::from)
+                    .flatten();
+
+                let entries = match result {
+                    Ok(entries) => entries,
+
+                    // The response has already started, so the failure can't be reported to the client
+                    Err(err) => {
+                        warn!(
+                            "! Failed to export energy data of device '{name}', skipping it: {err}"
+                        );
+                        break;
+                    }
+                };
+
+                let chunk = entries
+                    .iter()
+                    .filter(|entry| is_in_range(entry, interval, from, to))
+                    .map(|entry| {
+                        format.row(&EnergyRow::new(
+                            Some(&name),
+                            interval,
+                            entry.start_date_time,
+                            entry.energy,
+                        ))
+                    })
+                    .collect::<String>();
+
+                if sender.send(chunk).await.is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    let body = Body::from_stream(stream::unfold(receiver, async |mut receiver| {
+        receiver
+            .recv()
+            .await
+            .map(|chunk| (Ok::<_, io::Error>(chunk), receiver))
+    }));
+
+    let file_name = format!(
+        "energy-{}-{from}-{to}.{}",
+        interval.name(),
+        format.extension()
+    );
+
+    Ok((
+        [(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{file_name}\""),
+        )],
+        format.response(body),
+    )
+        .into_response())
+}
+
+/// Find the devices to export, and ensure they all support energy monitoring
+async fn resolve_devices(
+    state: &SharedState,
+    devices: Option<&str>,
+    group: Option<&str>,
+) -> ApiResult<Vec<Arc<TapoDevice>>> {
+    let mut names = devices
+        .into_iter()
+        .flat_map(|devices| devices.split(','))
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_owned)
+        .collect::<Vec<_>>();
+
+    if let Some(group) = group {
+        let config = state.config.read().await;
+
+        let group_devices = config
+            .groups
+            .iter()
+            .find(|candidate| candidate.name == group)
+            .ok_or_else(|| {
+                ApiError::new(
+                    ErrorCode::GroupNotFound,
+                    format!("Group '{group}' was not found"),
+                )
+            })?;
+
+        // Devices without energy monitoring are skipped, as a group is not an explicit selection
+        names.extend(
+            group_devices
+                .devices
+                .iter()
+                .filter(|device| {
+                    config
+                        .devices
+                        .iter()
+                        .any(|infos| &infos.name == *device && infos.device_type.monitors_energy())
+                })
+                .cloned(),
+        );
+    }
+
+    let mut seen = vec![];
+    names.retain(|name| {
+        let first = !seen.contains(name);
+        seen.push(name.clone());
+        first
+    });
+
+    if names.is_empty() {
+        return Err(ApiError::new(
+            ErrorCode::InvalidParameter,
+            "No energy-monitoring device to export, provide either 'devices' or 'group'",
+        ));
+    }
+
+    let devices = state.devices.read().await;
+
+    names
+        .iter()
+        .map(|name| {
+            let device = devices
+                .get(name)
+                .ok_or_else(|| ApiError::device_not_found(name))?;
+
+            let device_type = device.conn_infos().device_type;
+
+            if !device_type.monitors_energy() {
+                return Err(ApiError::new(
+                    ErrorCode::UnsupportedAction,
+                    format!(
+                        "Energy export is not supported by {} {} devices",
+                        device_type.type_name(),
+                        device_type.type_description()
+                    ),
+                )
+                .with_device(name));
+            }
+
+            Ok(Arc::clone(device))
+        })
+        .collect()
+}
+
+/// Check if a period overlaps the exported days and already started
+fn is_in_range(
+    entry: &EnergyDataIntervalResult,
+    interval: EnergyInterval,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> bool {
+    let start = entry.start_date_time.with_timezone(&Local).date_naive();
+    let end = interval
+        .period_end(entry.start_date_time)
+        .with_timezone(&Local)
+        .naive_local();
+
+    // The end is exclusive, so it is compared to the start of the first day rather than its date
+    entry.start_date_time <= Utc::now() && start <= to && end > from.and_time(NaiveTime::MIN)
+}
+
+/// Document the energy export route in the `OpenAPI` specification
+pub fn add_export_to_openapi(spec: &mut OpenApiBuilder) {
+    let query_param = |name: &str, required: bool, description: &str, schema: serde_json::Value| {
+        json!({
+            "name": name,
+            "in": "query",
+            "required": required,
+            "description": description,
+            "schema": schema
+        })
+    };
+
+    spec.add_route(
+        "/export/energy",
+        &Method::GET,
+        json!({
+            "summary": "Export the energy usage of multiple devices over a range of days",
+            "parameters": [
+                query_param("devices", false, "Comma-separated names of the devices to export", json!({ "type": "string" })),
+                query_param("group", false, "Group whose energy-monitoring devices are exported", json!({ "type": "string" })),
+                query_param("interval", false, "Length of each exported period", json!({ "type": "string", "enum": ["hourly", "daily", "monthly"], "default": "hourly" })),
+                query_param("from", true, "First day of the export (inclusive)", json!({ "type": "string", "format": "date" })),
+                query_param("to", false, "Last day of the export (inclusive), defaults to today", json!({ "type": "string", "format": "date" })),
+                query_param("format", false, "Format of the exported file", json!({ "type": "string", "enum": ["csv", "ndjson"], "default": "csv" }))
+            ],
+            "responses": {
+                "200": {
+                    "description": "Success",
+                    "content": { "text/csv": {}, "application/x-ndjson": {} }
+                },
+                "400": OpenApiBuilder::error_response("Invalid parameter, range too large for the interval, or device doesn't support energy monitoring"),
+                "404": OpenApiBuilder::error_response("Device or group not found")
+            }
+        }),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::to_bytes, http::StatusCode};
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::server::state::StateData;
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    fn entry(date: NaiveDate, hour: u32) -> EnergyDataIntervalResult {
+        let start = Local
+            .from_local_datetime(&date.and_time(NaiveTime::from_hms_opt(hour, 0, 0).unwrap()))
+            .earliest()
+            .unwrap();
+
+        EnergyDataIntervalResult {
+            start_date_time: start.with_timezone(&Utc),
+            energy: 10,
+        }
+    }
+
+    #[test]
+    fn hourly_entries_within_the_exported_days() {
+        let (from, to) = (date(2024, 6, 10), date(2024, 6, 12));
+        let is_in = |entry| is_in_range(&entry, EnergyInterval::Hourly, from, to);
+
+        assert!(!is_in(entry(date(2024, 6, 9), 23)));
+        assert!(is_in(entry(date(2024, 6, 10), 0)));
+        assert!(is_in(entry(date(2024, 6, 10), 1)));
+        assert!(is_in(entry(date(2024, 6, 11), 0)));
+        assert!(is_in(entry(date(2024, 6, 12), 23)));
+        assert!(!is_in(entry(date(2024, 6, 13), 0)));
+    }
+
+    #[test]
+    fn periods_overlapping_the_exported_days() {
+        // Months and quarters are returned as a whole, and kept if they overlap the exported days
+        let (from, to) = (date(2024, 6, 10), date(2024, 7, 5));
+        let is_in = |entry| is_in_range(&entry, EnergyInterval::Monthly, from, to);
+
+        assert!(!is_in(entry(date(2024, 5, 1), 0)));
+        assert!(is_in(entry(date(2024, 6, 1), 0)));
+        assert!(is_in(entry(date(2024, 7, 1), 0)));
+        assert!(!is_in(entry(date(2024, 8, 1), 0)));
+    }
+
+    #[test]
+    fn future_periods_are_excluded() {
+        let today = Local::now().date_naive();
+        let tomorrow = today.succ_opt().unwrap();
+
+        assert!(is_in_range(
+            &entry(today, 0),
+            EnergyInterval::Daily,
+            today,
+            tomorrow
+        ));
+        assert!(!is_in_range(
+            &entry(tomorrow, 0),
+            EnergyInterval::Daily,
+            today,
+            tomorrow
+        ));
+    }
+
+    async fn export(query: &str) -> (StatusCode, serde_json::Value) {
+        let state = StateData::for_tests(json!({})).await;
+
+        let response = export_energy(
+            State(state),
+            Uri::try_from(format!("/export/energy?devices=plug&{query}")).unwrap(),
+        )
+        .await
+        .into_response();
+
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn export_range_is_limited_by_interval() {
+        let (status, body) = export("from=2024-01-01&to=2024-04-02").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body["detail"],
+            "hourly energy data can't be exported for more than 92 days at once"
+        );
+
+        // Ranges within the limit go on to resolving the devices
+        let (status, body) = export("from=2024-01-01&to=2024-04-01").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["code"], "device_not_found");
+
+        let (status, _) = export("interval=monthly&from=2015-01-01&to=2024-12-31").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
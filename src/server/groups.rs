@@ -0,0 +1,276 @@
+use axum::{
+    Json,
+    body::Bytes,
+    extract::{Path, State},
+    http::{Method, Uri},
+};
+use serde::Serialize;
+use serde_json::{Value, json};
+use tokio::task::JoinSet;
+
+use crate::config::DeviceGroup;
+
+use super::{
+    ApiError, ApiResult, ErrorCode, SharedState,
+    actions::{ActionOutput, check_method, dispatch_action},
+    openapi::OpenApiBuilder,
+};
+
+/// Outcome of an operation run on multiple devices
+#[derive(Serialize)]
+pub struct ActionReport {
+    succeeded: usize,
+    failed: usize,
+    results: Vec<DeviceActionResult>,
+}
+
+#[derive(Serialize)]
+pub struct DeviceActionResult {
+    device: String,
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ApiError>,
+}
+
+impl ActionReport {
+    /// Build a report from the result of the operation on each device
+    pub fn from_results(
+        results: impl IntoIterator<Item = (String, ApiResult<Option<Value>>)>,
+    ) -> Self {
+        let results = results
+            .into_iter()
+            .map(|(device, result)| match result {
+                Ok(output) => DeviceActionResult {
+                    device,
+                    success: true,
+                    output,
+                    error: None,
+                },
+
+                Err(err) => DeviceActionResult {
+                    device,
+                    success: false,
+                    output: None,
+                    error: Some(err),
+                },
+            })
+            .collect::<Vec<_>>();
+
+        let succeeded = results.iter().filter(|result| result.success).count();
+
+        Self {
+            succeeded,
+            failed: results.len() - succeeded,
+            results,
+        }
+    }
+
+    /// Errors that occurred on the devices, if any
+    pub fn errors(&self) -> impl Iterator<Item = &ApiError> {
+        self.results
+            .iter()
+            .filter_map(|result| result.error.as_ref())
+    }
+}
+
+pub async fn list_groups(State(state): State<SharedState>) -> Json<Vec<DeviceGroup>> {
+    Json(state.config.read().await.groups.clone())
+}
+
+pub async fn run_group_action(
+    State(state): State<SharedState>,
+    Path((group, action)): Path<(String, String)>,
+    method: Method,
+    uri: Uri,
+    body: Bytes,
+) -> ApiResult<Json<ActionReport>> {
+    dispatch_group_action(&state, &group, &action, &method, &uri, &body)
+        .await
+        .map(Json)
+}
+
+/// Run an action concurrently on all devices of a group
+///
+/// Failures on some devices don't prevent the action from running on the other ones, but actions that no device
+/// of the group supports, or called with the wrong HTTP method, are rejected as a whole.
+pub async fn dispatch_group_action(
+    state: &SharedState,
+    group: &str,
+    action: &str,
+    method: &Method,
+    uri: &Uri,
+    body: &Bytes,
+) -> ApiResult<ActionReport> {
+    let members = state
+        .config
+        .read()
+        .await
+        .groups
+        .iter()
+        .find(|candidate| candidate.name == group)
+        .map(|group| group.devices.clone())
+        .ok_or_else(|| {
+            ApiError::new(
+                ErrorCode::GroupNotFound,
+                format!("Group '{group}' was not found"),
+            )
+        })?;
+
+    let expected_method = {
+        let devices = state.devices.read().await;
+
+        members
+            .iter()
+            .filter_map(|member| devices.get(member))
+            .find_map(|device| device.conn_infos().device_type.action_method(action))
+    }
+    .ok_or_else(|| {
+        ApiError::new(
+            ErrorCode::UnsupportedAction,
+            format!("Action '{action}' is not supported by any device of group '{group}'"),
+        )
+    })?;
+
+    check_method(state, method, &expected_method).await?;
+
+    let mut tasks = JoinSet::new();
+
+    for (index, device) in members.into_iter().enumerate() {
+        let state = SharedState::clone(state);
+        let action = action.to_owned();
+        let method = method.clone();
+        let uri = uri.clone();
+        let body = body.clone();
+
+        tasks.spawn(async move {
+            let result = dispatch_action(&state, &device, &action, &method, &uri, &body).await;
+            (index, device, result)
+        });
+    }
+
+    let mut results = tasks.join_all().await;
+
+    // Keep the same order as in the group's configuration
+    results.sort_by_key(|(index, _, _)| *index);
+
+    Ok(ActionReport::from_results(results.into_iter().map(
+        |(_, device, result)| (device, result.map(ActionOutput::into_value)),
+    )))
+}
+
+/// Document the group routes in the `OpenAPI` specification
+pub fn add_groups_to_openapi(spec: &mut OpenApiBuilder) {
+    spec.add_route(
+        "/groups",
+        &Method::GET,
+        json!({
+            "summary": "List all device groups",
+            "responses": { "200": { "description": "Success" } }
+        }),
+    );
+
+    let group_action = json!({
+        "summary": "Run an action concurrently on all devices of a group, and report the result for each device",
+        "parameters": [
+            {
+                "name": "name",
+                "in": "path",
+                "required": true,
+                "schema": { "type": "string" }
+            },
+            {
+                "name": "action",
+                "in": "path",
+                "required": true,
+                "schema": { "type": "string" }
+            }
+        ],
+        "responses": {
+            "200": { "description": "Success" },
+            "400": OpenApiBuilder::error_response("No device of the group supports the action"),
+            "404": OpenApiBuilder::error_response("Group not found"),
+            "405": OpenApiBuilder::error_response("Action called with the wrong HTTP method")
+        }
+    });
+
+    spec.add_route(
+        "/groups/{name}/{action}",
+        &Method::GET,
+        group_action.clone(),
+    );
+    spec.add_route("/groups/{name}/{action}", &Method::POST, group_action);
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::to_bytes, http::StatusCode, response::IntoResponse};
+
+    use super::*;
+    use crate::server::state::StateData;
+
+    async fn group_state() -> SharedState {
+        StateData::for_tests(json!({
+            "devices": [
+                { "name": "lamp", "device_type": "L530", "ip_addr": "127.0.0.1" },
+                { "name": "plug", "device_type": "P100", "ip_addr": "127.0.0.1" }
+            ],
+            "groups": [{ "name": "living-room", "devices": ["lamp", "plug"] }]
+        }))
+        .await
+    }
+
+    async fn run(
+        state: &SharedState,
+        method: Method,
+        group: &str,
+        action: &str,
+    ) -> (StatusCode, serde_json::Value) {
+        let response = run_group_action(
+            State(SharedState::clone(state)),
+            Path((group.to_owned(), action.to_owned())),
+            method,
+            Uri::try_from(format!("/groups/{group}/{action}")).unwrap(),
+            Bytes::new(),
+        )
+        .await
+        .into_response();
+
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn unknown_group() {
+        let state = group_state().await;
+
+        let (status, body) = run(&state, Method::POST, "kitchen", "off").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["code"], "group_not_found");
+    }
+
+    #[tokio::test]
+    async fn action_unsupported_by_every_member() {
+        let state = group_state().await;
+
+        let (status, body) = run(&state, Method::POST, "living-room", "dance").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "unsupported_action");
+        assert_eq!(
+            body["detail"],
+            "Action 'dance' is not supported by any device of group 'living-room'"
+        );
+    }
+
+    #[tokio::test]
+    async fn action_called_with_the_wrong_method() {
+        let state = group_state().await;
+
+        let (status, body) = run(&state, Method::GET, "living-room", "off").await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(body["code"], "method_not_allowed");
+    }
+}
@@ -0,0 +1,198 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tapo::{
+    HubDevice, HubHandler,
+    responses::{ChildDeviceHubResult, TriggerLogsResult},
+};
+
+use super::{ApiError, ApiResult, ErrorCode, actions::child_device_error};
+
+/// Number of trigger logs returned when no page size is provided
+const DEFAULT_TRIGGER_LOGS_PAGE_SIZE: u64 = 20;
+
+/// Trigger logs of a hub's sensor or button, newest first
+#[derive(Serialize, Deserialize)]
+pub struct TriggerLogs {
+    /// Identifier of the most recent returned log
+    start_id: u64,
+    /// Total number of logs held by the hub for this device
+    sum: u64,
+    logs: Vec<Value>,
+}
+
+impl<T: Serialize> TryFrom<TriggerLogsResult<T>> for TriggerLogs {
+    type Error = ApiError;
+
+    fn try_from(result: TriggerLogsResult<T>) -> ApiResult<Self> {
+        let logs = result
+            .logs
+            .into_iter()
+            .map(serde_json::to_value)
+            .collect::<Result<_, _>>()
+            .map_err(|err| {
+                ApiError::new(
+                    ErrorCode::InternalError,
+                    format!("Failed to serialize trigger logs: {err}"),
+                )
+            })?;
+
+        Ok(Self {
+            start_id: result.start_id,
+            sum: result.sum,
+            logs,
+        })
+    }
+}
+
+/// Identify a hub's child device from the parameters of an action, exactly one of which must be provided
+pub fn hub_child_identifier(
+    device_id: Option<String>,
+    nickname: Option<String>,
+) -> ApiResult<HubDevice> {
+    match (device_id, nickname) {
+        (Some(device_id), None) => Ok(HubDevice::ByDeviceId(device_id)),
+        (None, Some(nickname)) => Ok(HubDevice::ByNickname(nickname)),
+        _ => Err(ApiError::new(
+            ErrorCode::InvalidParameter,
+            "Exactly one of 'device_id' or 'nickname' must be provided to identify the child device",
+        )),
+    }
+}
+
+/// Get the current state of a hub's child device, whatever its type
+pub async fn get_hub_child(
+    hub: &HubHandler,
+    identifier: HubDevice,
+) -> ApiResult<ChildDeviceHubResult> {
+    hub.get_child_device_list()
+        .await?
+        .into_iter()
+        .find(|child| {
+            let (device_id, nickname) = child_ids(child);
+
+            match &identifier {
+                HubDevice::ByDeviceId(id) => id == device_id,
+                HubDevice::ByNickname(name) => name == nickname,
+            }
+        })
+        .ok_or_else(|| child_device_error(tapo::Error::DeviceNotFound))
+}
+
+/// Get the trigger logs of a hub's motion, contact or water sensor, or of a button
+pub async fn get_hub_trigger_logs(
+    hub: &HubHandler,
+    identifier: HubDevice,
+    page_size: Option<u64>,
+    start_id: Option<u64>,
+) -> ApiResult<TriggerLogs> {
+    let page_size = page_size.unwrap_or(DEFAULT_TRIGGER_LOGS_PAGE_SIZE);
+    let start_id = start_id.unwrap_or(0);
+
+    let child = get_hub_child(hub, identifier).await?;
+    let device = HubDevice::ByDeviceId(child_ids(&child).0.to_owned());
+
+    match child {
+        ChildDeviceHubResult::T100(_) => hub
+            .t100(device)
+            .await?
+            .get_trigger_logs(page_size, start_id)
+            .await?
+            .try_into(),
+
+        ChildDeviceHubResult::T110(_) => hub
+            .t110(device)
+            .await?
+            .get_trigger_logs(page_size, start_id)
+            .await?
+            .try_into(),
+
+        ChildDeviceHubResult::T300(_) => hub
+            .t300(device)
+            .await?
+            .get_trigger_logs(page_size, start_id)
+            .await?
+            .try_into(),
+
+        ChildDeviceHubResult::S200(_) => hub
+            .s200(device)
+            .await?
+            .get_trigger_logs(page_size, start_id)
+            .await?
+            .try_into(),
+
+        ChildDeviceHubResult::KE100(_)
+        | ChildDeviceHubResult::S210(_)
+        | ChildDeviceHubResult::T31X(_)
+        | ChildDeviceHubResult::Other(_) => Err(ApiError::new(
+            ErrorCode::UnsupportedAction,
+            "This child device doesn't record trigger logs",
+        )),
+    }
+}
+
+/// Device ID and nickname of a hub's child device
+fn child_ids(child: &ChildDeviceHubResult) -> (&str, &str) {
+    match child {
+        ChildDeviceHubResult::KE100(child) => (&child.device_id, &child.nickname),
+        ChildDeviceHubResult::S200(child) => (&child.device_id, &child.nickname),
+        ChildDeviceHubResult::S210(child) => (&child.device_id, &child.nickname),
+        ChildDeviceHubResult::T100(child) => (&child.device_id, &child.nickname),
+        ChildDeviceHubResult::T110(child) => (&child.device_id, &child.nickname),
+        ChildDeviceHubResult::T300(child) => (&child.device_id, &child.nickname),
+        ChildDeviceHubResult::T31X(child) => (&child.device_id, &child.nickname),
+        ChildDeviceHubResult::Other(child) => (&child.device_id, &child.nickname),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{http::StatusCode, response::IntoResponse};
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn child_identified_by_exactly_one_parameter() {
+        assert!(matches!(
+            hub_child_identifier(Some("8022".to_owned()), None),
+            Ok(HubDevice::ByDeviceId(device_id)) if device_id == "8022"
+        ));
+        assert!(matches!(
+            hub_child_identifier(None, Some("Hallway".to_owned())),
+            Ok(HubDevice::ByNickname(nickname)) if nickname == "Hallway"
+        ));
+
+        for (device_id, nickname) in [(None, None), (Some("8022"), Some("Hallway"))] {
+            let err =
+                hub_child_identifier(device_id.map(str::to_owned), nickname.map(str::to_owned))
+                    .err()
+                    .unwrap();
+
+            assert_eq!(err.into_response().status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[test]
+    fn trigger_logs_keep_their_order() {
+        let result = TriggerLogsResult {
+            start_id: 12,
+            sum: 40,
+            logs: vec![
+                json!({ "id": 12, "event": "open" }),
+                json!({ "id": 11, "event": "close" }),
+            ],
+        };
+
+        assert_eq!(
+            serde_json::to_value(TriggerLogs::try_from(result).ok().unwrap()).unwrap(),
+            json!({
+                "start_id": 12,
+                "sum": 40,
+                "logs": [
+                    { "id": 12, "event": "open" },
+                    { "id": 11, "event": "close" }
+                ]
+            })
+        );
+    }
+}
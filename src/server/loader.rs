@@ -5,22 +5,44 @@ use colored::Colorize;
 use log::{error, info};
 use tokio::task::JoinSet;
 
-use crate::{config::Config, devices::TapoDevice};
+use crate::{
+    config::{Config, TapoConnectionInfos, TapoCredentials},
+    devices::TapoDevice,
+};
 
 pub async fn load_tapo_devices(config: &Config) -> Result<Vec<TapoDevice>> {
     let Config {
         devices,
         tapo_credentials,
+        groups: _,
+        scenes: _,
+        schedules: _,
+        location: _,
+        events: _,
+        webhooks: _,
+        mqtt: _,
+        energy_history: _,
+        tariffs: _,
         server: _,
     } = config;
 
-    let mut tasks = JoinSet::new();
-
     info!(
         "Attempting to connect to the {} configured device(s)...",
         devices.len()
     );
 
+    connect_tapo_devices(devices, tapo_credentials).await
+}
+
+/// Connect to the provided devices concurrently
+///
+/// Devices that can't be connected to are still returned, as they may become reachable later on.
+pub async fn connect_tapo_devices(
+    devices: &[TapoConnectionInfos],
+    tapo_credentials: &TapoCredentials,
+) -> Result<Vec<TapoDevice>> {
+    let mut tasks = JoinSet::new();
+
     let tapo_credentials = Arc::new(tapo_credentials.clone());
 
     for conn_infos in devices {
@@ -43,7 +65,7 @@ pub async fn load_tapo_devices(config: &Config) -> Result<Vec<TapoDevice>> {
         match conn_result {
             Ok(()) => info!("|> Device {} connected successfully!", name.bright_yellow()),
 
-            Err(err) => error!("! Failed to connect to device '{name}': {err}"),
+            Err(err) => error!("! Failed to connect to device '{name}': {err:#}"),
         }
 
         devices.push(device);
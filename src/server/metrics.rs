@@ -0,0 +1,431 @@
+use std::{
+    collections::HashMap,
+    fmt::{Display, Write},
+    sync::Arc,
+    time::Instant,
+};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{Method, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
+
+use crate::{
+    config::TapoConnectionInfos,
+    devices::{DeviceHealth, DeviceStatus},
+};
+
+use super::{SharedState, events::DeviceSnapshot, openapi::OpenApiBuilder};
+
+/// Upper bounds of the request duration histogram's buckets, in seconds
+const DURATION_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Requests statistics for a single route and method
+#[derive(Default)]
+pub struct RouteMetrics {
+    requests: u64,
+    errors: u64,
+    duration_sum: f64,
+    /// Number of requests that took less than each bucket's upper bound
+    duration_buckets: [u64; DURATION_BUCKETS.len()],
+}
+
+/// Requests statistics, indexed by method and route
+pub type RequestMetrics = HashMap<(Method, String), RouteMetrics>;
+
+/// Record the number of requests, errors and durations of each route
+pub async fn track_requests(
+    State(state): State<SharedState>,
+    matched_path: Option<MatchedPath>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(route) = matched_path.map(|path| path.as_str().to_owned()) else {
+        return next.run(request).await;
+    };
+
+    let method = request.method().clone();
+    let start = Instant::now();
+
+    let response = next.run(request).await;
+
+    let duration = start.elapsed().as_secs_f64();
+    let is_error = response.status().is_client_error() || response.status().is_server_error();
+
+    let mut metrics = state.request_metrics.write().await;
+    let route = metrics.entry((method, route)).or_default();
+
+    route.requests += 1;
+    route.duration_sum += duration;
+
+    if is_error {
+        route.errors += 1;
+    }
+
+    for (bucket, bound) in route.duration_buckets.iter_mut().zip(DURATION_BUCKETS) {
+        if duration <= bound {
+            *bucket += 1;
+        }
+    }
+
+    response
+}
+
+/// Export metrics in the Prometheus text format
+///
+/// Device metrics come from the poller's cache, so scraping doesn't send any request to the devices.
+pub async fn export_metrics(State(state): State<SharedState>) -> Response {
+    let mut out = Metrics::default();
+
+    let devices = state
+        .devices
+        .read()
+        .await
+        .values()
+        .map(Arc::clone)
+        .collect::<Vec<_>>();
+
+    let snapshots = state.device_states.read().await.clone();
+
+    for device in devices {
+        let health = device.health().await;
+        let snapshot = snapshots.get(&device.conn_infos().name);
+
+        add_device_metrics(&mut out, device.conn_infos(), &health, snapshot);
+    }
+
+    add_request_metrics(&mut out, &*state.request_metrics.read().await);
+
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        out.finish(),
+    )
+        .into_response()
+}
+
+fn add_device_metrics(
+    out: &mut Metrics,
+    infos: &TapoConnectionInfos,
+    health: &DeviceHealth,
+    snapshot: Option<&DeviceSnapshot>,
+) {
+    let labels = [
+        ("device", infos.name.as_str()),
+        ("model", infos.device_type.type_name()),
+    ];
+
+    out.gauge(
+        "tapo_device_up",
+        "Whether the device can currently be reached",
+        &labels,
+        u8::from(health.status == DeviceStatus::Connected),
+    );
+
+    out.gauge(
+        "tapo_device_consecutive_failures",
+        "Number of consecutive failed attempts to reach the device",
+        &labels,
+        health.consecutive_failures,
+    );
+
+    if let Some(last_seen) = health.last_seen {
+        out.gauge(
+            "tapo_device_last_seen_timestamp_seconds",
+            "Last time the device could be reached",
+            &labels,
+            last_seen.timestamp(),
+        );
+    }
+
+    let Some(snapshot) = snapshot else {
+        return;
+    };
+
+    if let Some(on) = snapshot.on {
+        out.gauge(
+            "tapo_device_on",
+            "Whether the device is turned on",
+            &labels,
+            u8::from(on),
+        );
+    }
+
+    if let Some(rssi) = snapshot.rssi {
+        out.gauge(
+            "tapo_device_rssi_dbm",
+            "Wi-Fi signal strength of the device",
+            &labels,
+            rssi,
+        );
+    }
+
+    // Energy of power strips is only available per socket
+    let mut energy = vec![(
+        labels.to_vec(),
+        snapshot.current_power_milliwatts,
+        snapshot.today_energy,
+        snapshot.month_energy,
+    )];
+
+    // Sockets are identified by their position, as their nicknames may be empty or duplicated
+    let positions = snapshot
+        .sockets
+        .iter()
+        .map(|socket| socket.position.to_string())
+        .collect::<Vec<_>>();
+
+    for (socket, position) in snapshot.sockets.iter().zip(&positions) {
+        let mut labels = labels.to_vec();
+        labels.push(("socket", position));
+        labels.push(("socket_nickname", &socket.nickname));
+
+        out.gauge(
+            "tapo_socket_on",
+            "Whether the power strip's socket is turned on",
+            &labels,
+            u8::from(socket.on),
+        );
+
+        energy.push((
+            labels,
+            socket.current_power_milliwatts,
+            socket.today_energy,
+            socket.month_energy,
+        ));
+    }
+
+    for (labels, current_power, today_energy, month_energy) in energy {
+        if let Some(current_power) = current_power {
+            out.gauge(
+                "tapo_current_power_watts",
+                "Current power drawn by the device",
+                &labels,
+                format_milliwatts(current_power),
+            );
+        }
+
+        if let Some(today_energy) = today_energy {
+            out.gauge(
+                "tapo_today_energy_watt_hours",
+                "Energy used by the device today",
+                &labels,
+                today_energy,
+            );
+        }
+
+        if let Some(month_energy) = month_energy {
+            out.gauge(
+                "tapo_month_energy_watt_hours",
+                "Energy used by the device this month",
+                &labels,
+                month_energy,
+            );
+        }
+    }
+}
+
+/// Format a power in milliwatts as watts, without losing precision
+fn format_milliwatts(milliwatts: u64) -> String {
+    format!("{}.{:03}", milliwatts / 1000, milliwatts % 1000)
+}
+
+fn add_request_metrics(out: &mut Metrics, metrics: &RequestMetrics) {
+    for ((method, route), metrics) in metrics {
+        let labels = [("method", method.as_str()), ("route", route.as_str())];
+
+        out.counter(
+            "tapo_rest_http_requests_total",
+            "Number of HTTP requests handled",
+            &labels,
+            metrics.requests,
+        );
+
+        out.counter(
+            "tapo_rest_http_request_errors_total",
+            "Number of HTTP requests that resulted in a 4xx or 5xx status",
+            &labels,
+            metrics.errors,
+        );
+
+        out.histogram(
+            "tapo_rest_http_request_duration_seconds",
+            "Duration of HTTP requests",
+            &labels,
+            metrics,
+        );
+    }
+}
+
+/// Builder for the Prometheus text format
+///
+/// Samples of a same metric are grouped together, as required by the format.
+#[derive(Default)]
+struct Metrics {
+    families: Vec<(&'static str, &'static str, &'static str, String)>,
+}
+
+impl Metrics {
+    fn gauge(
+        &mut self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&str, &str)],
+        value: impl Display,
+    ) {
+        let sample = format!("{name}{} {value}\n", format_labels(labels));
+        self.family(name, help, "gauge").push_str(&sample);
+    }
+
+    fn counter(
+        &mut self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&str, &str)],
+        value: u64,
+    ) {
+        let sample = format!("{name}{} {value}\n", format_labels(labels));
+        self.family(name, help, "counter").push_str(&sample);
+    }
+
+    fn histogram(
+        &mut self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&str, &str)],
+        metrics: &RouteMetrics,
+    ) {
+        let mut samples = String::new();
+
+        for (count, bound) in metrics.duration_buckets.iter().zip(DURATION_BUCKETS) {
+            let bound = bound.to_string();
+            let mut labels = labels.to_vec();
+            labels.push(("le", &bound));
+
+            let _ = writeln!(samples, "{name}_bucket{} {count}", format_labels(&labels));
+        }
+
+        let mut labels_inf = labels.to_vec();
+        labels_inf.push(("le", "+Inf"));
+
+        let labels_inf = format_labels(&labels_inf);
+        let labels = format_labels(labels);
+
+        let _ = writeln!(samples, "{name}_bucket{labels_inf} {}", metrics.requests);
+        let _ = writeln!(samples, "{name}_sum{labels} {}", metrics.duration_sum);
+        let _ = writeln!(samples, "{name}_count{labels} {}", metrics.requests);
+
+        self.family(name, help, "histogram").push_str(&samples);
+    }
+
+    fn family(
+        &mut self,
+        name: &'static str,
+        help: &'static str,
+        kind: &'static str,
+    ) -> &mut String {
+        if !self.families.iter().any(|(other, ..)| *other == name) {
+            self.families.push((name, help, kind, String::new()));
+        }
+
+        let (.., samples) = self
+            .families
+            .iter_mut()
+            .find(|(other, ..)| *other == name)
+            .unwrap();
+
+        samples
+    }
+
+    fn finish(self) -> String {
+        let mut out = String::new();
+
+        for (name, help, kind, samples) in self.families {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} {kind}");
+            out.push_str(&samples);
+        }
+
+        out
+    }
+}
+
+fn format_labels(labels: &[(&str, &str)]) -> String {
+    let labels = labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+
+            format!("{name}=\"{value}\"")
+        })
+        .collect::<Vec<_>>();
+
+    format!("{{{}}}", labels.join(","))
+}
+
+/// Document the metrics route in the `OpenAPI` specification
+pub fn add_metrics_to_openapi(spec: &mut OpenApiBuilder) {
+    spec.add_route(
+        "/metrics",
+        &Method::GET,
+        json!({
+            "summary": "Export device and server metrics in the Prometheus text format",
+            "responses": {
+                "200": {
+                    "description": "Success",
+                    "content": { "text/plain": {} }
+                }
+            }
+        }),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn label_values_are_escaped() {
+        assert_eq!(
+            format_labels(&[
+                ("device", "desk \"lamp\""),
+                ("socket_nickname", "C:\\fan\nnew line"),
+            ]),
+            r#"{device="desk \"lamp\"",socket_nickname="C:\\fan\nnew line"}"#
+        );
+    }
+
+    #[test]
+    fn samples_are_grouped_by_metric() {
+        let mut metrics = Metrics::default();
+
+        metrics.gauge("tapo_device_up", "Up", &[("device", "a")], 1);
+        metrics.counter("tapo_requests", "Requests", &[("device", "a")], 3);
+        metrics.gauge("tapo_device_up", "Up", &[("device", "b")], 0);
+
+        assert_eq!(
+            metrics.finish(),
+            "# HELP tapo_device_up Up\n\
+             # TYPE tapo_device_up gauge\n\
+             tapo_device_up{device=\"a\"} 1\n\
+             tapo_device_up{device=\"b\"} 0\n\
+             # HELP tapo_requests Requests\n\
+             # TYPE tapo_requests counter\n\
+             tapo_requests{device=\"a\"} 3\n"
+        );
+    }
+
+    #[test]
+    fn milliwatts_keep_their_precision() {
+        assert_eq!(format_milliwatts(0), "0.000");
+        assert_eq!(format_milliwatts(7), "0.007");
+        assert_eq!(format_milliwatts(12_345), "12.345");
+    }
+}
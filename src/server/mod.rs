@@ -1,40 +1,94 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{net::IpAddr, path::PathBuf, sync::Arc};
 
 use anyhow::{Context, Result};
 use axum::{
     Json, Router,
-    extract::{Query, State},
-    http::StatusCode,
+    body::Bytes,
+    extract::{Path, State},
+    http::{Method, Uri},
     middleware,
     routing::{get, post},
 };
 use colored::Colorize;
 use log::{error, info};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use tokio::net::TcpListener;
 use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer};
 
-use crate::{config::TapoConnectionInfos, server::actions::make_actions_router};
+use crate::{
+    config::TapoConnectionInfos,
+    devices::DeviceHealth,
+    server::actions::{
+        add_actions_to_openapi, check_method, make_actions_router, parse_params, run_device_action,
+    },
+};
 
-use self::{auth::auth_middleware, state::StateData};
+use self::{
+    auth::auth_middleware,
+    costs::{add_costs_to_openapi, get_device_costs, get_group_costs},
+    discover::{add_discover_to_openapi, discover},
+    energy::{
+        add_energy_to_openapi, collect_energy_history, get_energy_history, get_power_history,
+    },
+    events::{add_events_to_openapi, poll_device_states, stream_events, stream_events_ws},
+    export::{add_export_to_openapi, export_energy},
+    groups::{add_groups_to_openapi, list_groups, run_group_action},
+    metrics::{add_metrics_to_openapi, export_metrics, track_requests},
+    mqtt::run_mqtt_bridge,
+    openapi::OpenApiBuilder,
+    scenes::{add_scenes_to_openapi, apply_scene, capture_scene, list_scenes},
+    scheduler::{
+        add_schedules_to_openapi, disable_schedule, enable_schedule, list_schedules, run_scheduler,
+    },
+    state::{ConfigDiff, StateData},
+    supervisor::supervise_devices,
+    watcher::watch_config_file,
+    webhooks::{add_webhooks_to_openapi, deliver_webhooks, list_deliveries, list_webhooks},
+};
 
 mod actions;
 mod auth;
+mod costs;
+mod discover;
+mod energy;
 mod errors;
+mod events;
+mod export;
+mod groups;
+mod hubs;
 mod loader;
+mod metrics;
+mod mqtt;
+mod openapi;
+mod scenes;
+mod scheduler;
 mod state;
+mod sun;
+mod supervisor;
+mod watcher;
+mod webhooks;
 
-pub use actions::TapoDeviceType;
-pub use errors::{ApiError, ApiResult};
+pub use actions::{Capability, TapoDeviceType};
+pub use errors::{ApiError, ApiResult, ErrorCode};
+pub use events::ThresholdDirection;
 
 pub type SharedState = Arc<StateData>;
 
 pub struct ServeOptions {
     pub config_path: PathBuf,
     pub port: u16,
+    /// Reload the configuration when its file changes
+    pub watch_config: bool,
 }
 
-pub async fn serve(ServeOptions { config_path, port }: ServeOptions) -> Result<()> {
+pub async fn serve(
+    ServeOptions {
+        config_path,
+        port,
+        watch_config,
+    }: ServeOptions,
+) -> Result<()> {
     let cors = CorsLayer::new()
         .allow_methods(AllowMethods::any())
         .allow_headers(AllowHeaders::any())
@@ -45,15 +99,77 @@ pub async fn serve(ServeOptions { config_path, port }: ServeOptions) -> Result<(
 
     let (actions_router, actions_route_uris) = make_actions_router();
 
+    let openapi_spec = make_openapi_spec();
+
     let state = Arc::new(StateData::init(config_path).await?);
 
+    tokio::spawn(supervise_devices(Arc::clone(&state)));
+    tokio::spawn(run_scheduler(Arc::clone(&state)));
+    tokio::spawn(poll_device_states(Arc::clone(&state)));
+    tokio::spawn(deliver_webhooks(Arc::clone(&state)));
+    tokio::spawn(run_mqtt_bridge(Arc::clone(&state)));
+    tokio::spawn(collect_energy_history(Arc::clone(&state)));
+
+    #[cfg(unix)]
+    tokio::spawn(watcher::reload_on_sighup(Arc::clone(&state)));
+
+    if watch_config {
+        tokio::spawn(watch_config_file(Arc::clone(&state)));
+    }
+
     let app = Router::new()
         // Reload the configuration file
         .route("/reload-config", post(reload_config))
         // Refresh a device's session
-        .route("/refresh-session", get(refresh_session))
+        .route(
+            "/refresh-session",
+            post(refresh_session).get(refresh_session),
+        )
         // List all available devices
         .route("/devices", get(list_devices))
+        // List the capabilities of a device
+        .route("/devices/{name}/capabilities", get(device_capabilities))
+        // Run an action on a device, regardless of its type
+        .route(
+            "/devices/{name}/{action}",
+            get(run_device_action).post(run_device_action),
+        )
+        // Discover the devices of the local network
+        .route("/discover", get(discover))
+        // List all device groups
+        .route("/groups", get(list_groups))
+        // Run an action on all devices of a group
+        .route(
+            "/groups/{name}/{action}",
+            get(run_group_action).post(run_group_action),
+        )
+        // List all scenes
+        .route("/scenes", get(list_scenes))
+        // Apply a scene
+        .route("/scenes/{name}/apply", post(apply_scene))
+        // Capture the current state of devices into a scene
+        .route("/scenes/{name}/capture", post(capture_scene))
+        // List all schedules
+        .route("/schedules", get(list_schedules))
+        // Enable or disable a schedule
+        .route("/schedules/{name}/enable", post(enable_schedule))
+        .route("/schedules/{name}/disable", post(disable_schedule))
+        // Stream device state changes
+        .route("/events", get(stream_events))
+        .route("/events/ws", get(stream_events_ws))
+        // List all webhooks
+        .route("/webhooks", get(list_webhooks))
+        // List the most recent webhook deliveries
+        .route("/webhooks/deliveries", get(list_deliveries))
+        // Get the stored energy history of a device
+        .route("/energy/{device}", get(get_energy_history))
+        .route("/energy/{device}/power", get(get_power_history))
+        .route("/export/energy", get(export_energy))
+        // Compute energy costs
+        .route("/costs/devices/{name}", get(get_device_costs))
+        .route("/costs/groups/{name}", get(get_group_costs))
+        // Export metrics for Prometheus
+        .route("/metrics", get(export_metrics))
         // Nested action routes
         .nest("/actions", actions_router)
         // Add authentication layer for all routes above
@@ -66,8 +182,15 @@ pub async fn serve(ServeOptions { config_path, port }: ServeOptions) -> Result<(
             "/actions",
             get(|| async move { actions_route_uris.join("\n") }),
         )
+        // OpenAPI specification of all routes
+        .route("/openapi.json", get(|| async move { Json(openapi_spec) }))
+        // Record statistics about all routes above
+        .route_layer(middleware::from_fn_with_state(
+            Arc::clone(&state),
+            track_requests,
+        ))
         .layer(cors)
-        .with_state(state);
+        .with_state(Arc::clone(&state));
 
     let addr = format!("0.0.0.0:{port}");
 
@@ -81,37 +204,169 @@ pub async fn serve(ServeOptions { config_path, port }: ServeOptions) -> Result<(
     let tcp_listener = TcpListener::bind(addr).await?;
 
     axum::serve(tcp_listener, app.into_make_service())
-        .with_graceful_shutdown(shutdown_signal())
+        .with_graceful_shutdown(shutdown_signal(state))
         .await
         .map_err(Into::into)
 }
 
-async fn shutdown_signal() {
+fn make_openapi_spec() -> serde_json::Value {
+    let mut spec = OpenApiBuilder::new();
+
+    add_actions_to_openapi(&mut spec);
+    add_discover_to_openapi(&mut spec);
+    add_groups_to_openapi(&mut spec);
+    add_scenes_to_openapi(&mut spec);
+    add_schedules_to_openapi(&mut spec);
+    add_events_to_openapi(&mut spec);
+    add_webhooks_to_openapi(&mut spec);
+    add_energy_to_openapi(&mut spec);
+    add_costs_to_openapi(&mut spec);
+    add_export_to_openapi(&mut spec);
+    add_metrics_to_openapi(&mut spec);
+
+    let device_param = json!({
+        "name": "name",
+        "in": "path",
+        "required": true,
+        "schema": { "type": "string" }
+    });
+
+    spec.add_route(
+        "/devices",
+        &Method::GET,
+        json!({
+            "summary": "List all configured devices, along with their capabilities and health",
+            "responses": { "200": { "description": "Success" } }
+        }),
+    );
+
+    spec.add_route(
+        "/devices/{name}/capabilities",
+        &Method::GET,
+        json!({
+            "summary": "List the capabilities of a device",
+            "parameters": [device_param],
+            "responses": {
+                "200": {
+                    "description": "Success",
+                    "content": {
+                        "application/json": {
+                            "schema": { "type": "array", "items": { "type": "string" } }
+                        }
+                    }
+                },
+                "404": OpenApiBuilder::error_response("Device not found")
+            }
+        }),
+    );
+
+    spec.add_route(
+        "/refresh-session",
+        &Method::POST,
+        json!({
+            "summary": "Refresh the session with a device",
+            "description": "Can also be called with GET if legacy GET actions are allowed in the configuration",
+            "parameters": [{
+                "name": "device",
+                "in": "query",
+                "required": true,
+                "schema": { "type": "string" }
+            }],
+            "responses": {
+                "200": { "description": "Success" },
+                "404": OpenApiBuilder::error_response("Device not found"),
+                "405": OpenApiBuilder::error_response("Called with the wrong HTTP method")
+            }
+        }),
+    );
+
+    spec.add_route(
+        "/reload-config",
+        &Method::POST,
+        json!({
+            "summary": "Reload the configuration file",
+            "description": "Only the added or changed devices are connected to, unchanged devices keep their session",
+            "responses": {
+                "200": {
+                    "description": "Names of the added, changed, removed and unchanged devices",
+                    "content": {
+                        "application/json": {
+                            "schema": {
+                                "type": "object",
+                                "properties": {
+                                    "added": { "type": "array", "items": { "type": "string" } },
+                                    "changed": { "type": "array", "items": { "type": "string" } },
+                                    "removed": { "type": "array", "items": { "type": "string" } },
+                                    "unchanged": { "type": "array", "items": { "type": "string" } }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }),
+    );
+
+    spec.build()
+}
+
+async fn shutdown_signal(state: SharedState) {
     if let Err(err) = tokio::signal::ctrl_c().await {
         error!("Failed to listen for shutdown signal: {err}");
     }
     info!("Received shutdown signal, shutting down gracefully...");
+
+    // Close event streams, which would otherwise prevent the server from shutting down
+    state.shutdown.send_replace(true);
 }
 
-async fn list_devices(state: State<Arc<StateData>>) -> Json<Vec<TapoConnectionInfos>> {
-    Json(
-        state
-            .devices
-            .read()
-            .await
-            .values()
-            .map(|dev| dev.conn_infos().clone())
-            .collect(),
-    )
+#[derive(Serialize)]
+struct DeviceDetails {
+    #[serde(flatten)]
+    conn_infos: TapoConnectionInfos,
+    /// Address the device is currently reached at, which may have been resolved from its MAC address
+    current_ip_addr: Option<IpAddr>,
+    capabilities: &'static [Capability],
+    health: DeviceHealth,
 }
 
-async fn reload_config(state: State<Arc<StateData>>) -> ApiResult<()> {
-    state
+async fn list_devices(state: State<Arc<StateData>>) -> Json<Vec<DeviceDetails>> {
+    let devices = state.devices.read().await;
+
+    let mut details = Vec::with_capacity(devices.len());
+
+    for device in devices.values() {
+        details.push(DeviceDetails {
+            conn_infos: device.conn_infos().clone(),
+            current_ip_addr: device.ip_addr().await,
+            capabilities: device.conn_infos().device_type.capabilities(),
+            health: device.health().await,
+        });
+    }
+
+    Json(details)
+}
+
+async fn device_capabilities(
+    State(state): State<SharedState>,
+    Path(name): Path<String>,
+) -> ApiResult<Json<&'static [Capability]>> {
+    let devices = state.devices.read().await;
+
+    let device = devices
+        .get(&name)
+        .ok_or_else(|| ApiError::device_not_found(&name))?;
+
+    Ok(Json(device.conn_infos().device_type.capabilities()))
+}
+
+async fn reload_config(state: State<Arc<StateData>>) -> ApiResult<Json<ConfigDiff>> {
+    let diff = state
         .reload_config()
         .await
         .context("Failed to reload config")?;
 
-    Ok(())
+    Ok(Json(diff))
 }
 
 #[derive(Deserialize)]
@@ -121,20 +376,23 @@ pub struct RefreshDeviceSessionParams {
 
 pub async fn refresh_session(
     State(state): State<SharedState>,
-    Query(params): Query<RefreshDeviceSessionParams>,
+    method: Method,
+    uri: Uri,
+    body: Bytes,
 ) -> ApiResult<()> {
-    let RefreshDeviceSessionParams { device } = params;
+    check_method(&state, &method, &Method::POST).await?;
+
+    let RefreshDeviceSessionParams { device: name } = parse_params(&uri, &body)?;
 
     let devices = state.devices.read().await;
 
     let device = devices
-        .get(&device)
-        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("Unknown device: {device}")))?;
+        .get(&name)
+        .ok_or_else(|| ApiError::device_not_found(&name))?;
 
     device
         .refresh_session()
         .await
-        .context("Failed to refresh device's session")?;
-
-    Ok(())
+        .context("Failed to refresh device's session")
+        .map_err(|err| ApiError::from(err).with_device(&name))
 }
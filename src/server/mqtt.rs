@@ -0,0 +1,1002 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use anyhow::{Result, anyhow, bail};
+use axum::{
+    body::Bytes,
+    http::{Method, Uri},
+};
+use log::{debug, info, warn};
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Packet, QoS};
+use serde::Deserialize;
+use serde_json::{Map, Value, json};
+use tokio::sync::Mutex;
+
+use crate::{
+    config::{Config, MqttConfig, TapoConnectionInfos},
+    devices::DeviceStatus,
+};
+
+use super::{
+    Capability, SharedState,
+    actions::dispatch_action,
+    events::{DeviceSnapshot, SocketSnapshot, refresh_snapshot},
+    scheduler::params_body,
+};
+
+/// Delay between two checks for state changes to publish
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Delay before reconnecting to the broker after a connection error
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+/// Number of requests that can be queued while the broker is unreachable
+const REQUESTS_CAPACITY: usize = 64;
+
+/// Color temperature range supported by Tapo lights, in Kelvin
+const MIN_COLOR_TEMPERATURE: u16 = 2500;
+const MAX_COLOR_TEMPERATURE: u16 = 6500;
+
+/// Payloads published to each topic, to only publish what changed
+type Published = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+/// Publish the state of devices to an MQTT broker and run the commands received from it
+///
+/// Changes to the `mqtt` section of the configuration require a restart.
+pub async fn run_mqtt_bridge(state: SharedState) {
+    let Some(config) = state.config.read().await.mqtt.clone() else {
+        return;
+    };
+
+    let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+    options.set_keep_alive(KEEP_ALIVE);
+    options.set_last_will(LastWill::new(
+        bridge_topic(&config),
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+
+    if let Some(username) = &config.username {
+        options.set_credentials(username, config.password.clone().unwrap_or_default());
+    }
+
+    let (client, mut event_loop) = AsyncClient::new(options, REQUESTS_CAPACITY);
+    let published = Published::default();
+
+    tokio::spawn(publish_states(
+        SharedState::clone(&state),
+        client.clone(),
+        config.clone(),
+        Arc::clone(&published),
+    ));
+
+    loop {
+        match event_loop.poll().await {
+            // Requests are sent by the event loop itself, so they must be made from another task
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to MQTT broker {}:{}", config.host, config.port);
+
+                tokio::spawn(on_connect(
+                    client.clone(),
+                    config.clone(),
+                    Arc::clone(&published),
+                ));
+            }
+
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                tokio::spawn(handle_command(
+                    SharedState::clone(&state),
+                    config.clone(),
+                    publish.topic,
+                    publish.payload,
+                ));
+            }
+
+            Ok(_) => {}
+
+            Err(err) => {
+                warn!("! MQTT connection error: {err}");
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+async fn on_connect(client: AsyncClient, config: MqttConfig, published: Published) {
+    // Retained messages may have been lost while disconnected, so everything is published again
+    published.lock().await.clear();
+
+    let result = async {
+        client
+            .publish(bridge_topic(&config), QoS::AtLeastOnce, true, "online")
+            .await?;
+
+        client
+            .subscribe(format!("{}/+/set", config.base_topic), QoS::AtLeastOnce)
+            .await?;
+
+        client
+            .subscribe(
+                format!("{}/+/sockets/+/set", config.base_topic),
+                QoS::AtLeastOnce,
+            )
+            .await
+    };
+
+    if let Err(err) = result.await {
+        warn!("! Failed to set up MQTT subscriptions: {err}");
+    }
+}
+
+/// Periodically publish the availability and state of all devices, along with discovery payloads
+async fn publish_states(
+    state: SharedState,
+    client: AsyncClient,
+    config: MqttConfig,
+    published: Published,
+) {
+    let mut interval = tokio::time::interval(PUBLISH_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let messages = collect_messages(&state, &config).await;
+        let mut published = published.lock().await;
+
+        for (topic, payload) in &messages {
+            if published.get(topic) == Some(payload) {
+                continue;
+            }
+
+            match client
+                .publish(topic, QoS::AtLeastOnce, true, payload.clone())
+                .await
+            {
+                Ok(()) => {
+                    published.insert(topic.clone(), payload.clone());
+                }
+                Err(err) => warn!("! Failed to publish to MQTT topic '{topic}': {err}"),
+            }
+        }
+
+        // Clear the retained messages of devices that were removed from the configuration
+        let removed = published
+            .keys()
+            .filter(|topic| !messages.contains_key(*topic))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for topic in removed {
+            match client
+                .publish(&topic, QoS::AtLeastOnce, true, Vec::new())
+                .await
+            {
+                Ok(()) => {
+                    published.remove(&topic);
+                }
+                Err(err) => warn!("! Failed to clear MQTT topic '{topic}': {err}"),
+            }
+        }
+    }
+}
+
+async fn collect_messages(state: &SharedState, config: &MqttConfig) -> HashMap<String, Vec<u8>> {
+    let devices = state
+        .devices
+        .read()
+        .await
+        .values()
+        .map(Arc::clone)
+        .collect::<Vec<_>>();
+
+    let snapshots = state.device_states.read().await.clone();
+
+    let mut messages = HashMap::new();
+
+    for device in devices {
+        let infos = device.conn_infos();
+        let available = device.health().await.status != DeviceStatus::Unreachable;
+
+        messages.insert(
+            device_topic(config, &infos.name, "availability"),
+            if available { "online" } else { "offline" }.into(),
+        );
+
+        let snapshot = snapshots.get(&infos.name);
+
+        if let Some(snapshot) = snapshot {
+            messages.insert(
+                device_topic(config, &infos.name, "state"),
+                state_payload(snapshot).to_string().into_bytes(),
+            );
+        }
+
+        if config.home_assistant_discovery {
+            // Sockets are only known once the power strip has been polled
+            let sockets = snapshot.map_or(&[][..], |snapshot| &snapshot.sockets);
+
+            for (topic, payload) in discovery_messages(config, infos, sockets) {
+                messages.insert(topic, payload.to_string().into_bytes());
+            }
+        }
+    }
+
+    messages
+}
+
+/// Describe the state of a device, using the format of Home Assistant's JSON lights
+fn state_payload(snapshot: &DeviceSnapshot) -> Value {
+    let mut payload = Map::new();
+
+    if let Some(on) = snapshot.on {
+        payload.insert("state".to_owned(), json!(if on { "ON" } else { "OFF" }));
+    }
+
+    if let Some(brightness) = snapshot.brightness {
+        payload.insert("brightness".to_owned(), json!(brightness));
+    }
+
+    match (
+        snapshot.hue,
+        snapshot.saturation,
+        snapshot.color_temperature,
+    ) {
+        // A color temperature of 0 means the color is set using hue and saturation
+        (Some(hue), Some(saturation), None | Some(0)) => {
+            payload.insert("color_mode".to_owned(), json!("hs"));
+            payload.insert("color".to_owned(), json!({ "h": hue, "s": saturation }));
+        }
+
+        (_, _, Some(color_temperature)) if color_temperature > 0 => {
+            payload.insert("color_mode".to_owned(), json!("color_temp"));
+            payload.insert("color_temp".to_owned(), json!(color_temperature));
+        }
+
+        _ => {
+            if snapshot.brightness.is_some() {
+                payload.insert("color_mode".to_owned(), json!("brightness"));
+            }
+        }
+    }
+
+    if let Some(current_power) = snapshot.current_power {
+        payload.insert("current_power".to_owned(), json!(current_power));
+    }
+
+    if let Some(today_energy) = snapshot.today_energy {
+        payload.insert("today_energy".to_owned(), json!(today_energy));
+    }
+
+    if !snapshot.sockets.is_empty() {
+        let sockets = snapshot
+            .sockets
+            .iter()
+            .map(|socket| (socket.position.to_string(), socket_state(socket)))
+            .collect::<Map<_, _>>();
+
+        payload.insert("sockets".to_owned(), Value::Object(sockets));
+    }
+
+    Value::Object(payload)
+}
+
+/// Describe the state of a power strip's socket
+fn socket_state(socket: &SocketSnapshot) -> Value {
+    let mut payload = Map::new();
+
+    payload.insert(
+        "state".to_owned(),
+        json!(if socket.on { "ON" } else { "OFF" }),
+    );
+    payload.insert("nickname".to_owned(), json!(socket.nickname));
+
+    if let Some(current_power) = socket.current_power {
+        payload.insert("current_power".to_owned(), json!(current_power));
+    }
+
+    if let Some(today_energy) = socket.today_energy {
+        payload.insert("today_energy".to_owned(), json!(today_energy));
+    }
+
+    Value::Object(payload)
+}
+
+/// Build the Home Assistant discovery payloads of a device, based on its capabilities
+///
+/// Each socket of a power strip is announced as its own switch.
+fn discovery_messages(
+    config: &MqttConfig,
+    infos: &TapoConnectionInfos,
+    sockets: &[SocketSnapshot],
+) -> Vec<(String, Value)> {
+    let capabilities = infos.device_type.capabilities();
+    let prefix = &config.discovery_prefix;
+
+    let object_id = object_id(&infos.name);
+
+    let state_topic = device_topic(config, &infos.name, "state");
+    let command_topic = device_topic(config, &infos.name, "set");
+
+    let common = json!({
+        "availability": [
+            { "topic": bridge_topic(config) },
+            { "topic": device_topic(config, &infos.name, "availability") }
+        ],
+        "availability_mode": "all",
+        "state_topic": state_topic,
+        "device": {
+            "identifiers": [object_id],
+            "name": infos.name,
+            "model": infos.device_type.type_name(),
+            "manufacturer": "TP-Link"
+        }
+    });
+
+    let entity = |fields: Value| {
+        let mut entity = common.clone();
+
+        if let (Value::Object(entity), Value::Object(fields)) = (&mut entity, fields) {
+            entity.extend(fields);
+        }
+
+        entity
+    };
+
+    let mut messages = vec![];
+
+    if capabilities.contains(&Capability::Brightness) {
+        let mut color_modes = vec![];
+
+        if capabilities.contains(&Capability::Color) {
+            color_modes.push("hs");
+        }
+
+        if capabilities.contains(&Capability::ColorTemperature) {
+            color_modes.push("color_temp");
+        }
+
+        if color_modes.is_empty() {
+            color_modes.push("brightness");
+        }
+
+        messages.push((
+            format!("{prefix}/light/{object_id}/config"),
+            entity(json!({
+                "name": null,
+                "unique_id": object_id,
+                "schema": "json",
+                "command_topic": command_topic,
+                "brightness": true,
+                "brightness_scale": 100,
+                "supported_color_modes": color_modes,
+                "color_temp_kelvin": true,
+                "min_kelvin": MIN_COLOR_TEMPERATURE,
+                "max_kelvin": MAX_COLOR_TEMPERATURE
+            })),
+        ));
+    } else if capabilities.contains(&Capability::OnOff) {
+        messages.push((
+            format!("{prefix}/switch/{object_id}/config"),
+            entity(json!({
+                "name": null,
+                "unique_id": object_id,
+                "command_topic": command_topic,
+                "value_template": "{{ value_json.state }}",
+                "payload_on": "ON",
+                "payload_off": "OFF"
+            })),
+        ));
+    }
+
+    if capabilities.contains(&Capability::EnergyMonitoring) {
+        messages.push((
+            format!("{prefix}/sensor/{object_id}_power/config"),
+            entity(json!({
+                "name": "Power",
+                "unique_id": format!("{object_id}_power"),
+                "value_template": "{{ value_json.current_power }}",
+                "unit_of_measurement": "W",
+                "device_class": "power",
+                "state_class": "measurement"
+            })),
+        ));
+
+        messages.push((
+            format!("{prefix}/sensor/{object_id}_energy/config"),
+            entity(json!({
+                "name": "Energy today",
+                "unique_id": format!("{object_id}_energy"),
+                "value_template": "{{ value_json.today_energy }}",
+                "unit_of_measurement": "Wh",
+                "device_class": "energy",
+                "state_class": "total_increasing"
+            })),
+        ));
+    }
+
+    if capabilities.contains(&Capability::ChildPlugs) {
+        messages.extend(sockets.iter().map(|socket| {
+            let unique_id = format!("{object_id}_socket_{}", socket.position);
+
+            (
+                format!("{prefix}/switch/{unique_id}/config"),
+                entity(socket_switch(config, &infos.name, &unique_id, socket)),
+            )
+        }));
+    }
+
+    messages
+}
+
+/// Identifier of a device in Home Assistant, derived from its name
+fn object_id(name: &str) -> String {
+    format!(
+        "tapo_rest_{}",
+        name.chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect::<String>()
+    )
+}
+
+/// Fields of the discovery payload of a power strip's socket, announced as a switch
+fn socket_switch(
+    config: &MqttConfig,
+    device: &str,
+    unique_id: &str,
+    socket: &SocketSnapshot,
+) -> Value {
+    let position = socket.position;
+
+    json!({
+        "name": socket.nickname,
+        "unique_id": unique_id,
+        "command_topic": socket_topic(config, device, position, "set"),
+        "value_template": format!("{{{{ value_json.sockets['{position}'].state }}}}"),
+        "payload_on": "ON",
+        "payload_off": "OFF"
+    })
+}
+
+/// Command using the format of Home Assistant's JSON lights
+#[derive(Deserialize)]
+struct LightCommand {
+    #[serde(default)]
+    state: Option<String>,
+    #[serde(default)]
+    brightness: Option<u8>,
+    #[serde(default)]
+    color: Option<HsColor>,
+    #[serde(default)]
+    color_temp: Option<u16>,
+}
+
+#[derive(Deserialize)]
+struct HsColor {
+    h: f64,
+    s: f64,
+}
+
+/// Command running any action supported by the device
+#[derive(Deserialize)]
+struct ActionCommand {
+    action: String,
+    #[serde(default)]
+    params: Option<Value>,
+}
+
+async fn handle_command(state: SharedState, config: MqttConfig, topic: String, payload: Bytes) {
+    let Some((device, socket)) = parse_command_topic(&config, &topic) else {
+        return;
+    };
+
+    debug!("Received MQTT command for device '{device}'");
+
+    let result = match socket {
+        Some(position) => run_socket_command(&state, device, position, &payload).await,
+        None => run_command(&state, device, &payload).await,
+    };
+
+    if let Err(err) = result {
+        warn!("! MQTT command for device '{device}' failed: {err:#}");
+        return;
+    }
+
+    // Publish the new state right away instead of waiting for the next poll
+    if let Err(err) = refresh_snapshot(&state, device).await {
+        debug!("Failed to refresh the state of device '{device}': {err:#}");
+    }
+}
+
+async fn run_command(state: &SharedState, device: &str, payload: &[u8]) -> Result<()> {
+    // Commands are meant to change the state of devices, so they are run as POST requests
+    let uri = Uri::from_static("/");
+
+    for (action, params) in parse_command(payload)? {
+        let body = params_body(params.as_ref())?;
+
+        dispatch_action(state, device, &action, &Method::POST, &uri, &body)
+            .await
+            .map_err(|err| anyhow!("{err}"))?;
+    }
+
+    Ok(())
+}
+
+/// Turn a power strip's socket on or off
+async fn run_socket_command(
+    state: &SharedState,
+    device: &str,
+    position: u8,
+    payload: &[u8],
+) -> Result<()> {
+    let uri = Uri::from_static("/");
+    let action = parse_socket_command(payload)?;
+    let body = params_body(Some(&json!({ "position": position })))?;
+
+    dispatch_action(state, device, action, &Method::POST, &uri, &body)
+        .await
+        .map_err(|err| anyhow!("{err}"))?;
+
+    Ok(())
+}
+
+/// Find the device (and socket, for power strips) a command was sent to
+///
+/// Commands are sent to `<base>/<device>/set`, or `<base>/<device>/sockets/<position>/set` for a single socket.
+fn parse_command_topic<'a>(config: &MqttConfig, topic: &'a str) -> Option<(&'a str, Option<u8>)> {
+    let target = topic
+        .strip_prefix(&format!("{}/", config.base_topic))?
+        .strip_suffix("/set")?;
+
+    match target.split_once("/sockets/") {
+        Some((device, position)) => Some((device, Some(position.parse().ok()?))),
+        None => Some((target, None)),
+    }
+}
+
+/// Convert a socket command, which can only turn it on or off, to the action to run
+fn parse_socket_command(payload: &[u8]) -> Result<&'static str> {
+    let text = std::str::from_utf8(payload)?.trim();
+
+    if text.eq_ignore_ascii_case("on") {
+        Ok("socket-on")
+    } else if text.eq_ignore_ascii_case("off") {
+        Ok("socket-off")
+    } else {
+        bail!("Socket commands must be 'ON' or 'OFF'")
+    }
+}
+
+/// Convert a command to the list of actions to run
+fn parse_command(payload: &[u8]) -> Result<Vec<(String, Option<Value>)>> {
+    let text = std::str::from_utf8(payload)?.trim();
+
+    if text.eq_ignore_ascii_case("on") || text.eq_ignore_ascii_case("off") {
+        return Ok(vec![(text.to_ascii_lowercase(), None)]);
+    }
+
+    let command = serde_json::from_str::<Value>(text)?;
+
+    if command.get("action").is_some() {
+        let ActionCommand { action, params } = serde_json::from_value(command)?;
+        return Ok(vec![(action, params)]);
+    }
+
+    let LightCommand {
+        state,
+        brightness,
+        color,
+        color_temp,
+    } = serde_json::from_value(command)?;
+
+    let mut actions = vec![];
+
+    match state.as_deref() {
+        Some("OFF") => return Ok(vec![("off".to_owned(), None)]),
+        Some("ON") => actions.push(("on".to_owned(), None)),
+        Some(other) => bail!("Unknown state '{other}'"),
+        None => {}
+    }
+
+    if let Some(level) = brightness {
+        actions.push((
+            "set-brightness".to_owned(),
+            Some(json!({ "level": level.max(1) })),
+        ));
+    }
+
+    if let Some(HsColor { h, s }) = color {
+        actions.push((
+            "set-hue-saturation".to_owned(),
+            Some(json!({ "hue": round_color(h, 360), "saturation": round_color(s, 100) })),
+        ));
+    }
+
+    if let Some(color_temperature) = color_temp {
+        actions.push((
+            "set-color-temperature".to_owned(),
+            Some(json!({
+                "color_temperature": color_temperature.clamp(MIN_COLOR_TEMPERATURE, MAX_COLOR_TEMPERATURE)
+            })),
+        ));
+    }
+
+    if actions.is_empty() {
+        bail!("Command doesn't contain anything to do");
+    }
+
+    Ok(actions)
+}
+
+/// Round a hue or saturation sent by Home Assistant, which may not be an integer
+#[allow(
+    clippy::as_conversions,
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss
+)]
+fn round_color(value: f64, max: u16) -> u16 {
+    // The value is clamped beforehand, so the conversion is lossless
+    value.round().clamp(0.0, f64::from(max)) as u16
+}
+
+fn bridge_topic(config: &MqttConfig) -> String {
+    format!("{}/bridge/availability", config.base_topic)
+}
+
+fn device_topic(config: &MqttConfig, device: &str, suffix: &str) -> String {
+    format!("{}/{device}/{suffix}", config.base_topic)
+}
+
+fn socket_topic(config: &MqttConfig, device: &str, position: u8, suffix: &str) -> String {
+    format!("{}/{device}/sockets/{position}/{suffix}", config.base_topic)
+}
+
+/// Ensure the MQTT configuration is valid, and that all device names can be used in topics
+pub fn validate_mqtt_config(config: &Config) -> Result<()> {
+    let Some(mqtt) = &config.mqtt else {
+        return Ok(());
+    };
+
+    if mqtt.host.is_empty() {
+        bail!("MQTT broker's host cannot be empty");
+    }
+
+    let is_valid_topic = |topic: &str| !topic.is_empty() && !topic.contains(['+', '#']);
+
+    if !is_valid_topic(&mqtt.base_topic) || !is_valid_topic(&mqtt.discovery_prefix) {
+        bail!("MQTT topic prefixes cannot be empty or contain wildcards ('+' or '#')");
+    }
+
+    for device in &config.devices {
+        if device.name.is_empty() || device.name.contains(['/', '+', '#']) {
+            bail!(
+                "Device '{}' cannot be used in MQTT topics, as its name is empty or contains '/', '+' or '#'",
+                device.name
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(payload: &str) -> Vec<(String, Option<Value>)> {
+        parse_command(payload.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn plain_on_off() {
+        assert_eq!(parse("ON"), [("on".to_owned(), None)]);
+        assert_eq!(parse(" off\n"), [("off".to_owned(), None)]);
+    }
+
+    #[test]
+    fn action_command() {
+        assert_eq!(
+            parse(r#"{"action": "set-brightness", "params": {"level": 30}}"#),
+            [("set-brightness".to_owned(), Some(json!({ "level": 30 })))]
+        );
+        assert_eq!(
+            parse(r#"{"action": "refresh-session"}"#),
+            [("refresh-session".to_owned(), None)]
+        );
+    }
+
+    #[test]
+    fn light_command() {
+        assert_eq!(
+            parse(r#"{"state": "ON", "brightness": 0, "color": {"h": 359.6, "s": 42.4}}"#),
+            [
+                ("on".to_owned(), None),
+                ("set-brightness".to_owned(), Some(json!({ "level": 1 }))),
+                (
+                    "set-hue-saturation".to_owned(),
+                    Some(json!({ "hue": 360, "saturation": 42 }))
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn light_command_clamps_color_temperature() {
+        assert_eq!(
+            parse(r#"{"color_temp": 9000}"#),
+            [(
+                "set-color-temperature".to_owned(),
+                Some(json!({ "color_temperature": MAX_COLOR_TEMPERATURE }))
+            )]
+        );
+        assert_eq!(
+            parse(r#"{"color_temp": 2000}"#),
+            [(
+                "set-color-temperature".to_owned(),
+                Some(json!({ "color_temperature": MIN_COLOR_TEMPERATURE }))
+            )]
+        );
+    }
+
+    #[test]
+    fn off_ignores_other_fields() {
+        assert_eq!(
+            parse(r#"{"state": "OFF", "brightness": 50}"#),
+            [("off".to_owned(), None)]
+        );
+    }
+
+    #[test]
+    fn invalid_commands() {
+        for payload in [
+            "toggle",
+            r#"{"state": "TOGGLE"}"#,
+            "{}",
+            r#"{"brightness": 300}"#,
+        ] {
+            assert!(parse_command(payload.as_bytes()).is_err(), "{payload}");
+        }
+
+        assert!(parse_command(&[0xff, 0xfe]).is_err());
+    }
+
+    fn mqtt_config() -> MqttConfig {
+        serde_json::from_value(json!({ "host": "broker" })).unwrap()
+    }
+
+    fn device(name: &str, device_type: &str) -> TapoConnectionInfos {
+        serde_json::from_value(json!({
+            "name": name,
+            "device_type": device_type,
+            "ip_addr": "127.0.0.1"
+        }))
+        .unwrap()
+    }
+
+    fn discovery_topics(device_type: &str) -> Vec<String> {
+        discovery_messages(&mqtt_config(), &device("desk lamp", device_type), &[])
+            .into_iter()
+            .map(|(topic, _)| topic)
+            .collect()
+    }
+
+    #[test]
+    fn color_light_state() {
+        let snapshot = DeviceSnapshot {
+            on: Some(true),
+            brightness: Some(40),
+            hue: Some(120),
+            saturation: Some(80),
+            color_temperature: Some(0),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            state_payload(&snapshot),
+            json!({
+                "state": "ON",
+                "brightness": 40,
+                "color_mode": "hs",
+                "color": { "h": 120, "s": 80 }
+            })
+        );
+    }
+
+    #[test]
+    fn color_temperature_light_state() {
+        let snapshot = DeviceSnapshot {
+            on: Some(false),
+            brightness: Some(100),
+            hue: Some(120),
+            saturation: Some(80),
+            color_temperature: Some(2700),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            state_payload(&snapshot),
+            json!({
+                "state": "OFF",
+                "brightness": 100,
+                "color_mode": "color_temp",
+                "color_temp": 2700
+            })
+        );
+    }
+
+    #[test]
+    fn plug_state() {
+        let snapshot = DeviceSnapshot {
+            on: Some(true),
+            current_power: Some(12),
+            today_energy: Some(345),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            state_payload(&snapshot),
+            json!({ "state": "ON", "current_power": 12, "today_energy": 345 })
+        );
+    }
+
+    #[test]
+    fn discovery_follows_capabilities() {
+        assert_eq!(
+            discovery_topics("L530"),
+            ["homeassistant/light/tapo_rest_desk_lamp/config"]
+        );
+        assert_eq!(
+            discovery_topics("P110"),
+            [
+                "homeassistant/switch/tapo_rest_desk_lamp/config",
+                "homeassistant/sensor/tapo_rest_desk_lamp_power/config",
+                "homeassistant/sensor/tapo_rest_desk_lamp_energy/config",
+            ]
+        );
+        assert_eq!(
+            discovery_topics("P100"),
+            ["homeassistant/switch/tapo_rest_desk_lamp/config"]
+        );
+    }
+
+    #[test]
+    fn light_discovery_payload() {
+        let messages = discovery_messages(&mqtt_config(), &device("desk lamp", "L530"), &[]);
+        let (_, payload) = &messages[0];
+
+        assert_eq!(payload["unique_id"], "tapo_rest_desk_lamp");
+        assert_eq!(payload["state_topic"], "tapo/desk lamp/state");
+        assert_eq!(payload["command_topic"], "tapo/desk lamp/set");
+        assert_eq!(
+            payload["supported_color_modes"],
+            json!(["hs", "color_temp"])
+        );
+        assert_eq!(payload["device"]["model"], "L530");
+        assert_eq!(
+            payload["availability"],
+            json!([
+                { "topic": "tapo/bridge/availability" },
+                { "topic": "tapo/desk lamp/availability" }
+            ])
+        );
+    }
+
+    fn socket(position: u8, on: bool, current_power: Option<u64>) -> SocketSnapshot {
+        SocketSnapshot {
+            position,
+            nickname: format!("Socket {position}"),
+            on,
+            current_power,
+            current_power_milliwatts: current_power.map(|watts| watts * 1000),
+            today_energy: current_power.map(|_| 120),
+            month_energy: None,
+        }
+    }
+
+    #[test]
+    fn power_strip_state() {
+        let snapshot = DeviceSnapshot {
+            sockets: vec![socket(1, true, Some(5)), socket(2, false, None)],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            state_payload(&snapshot),
+            json!({
+                "sockets": {
+                    "1": { "state": "ON", "nickname": "Socket 1", "current_power": 5, "today_energy": 120 },
+                    "2": { "state": "OFF", "nickname": "Socket 2" }
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn power_strip_sockets_are_announced_as_switches() {
+        let sockets = [socket(1, true, None), socket(2, false, None)];
+        let messages = discovery_messages(&mqtt_config(), &device("desk", "P300"), &sockets);
+
+        assert_eq!(
+            messages
+                .iter()
+                .map(|(topic, _)| topic.as_str())
+                .collect::<Vec<_>>(),
+            [
+                "homeassistant/switch/tapo_rest_desk_socket_1/config",
+                "homeassistant/switch/tapo_rest_desk_socket_2/config",
+            ]
+        );
+
+        let (_, payload) = &messages[1];
+        assert_eq!(payload["name"], "Socket 2");
+        assert_eq!(payload["unique_id"], "tapo_rest_desk_socket_2");
+        assert_eq!(payload["state_topic"], "tapo/desk/state");
+        assert_eq!(payload["command_topic"], "tapo/desk/sockets/2/set");
+        assert_eq!(
+            payload["value_template"],
+            "{{ value_json.sockets['2'].state }}"
+        );
+
+        // Sockets are announced along with the energy sensors of the whole strip
+        assert_eq!(
+            discovery_messages(&mqtt_config(), &device("desk", "P304M"), &sockets).len(),
+            2
+        );
+
+        // Devices without sockets ignore them
+        assert_eq!(
+            discovery_messages(&mqtt_config(), &device("desk", "P100"), &sockets).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn command_topics() {
+        let config = mqtt_config();
+
+        assert_eq!(
+            parse_command_topic(&config, "tapo/desk lamp/set"),
+            Some(("desk lamp", None))
+        );
+        assert_eq!(
+            parse_command_topic(&config, "tapo/strip/sockets/3/set"),
+            Some(("strip", Some(3)))
+        );
+        assert_eq!(
+            parse_command_topic(&config, "tapo/strip/sockets/x/set"),
+            None
+        );
+        assert_eq!(parse_command_topic(&config, "tapo/desk lamp/state"), None);
+        assert_eq!(parse_command_topic(&config, "other/desk lamp/set"), None);
+    }
+
+    #[test]
+    fn socket_commands() {
+        assert_eq!(parse_socket_command(b"ON").unwrap(), "socket-on");
+        assert_eq!(parse_socket_command(b"off\n").unwrap(), "socket-off");
+        assert!(parse_socket_command(br#"{"state": "ON"}"#).is_err());
+    }
+
+    #[test]
+    fn device_names_must_be_valid_topic_levels() {
+        let mut config = serde_json::from_value::<Config>(json!({
+            "tapo_credentials": { "email": "user@example.com", "password": "secret" },
+            "devices": [],
+            "server": { "password": "secret", "api_keys": [] },
+            "mqtt": { "host": "broker" }
+        }))
+        .unwrap();
+
+        assert!(validate_mqtt_config(&config).is_ok());
+
+        for name in ["kitchen/plug", "plug+", "#", ""] {
+            config.devices = vec![device(name, "P100")];
+            assert!(validate_mqtt_config(&config).is_err(), "{name}");
+        }
+
+        config.devices = vec![device("kitchen plug", "P100")];
+        assert!(validate_mqtt_config(&config).is_ok());
+    }
+}
@@ -0,0 +1,724 @@
+use std::fmt;
+
+use axum::{Json, http::Method};
+use serde::{
+    Deserialize, Deserializer,
+    de::{
+        self, DeserializeOwned, DeserializeSeed, MapAccess, SeqAccess, Visitor,
+        value::BorrowedStrDeserializer,
+    },
+    forward_to_deserialize_any,
+};
+use serde_json::{Map, Value, json};
+
+use super::ErrorCode;
+
+/// Builder for the `OpenAPI` specification of the server's routes
+pub struct OpenApiBuilder {
+    paths: Map<String, Value>,
+    schemas: Map<String, Value>,
+}
+
+impl OpenApiBuilder {
+    pub fn new() -> Self {
+        let mut builder = Self {
+            paths: Map::new(),
+            schemas: Map::new(),
+        };
+
+        let error_code = builder.schema(Shape::of::<ErrorCode>());
+
+        builder.component(
+            "Error",
+            json!({
+                "type": "object",
+                "description": "Error details, as described in RFC 7807",
+                "properties": {
+                    "type": { "type": "string" },
+                    "title": { "type": "string" },
+                    "status": { "type": "integer" },
+                    "detail": { "type": "string" },
+                    "code": error_code,
+                    "device": { "type": "string" }
+                },
+                "required": ["type", "title", "status", "detail", "code"]
+            }),
+        );
+
+        builder
+    }
+
+    /// Describe an error response
+    pub fn error_response(description: &str) -> Value {
+        json!({
+            "description": description,
+            "content": {
+                "application/problem+json": {
+                    "schema": { "$ref": "#/components/schemas/Error" }
+                }
+            }
+        })
+    }
+
+    /// Describe an action's parameter from its type
+    pub fn param<'de, T: Deserialize<'de>>(&mut self, name: &'static str) -> ActionParam {
+        let shape = Shape::of::<T>();
+
+        ActionParam {
+            name,
+            required: !matches!(shape, Shape::Optional(_)),
+            schema: self.schema(shape),
+        }
+    }
+
+    /// Describe an action's response from its type
+    pub fn response<T: ResponseSchema>(&mut self) -> Option<Value> {
+        T::shape().map(|shape| self.schema(shape))
+    }
+
+    /// Register an action's route
+    ///
+    /// If `device_in_path` is set, the device is provided through the `{name}` path segment instead of the query string.
+    /// Routes that were already registered for the same method are ignored.
+    pub fn add_action(
+        &mut self,
+        uri: &str,
+        method: &Method,
+        summary: &str,
+        device_in_path: bool,
+        params: &[ActionParam],
+        response: Option<Value>,
+    ) {
+        let method = method.as_str().to_lowercase();
+
+        let path = self
+            .paths
+            .entry(uri)
+            .or_insert_with(|| Value::Object(Map::new()))
+            .as_object_mut()
+            .unwrap();
+
+        if path.contains_key(&method) {
+            return;
+        }
+
+        let device_param = if device_in_path {
+            json!({
+                "name": "name",
+                "in": "path",
+                "required": true,
+                "description": "Name of the device",
+                "schema": { "type": "string" }
+            })
+        } else {
+            json!({
+                "name": "device",
+                "in": "query",
+                "required": true,
+                "description": "Name of the device",
+                "schema": { "type": "string" }
+            })
+        };
+
+        let mut parameters = vec![device_param];
+
+        parameters.extend(params.iter().map(|param| {
+            json!({
+                "name": param.name,
+                "in": "query",
+                "required": param.required,
+                "schema": param.schema
+            })
+        }));
+
+        let mut operation = json!({
+            "summary": summary,
+            "parameters": parameters,
+            "responses": {
+                "200": match response {
+                    Some(schema) => json!({
+                        "description": "Success",
+                        "content": { "application/json": { "schema": schema } }
+                    }),
+                    None => json!({ "description": "Success" }),
+                },
+                "400": Self::error_response("Invalid parameters, or action not supported by this device"),
+                "404": Self::error_response("Device not found"),
+                "405": Self::error_response("Action called with the wrong HTTP method"),
+                "502": Self::error_response("The device returned an error"),
+                "503": Self::error_response("The device is unreachable"),
+                "504": Self::error_response("The device took too long to respond")
+            }
+        });
+
+        // Parameters of state-changing actions may also be provided as a JSON body
+        if method != "get" {
+            let mut properties = Map::new();
+            let mut required = vec![];
+
+            if !device_in_path {
+                properties.insert("device".to_owned(), json!({ "type": "string" }));
+                required.push("device");
+            }
+
+            for param in params {
+                properties.insert(param.name.to_owned(), param.schema.clone());
+
+                if param.required {
+                    required.push(param.name);
+                }
+            }
+
+            operation["requestBody"] = json!({
+                "required": false,
+                "content": {
+                    "application/json": {
+                        "schema": {
+                            "type": "object",
+                            "properties": properties,
+                            "required": required
+                        }
+                    }
+                }
+            });
+        }
+
+        path.insert(method, operation);
+    }
+
+    /// Register a route that isn't an action
+    pub fn add_route(&mut self, uri: &str, method: &Method, operation: Value) {
+        self.paths
+            .entry(uri)
+            .or_insert_with(|| Value::Object(Map::new()))
+            .as_object_mut()
+            .unwrap()
+            .insert(method.as_str().to_lowercase(), operation);
+    }
+
+    pub fn build(self) -> Value {
+        json!({
+            "openapi": "3.0.3",
+            "info": {
+                "title": "Tapo REST API",
+                "description": env!("CARGO_PKG_DESCRIPTION"),
+                "version": env!("CARGO_PKG_VERSION")
+            },
+            "paths": self.paths,
+            "components": {
+                "schemas": self.schemas,
+                "securitySchemes": {
+                    "bearerAuth": { "type": "http", "scheme": "bearer" }
+                }
+            },
+            "security": [{ "bearerAuth": [] }]
+        })
+    }
+
+    /// Convert a shape to a JSON schema, registering named types as reusable components
+    fn schema(&mut self, shape: Shape) -> Value {
+        match shape {
+            Shape::Any => json!({}),
+            Shape::Bool => json!({ "type": "boolean" }),
+            Shape::Integer { min, max } => {
+                let mut schema = json!({ "type": "integer" });
+
+                if let Some(min) = min {
+                    schema["minimum"] = json!(min);
+                }
+
+                if let Some(max) = max {
+                    schema["maximum"] = json!(max);
+                }
+
+                schema
+            }
+            Shape::Number => json!({ "type": "number" }),
+            Shape::String { format: None } => json!({ "type": "string" }),
+            Shape::String {
+                format: Some(format),
+            } => json!({ "type": "string", "format": format }),
+            Shape::Array(items) => json!({ "type": "array", "items": self.schema(*items) }),
+            Shape::Optional(inner) => {
+                let mut schema = self.schema(*inner);
+
+                // '$ref' can't have sibling properties in OpenAPI 3.0
+                if schema.get("$ref").is_some() {
+                    schema = json!({ "allOf": [schema] });
+                }
+
+                schema["nullable"] = json!(true);
+                schema
+            }
+            Shape::Object { name, fields } => {
+                // Registered beforehand, so recursive types refer to themselves instead of looping
+                if self.schemas.contains_key(name) {
+                    return self.component(name, Value::Null);
+                }
+
+                self.schemas
+                    .insert(name.to_owned(), json!({ "type": "object" }));
+
+                let properties = fields
+                    .into_iter()
+                    .map(|(field, shape)| (field.to_owned(), self.schema(shape)))
+                    .collect::<Map<_, _>>();
+
+                self.schemas.insert(
+                    name.to_owned(),
+                    json!({ "type": "object", "properties": properties }),
+                );
+
+                self.component(name, Value::Null)
+            }
+            Shape::Enum { name, variants } => {
+                self.component(name, json!({ "type": "string", "enum": variants }))
+            }
+        }
+    }
+
+    fn component(&mut self, name: &str, schema: Value) -> Value {
+        self.schemas.entry(name).or_insert(schema);
+        json!({ "$ref": format!("#/components/schemas/{name}") })
+    }
+}
+
+pub struct ActionParam {
+    name: &'static str,
+    required: bool,
+    schema: Value,
+}
+
+/// Types that can be returned by an action
+pub trait ResponseSchema {
+    fn shape() -> Option<Shape>;
+}
+
+impl ResponseSchema for () {
+    fn shape() -> Option<Shape> {
+        None
+    }
+}
+
+impl<T: DeserializeOwned> ResponseSchema for Json<T> {
+    fn shape() -> Option<Shape> {
+        Some(Shape::of::<T>())
+    }
+}
+
+/// Shape of a type, as exposed by its `Deserialize` implementation
+pub enum Shape {
+    Any,
+    Bool,
+    Integer {
+        min: Option<i64>,
+        max: Option<u64>,
+    },
+    Number,
+    String {
+        /// `OpenAPI` format of the string (e.g. `date`), if known
+        format: Option<&'static str>,
+    },
+    Array(Box<Shape>),
+    Optional(Box<Shape>),
+    Object {
+        name: &'static str,
+        fields: Vec<(&'static str, Shape)>,
+    },
+    Enum {
+        name: &'static str,
+        variants: &'static [&'static str],
+    },
+}
+
+impl Shape {
+    fn of<'de, T: Deserialize<'de>>() -> Self {
+        Self::at_path::<T>(&[])
+    }
+
+    /// Shape of the value found by following the provided struct fields (as indexes) from the root type
+    ///
+    /// Deserialization stops at the first field, so each field is introspected by deserializing the root type again.
+    fn at_path<'de, T: Deserialize<'de>>(path: &[usize]) -> Self {
+        let mut shape = Self::Any;
+
+        // Introspection always ends up with an error once the shape has been determined
+        let _ = T::deserialize(Introspector {
+            shape: &mut shape,
+            path,
+            depth: 0,
+            at_path: Self::at_path::<T>,
+        });
+
+        shape
+    }
+}
+
+/// Maximum depth of nested structs introspected, in case of recursive types
+const MAX_INTROSPECTION_DEPTH: usize = 16;
+
+/// Deserializer recording the shape of the deserialized type instead of producing a value
+struct Introspector<'a> {
+    shape: &'a mut Shape,
+
+    /// Path of struct fields leading to the introspected value, from the root type
+    path: &'a [usize],
+
+    /// Number of fields of the path already followed
+    depth: usize,
+
+    /// Introspect the root type again, following another path
+    at_path: fn(&[usize]) -> Shape,
+}
+
+impl Introspector<'_> {
+    /// Whether the introspected value still needs to be reached by following struct fields
+    fn is_on_path(&self) -> bool {
+        self.depth < self.path.len()
+    }
+
+    fn reborrow(&mut self) -> Introspector<'_> {
+        Introspector {
+            shape: self.shape,
+            path: self.path,
+            depth: self.depth,
+            at_path: self.at_path,
+        }
+    }
+}
+
+/// `OpenAPI` format of a string, recognized from the description of the value its deserializer expects
+fn string_format(expected: &dyn de::Expected) -> Option<&'static str> {
+    // Descriptions used by chrono's types
+    match expected.to_string().as_str() {
+        "a formatted date string" => Some("date"),
+        "a formatted date and time string" | "an RFC 3339 formatted date and time string" => {
+            Some("date-time")
+        }
+        _ => None,
+    }
+}
+
+macro_rules! introspect_integers {
+    ($($method: ident => $type: ty),+) => {
+        $(fn $method<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+            *self.shape = Shape::Integer {
+                min: i64::try_from(<$type>::MIN).ok(),
+                max: u64::try_from(<$type>::MAX).ok(),
+            };
+
+            Err(IntrospectionDone)
+        })+
+    };
+}
+
+impl<'de> Deserializer<'de> for Introspector<'_> {
+    type Error = IntrospectionDone;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+        Err(IntrospectionDone)
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+        *self.shape = Shape::Bool;
+        Err(IntrospectionDone)
+    }
+
+    introspect_integers!(
+        deserialize_i8 => i8,
+        deserialize_i16 => i16,
+        deserialize_i32 => i32,
+        deserialize_i64 => i64,
+        deserialize_u8 => u8,
+        deserialize_u16 => u16,
+        deserialize_u32 => u32,
+        deserialize_u64 => u64
+    );
+
+    fn deserialize_f32<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+        *self.shape = Shape::Number;
+        Err(IntrospectionDone)
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+        *self.shape = Shape::Number;
+        Err(IntrospectionDone)
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+        *self.shape = Shape::String { format: None };
+        Err(IntrospectionDone)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        *self.shape = Shape::String {
+            format: string_format(&visitor),
+        };
+        Err(IntrospectionDone)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(mut self, visitor: V) -> Result<V::Value, Self::Error> {
+        if self.is_on_path() {
+            return visitor.visit_some(self.reborrow());
+        }
+
+        let mut inner = Shape::Any;
+        let _ = visitor.visit_some(Introspector {
+            shape: &mut inner,
+            ..self.reborrow()
+        });
+
+        *self.shape = Shape::Optional(Box::new(inner));
+        Err(IntrospectionDone)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(mut self, visitor: V) -> Result<V::Value, Self::Error> {
+        if self.is_on_path() {
+            return visitor.visit_seq(IntrospectorSeq {
+                items: self.reborrow(),
+            });
+        }
+
+        let mut items = Shape::Any;
+        let _ = visitor.visit_seq(IntrospectorSeq {
+            items: Introspector {
+                shape: &mut items,
+                ..self.reborrow()
+            },
+        });
+
+        *self.shape = Shape::Array(Box::new(items));
+        Err(IntrospectionDone)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        // Follow the path down to the introspected field
+        if let Some(&field) = self.path.get(self.depth) {
+            return visitor.visit_map(IntrospectorStruct {
+                field: fields.get(field).copied(),
+                value: Some(Introspector {
+                    depth: self.depth + 1,
+                    ..self
+                }),
+            });
+        }
+
+        let fields = if self.depth < MAX_INTROSPECTION_DEPTH {
+            fields
+                .iter()
+                .enumerate()
+                .map(|(index, field)| {
+                    let path = [self.path, &[index]].concat();
+                    (*field, (self.at_path)(&path))
+                })
+                .collect()
+        } else {
+            vec![]
+        };
+
+        *self.shape = Shape::Object { name, fields };
+        Err(IntrospectionDone)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        _: V,
+    ) -> Result<V::Value, Self::Error> {
+        *self.shape = Shape::Enum { name, variants };
+        Err(IntrospectionDone)
+    }
+
+    forward_to_deserialize_any! {
+        i128 u128 bytes byte_buf unit unit_struct tuple tuple_struct map identifier ignored_any
+    }
+}
+
+/// Sequence introspecting the type of its items
+struct IntrospectorSeq<'a> {
+    items: Introspector<'a>,
+}
+
+impl<'de> SeqAccess<'de> for IntrospectorSeq<'_> {
+    type Error = IntrospectionDone;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Self::Error> {
+        seed.deserialize(self.items.reborrow()).map(Some)
+    }
+}
+
+/// Struct only containing the field to introspect
+struct IntrospectorStruct<'a> {
+    field: Option<&'static str>,
+    value: Option<Introspector<'a>>,
+}
+
+impl<'de> MapAccess<'de> for IntrospectorStruct<'_> {
+    type Error = IntrospectionDone;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Self::Error> {
+        let Some(field) = self.field.take() else {
+            return Err(IntrospectionDone);
+        };
+
+        seed.deserialize(BorrowedStrDeserializer::new(field))
+            .map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, Self::Error> {
+        let value = self.value.take().ok_or(IntrospectionDone)?;
+        seed.deserialize(value)
+    }
+}
+
+#[derive(Debug)]
+pub struct IntrospectionDone;
+
+impl fmt::Display for IntrospectionDone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "introspection done")
+    }
+}
+
+impl std::error::Error for IntrospectionDone {}
+
+impl de::Error for IntrospectionDone {
+    fn custom<T: fmt::Display>(_: T) -> Self {
+        Self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, NaiveDate, Utc};
+
+    use super::*;
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct Reading {
+        date: NaiveDate,
+        measured_at: Option<DateTime<Utc>>,
+        values: Vec<Sample>,
+        unit: Unit,
+    }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct Sample {
+        watts: u16,
+        label: String,
+        valid: bool,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    #[allow(dead_code)]
+    enum Unit {
+        Watt,
+        Kilowatt,
+    }
+
+    fn schema_of<'de, T: Deserialize<'de>>() -> (Value, Value) {
+        let mut builder = OpenApiBuilder::new();
+        let schema = builder.schema(Shape::of::<T>());
+        (schema, Value::Object(builder.schemas))
+    }
+
+    #[test]
+    fn scalar_schemas() {
+        assert_eq!(
+            schema_of::<u8>().0,
+            json!({ "type": "integer", "minimum": 0, "maximum": 255 })
+        );
+        assert_eq!(schema_of::<f64>().0, json!({ "type": "number" }));
+        assert_eq!(
+            schema_of::<Option<String>>().0,
+            json!({ "type": "string", "nullable": true })
+        );
+        assert_eq!(
+            schema_of::<NaiveDate>().0,
+            json!({ "type": "string", "format": "date" })
+        );
+        assert_eq!(
+            schema_of::<DateTime<Utc>>().0,
+            json!({ "type": "string", "format": "date-time" })
+        );
+    }
+
+    #[test]
+    fn struct_properties_have_their_own_schema() {
+        let (schema, schemas) = schema_of::<Reading>();
+
+        assert_eq!(schema, json!({ "$ref": "#/components/schemas/Reading" }));
+        assert_eq!(
+            schemas["Reading"],
+            json!({
+                "type": "object",
+                "properties": {
+                    "date": { "type": "string", "format": "date" },
+                    "measured_at": { "type": "string", "format": "date-time", "nullable": true },
+                    "values": {
+                        "type": "array",
+                        "items": { "$ref": "#/components/schemas/Sample" }
+                    },
+                    "unit": { "$ref": "#/components/schemas/Unit" }
+                }
+            })
+        );
+        assert_eq!(
+            schemas["Sample"],
+            json!({
+                "type": "object",
+                "properties": {
+                    "watts": { "type": "integer", "minimum": 0, "maximum": 65535 },
+                    "label": { "type": "string" },
+                    "valid": { "type": "boolean" }
+                }
+            })
+        );
+        assert_eq!(
+            schemas["Unit"],
+            json!({ "type": "string", "enum": ["watt", "kilowatt"] })
+        );
+    }
+
+    #[test]
+    fn date_params_are_documented_as_dates() {
+        let mut builder = OpenApiBuilder::new();
+        let param = builder.param::<Option<NaiveDate>>("end_date");
+
+        assert!(!param.required);
+        assert_eq!(
+            param.schema,
+            json!({ "type": "string", "format": "date", "nullable": true })
+        );
+    }
+}
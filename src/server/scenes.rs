@@ -0,0 +1,636 @@
+use anyhow::{Result, bail};
+use axum::{
+    Json,
+    body::Bytes,
+    extract::{Path, State},
+    http::{Method, Uri},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tapo::requests::ColorLightSetDeviceInfoParams;
+use tokio::task::JoinSet;
+
+use crate::{
+    config::{HueSaturation, Scene, SceneDeviceState, SceneLightingEffect, TapoConnectionInfos},
+    devices::TapoDeviceInner,
+};
+
+use super::{
+    ApiError, ApiResult, Capability, ErrorCode, SharedState, actions::parse_params,
+    groups::ActionReport, openapi::OpenApiBuilder,
+};
+
+pub async fn list_scenes(State(state): State<SharedState>) -> Json<Vec<Scene>> {
+    Json(state.config.read().await.scenes.clone())
+}
+
+pub async fn apply_scene(
+    State(state): State<SharedState>,
+    Path(name): Path<String>,
+) -> ApiResult<Json<ActionReport>> {
+    dispatch_scene(&state, &name).await.map(Json)
+}
+
+/// Apply a scene, setting the state of all its devices concurrently
+///
+/// Failures on some devices don't prevent the scene from being applied to the other ones.
+pub async fn dispatch_scene(state: &SharedState, name: &str) -> ApiResult<ActionReport> {
+    let scene = state
+        .config
+        .read()
+        .await
+        .scenes
+        .iter()
+        .find(|candidate| candidate.name == name)
+        .cloned()
+        .ok_or_else(|| {
+            ApiError::new(
+                ErrorCode::SceneNotFound,
+                format!("Scene '{name}' was not found"),
+            )
+        })?;
+
+    let mut tasks = JoinSet::new();
+
+    for (index, target) in scene.devices.into_iter().enumerate() {
+        let state = SharedState::clone(state);
+
+        tasks.spawn(async move {
+            let result = apply_device_state(&state, &target).await;
+            (index, target.device, result)
+        });
+    }
+
+    let mut results = tasks.join_all().await;
+
+    // Keep the same order as in the scene's configuration
+    results.sort_by_key(|(index, _, _)| *index);
+
+    Ok(ActionReport::from_results(results.into_iter().map(
+        |(_, device, result)| (device, result.map(|()| None)),
+    )))
+}
+
+#[derive(Deserialize)]
+pub struct CaptureSceneParams {
+    /// Devices to capture the state of (defaults to all devices that can be turned on and off)
+    #[serde(default)]
+    devices: Vec<String>,
+}
+
+/// Scene created from the current state of some devices
+#[derive(Serialize)]
+pub struct CapturedScene {
+    #[serde(flatten)]
+    scene: Scene,
+    /// Captured scenes are not written to the configuration file, and are lost when it is reloaded
+    ephemeral: bool,
+}
+
+/// Create a scene from the current state of some devices
+///
+/// Captured scenes are kept in memory until the configuration is reloaded,
+/// and are returned so they can be added to the configuration file.
+pub async fn capture_scene(
+    State(state): State<SharedState>,
+    Path(name): Path<String>,
+    uri: Uri,
+    body: Bytes,
+) -> ApiResult<Json<CapturedScene>> {
+    let CaptureSceneParams { devices } = parse_params(&uri, &body)?;
+
+    let devices = if devices.is_empty() {
+        let mut devices = state
+            .config
+            .read()
+            .await
+            .devices
+            .iter()
+            .filter(|device| {
+                device
+                    .device_type
+                    .capabilities()
+                    .contains(&Capability::OnOff)
+            })
+            .map(|device| device.name.clone())
+            .collect::<Vec<_>>();
+
+        devices.sort();
+        devices
+    } else {
+        devices
+    };
+
+    let mut tasks = JoinSet::new();
+
+    for (index, device) in devices.into_iter().enumerate() {
+        let state = SharedState::clone(&state);
+
+        tasks.spawn(async move {
+            let result = capture_device_state(&state, &device).await;
+            (index, result)
+        });
+    }
+
+    let mut results = tasks.join_all().await;
+    results.sort_by_key(|(index, _)| *index);
+
+    let scene = Scene {
+        name,
+        devices: results
+            .into_iter()
+            .map(|(_, result)| result)
+            .collect::<ApiResult<_>>()?,
+    };
+
+    let mut config = state.config.write().await;
+
+    validate_scene(&scene, &config.devices)
+        .map_err(|err| ApiError::new(ErrorCode::InvalidParameter, err.to_string()))?;
+
+    match config
+        .scenes
+        .iter_mut()
+        .find(|candidate| candidate.name == scene.name)
+    {
+        Some(existing) => *existing = scene.clone(),
+        None => config.scenes.push(scene.clone()),
+    }
+
+    Ok(Json(CapturedScene {
+        scene,
+        ephemeral: true,
+    }))
+}
+
+/// Ensure a scene only refers to existing devices, with properties they support
+pub fn validate_scene(scene: &Scene, devices: &[TapoConnectionInfos]) -> Result<()> {
+    for (i, target) in scene.devices.iter().enumerate() {
+        if scene.devices[..i]
+            .iter()
+            .any(|other| other.device == target.device)
+        {
+            bail!(
+                "Scene '{}' sets the state of device '{}' multiple times",
+                scene.name,
+                target.device
+            );
+        }
+
+        let Some(device) = devices.iter().find(|device| device.name == target.device) else {
+            bail!(
+                "Scene '{}' refers to unknown device '{}'",
+                scene.name,
+                target.device
+            );
+        };
+
+        let capabilities = device.device_type.capabilities();
+
+        let properties = [
+            ("on", target.on.is_some(), Capability::OnOff),
+            (
+                "brightness",
+                target.brightness.is_some(),
+                Capability::Brightness,
+            ),
+            ("color", target.color.is_some(), Capability::Color),
+            (
+                "hue_saturation",
+                target.hue_saturation.is_some(),
+                Capability::Color,
+            ),
+            (
+                "color_temperature",
+                target.color_temperature.is_some(),
+                Capability::ColorTemperature,
+            ),
+            (
+                "lighting_effect",
+                target.lighting_effect.is_some(),
+                Capability::LightingEffects,
+            ),
+        ];
+
+        for (property, is_set, capability) in properties {
+            if is_set && !capabilities.contains(&capability) {
+                bail!(
+                    "Scene '{}' sets property '{property}' of device '{}', which it doesn't support",
+                    scene.name,
+                    target.device
+                );
+            }
+        }
+
+        let color_settings = [
+            target.color.is_some(),
+            target.hue_saturation.is_some(),
+            target.color_temperature.is_some(),
+            target.lighting_effect.is_some(),
+        ];
+
+        if color_settings.into_iter().filter(|is_set| *is_set).count() > 1 {
+            bail!(
+                "Scene '{}' sets more than one of 'color', 'hue_saturation', 'color_temperature' and 'lighting_effect' for device '{}'",
+                scene.name,
+                target.device
+            );
+        }
+
+        if target.lighting_effect.is_some() && target.on == Some(false) {
+            bail!(
+                "Scene '{}' sets a lighting effect on device '{}', which it also turns off",
+                scene.name,
+                target.device
+            );
+        }
+    }
+
+    Ok(())
+}
+
+async fn apply_device_state(state: &SharedState, target: &SceneDeviceState) -> ApiResult<()> {
+    let device = state
+        .devices
+        .read()
+        .await
+        .get(&target.device)
+        .cloned()
+        .ok_or_else(|| ApiError::device_not_found(&target.device))?;
+
+    device
+        .with_client(async |client| set_state(client, target).await)
+        .await
+        .map_err(ApiError::from)
+        .and_then(|result| result.map_err(ApiError::from))
+        .map_err(|err| err.with_device(&target.device))
+}
+
+async fn capture_device_state(state: &SharedState, name: &str) -> ApiResult<SceneDeviceState> {
+    let device = state
+        .devices
+        .read()
+        .await
+        .get(name)
+        .cloned()
+        .ok_or_else(|| ApiError::device_not_found(name))?;
+
+    device
+        .with_client(async |client| get_state(client, name).await)
+        .await
+        .map_err(ApiError::from)
+        .flatten()
+        .map_err(|err| err.with_device(name))
+}
+
+async fn set_state(client: &TapoDeviceInner, target: &SceneDeviceState) -> Result<(), tapo::Error> {
+    let SceneDeviceState {
+        device: _,
+        on,
+        brightness,
+        color,
+        hue_saturation,
+        color_temperature,
+        lighting_effect,
+    } = target;
+
+    // Set all properties in a single request for devices that support it
+    let color_params = || {
+        let mut params = ColorLightSetDeviceInfoParams::new();
+
+        if let Some(brightness) = brightness {
+            params = params.brightness(*brightness);
+        }
+
+        if let Some(color) = color {
+            params = params.color(color.clone());
+        }
+
+        if let Some(HueSaturation { hue, saturation }) = hue_saturation {
+            params = params.hue_saturation(*hue, *saturation);
+        }
+
+        if let Some(color_temperature) = color_temperature {
+            params = params.color_temperature(*color_temperature);
+        }
+
+        match on {
+            Some(true) => params.on(),
+            Some(false) => params.off(),
+            None => params,
+        }
+    };
+
+    let sets_color_params = on.is_some()
+        || brightness.is_some()
+        || color.is_some()
+        || hue_saturation.is_some()
+        || color_temperature.is_some();
+
+    match client {
+        TapoDeviceInner::L510(device)
+        | TapoDeviceInner::L520(device)
+        | TapoDeviceInner::L610(device) => {
+            match (on, brightness) {
+                // Setting the brightness turns the light on, so it is skipped for lights to turn off
+                (Some(false), _) => device.off().await?,
+                (_, Some(brightness)) => device.set_brightness(*brightness).await?,
+                (Some(true), None) => device.on().await?,
+                (None, None) => {}
+            }
+        }
+
+        TapoDeviceInner::L530(device)
+        | TapoDeviceInner::L535(device)
+        | TapoDeviceInner::L630(device) => {
+            if sets_color_params {
+                color_params().send(device).await?;
+            }
+        }
+
+        TapoDeviceInner::L900(device) => {
+            if sets_color_params {
+                color_params().send(device).await?;
+            }
+        }
+
+        TapoDeviceInner::L920(device) | TapoDeviceInner::L930(device) => {
+            if sets_color_params {
+                color_params().send(device).await?;
+            }
+
+            match lighting_effect {
+                Some(SceneLightingEffect::Preset(preset)) => {
+                    device.set_lighting_effect(preset.clone()).await?;
+                }
+                Some(SceneLightingEffect::Custom(effect)) => {
+                    device.set_lighting_effect(*effect.clone()).await?;
+                }
+                None => {}
+            }
+        }
+
+        TapoDeviceInner::P100(device) | TapoDeviceInner::P105(device) => match on {
+            Some(true) => device.on().await?,
+            Some(false) => device.off().await?,
+            None => {}
+        },
+
+        TapoDeviceInner::P110(device)
+        | TapoDeviceInner::P110M(device)
+        | TapoDeviceInner::P115(device) => match on {
+            Some(true) => device.on().await?,
+            Some(false) => device.off().await?,
+            None => {}
+        },
+
+        // Scenes are validated against the devices' capabilities, so there is nothing to set here
+        TapoDeviceInner::P300(_)
+        | TapoDeviceInner::P304(_)
+        | TapoDeviceInner::P304M(_)
+        | TapoDeviceInner::P316(_)
+        | TapoDeviceInner::H100(_)
+        | TapoDeviceInner::H200(_) => {}
+    }
+
+    Ok(())
+}
+
+async fn get_state(client: &TapoDeviceInner, name: &str) -> ApiResult<SceneDeviceState> {
+    let mut state = SceneDeviceState {
+        device: name.to_owned(),
+        on: None,
+        brightness: None,
+        color: None,
+        hue_saturation: None,
+        color_temperature: None,
+        lighting_effect: None,
+    };
+
+    // Color is either described by a temperature, or by a hue and saturation
+    let set_color = |state: &mut SceneDeviceState,
+                     color_temp: u16,
+                     hue: Option<u16>,
+                     saturation: Option<u16>| {
+        if color_temp > 0 {
+            state.color_temperature = Some(color_temp);
+        } else if let (Some(hue), Some(saturation)) = (hue, saturation) {
+            state.hue_saturation = Some(HueSaturation {
+                hue,
+                saturation: u8::try_from(saturation).unwrap_or(u8::MAX),
+            });
+        }
+    };
+
+    match client {
+        TapoDeviceInner::L510(device)
+        | TapoDeviceInner::L520(device)
+        | TapoDeviceInner::L610(device) => {
+            let info = device.get_device_info().await?;
+            state.on = Some(info.device_on);
+            state.brightness = Some(info.brightness);
+        }
+
+        TapoDeviceInner::L530(device)
+        | TapoDeviceInner::L535(device)
+        | TapoDeviceInner::L630(device) => {
+            let info = device.get_device_info().await?;
+            state.on = Some(info.device_on);
+            state.brightness = Some(info.brightness);
+            set_color(&mut state, info.color_temp, info.hue, info.saturation);
+        }
+
+        TapoDeviceInner::L900(device) => {
+            let info = device.get_device_info().await?;
+            state.on = Some(info.device_on);
+            state.brightness = Some(info.brightness);
+            set_color(&mut state, info.color_temp, info.hue, info.saturation);
+        }
+
+        // The active lighting effect isn't part of the device's informations, so it can't be captured
+        TapoDeviceInner::L920(device) | TapoDeviceInner::L930(device) => {
+            let info = device.get_device_info().await?;
+            state.on = Some(info.device_on);
+            state.brightness = Some(info.brightness);
+            set_color(&mut state, info.color_temp, info.hue, info.saturation);
+        }
+
+        TapoDeviceInner::P100(device) | TapoDeviceInner::P105(device) => {
+            state.on = Some(device.get_device_info().await?.device_on);
+        }
+
+        TapoDeviceInner::P110(device)
+        | TapoDeviceInner::P110M(device)
+        | TapoDeviceInner::P115(device) => {
+            state.on = Some(device.get_device_info().await?.device_on);
+        }
+
+        TapoDeviceInner::P300(_)
+        | TapoDeviceInner::P304(_)
+        | TapoDeviceInner::P304M(_)
+        | TapoDeviceInner::P316(_)
+        | TapoDeviceInner::H100(_)
+        | TapoDeviceInner::H200(_) => {
+            return Err(ApiError::new(
+                ErrorCode::UnsupportedAction,
+                format!(
+                    "The state of {} devices can't be captured in a scene",
+                    client.type_name()
+                ),
+            ));
+        }
+    }
+
+    Ok(state)
+}
+
+/// Document the scene routes in the `OpenAPI` specification
+pub fn add_scenes_to_openapi(spec: &mut OpenApiBuilder) {
+    spec.add_route(
+        "/scenes",
+        &Method::GET,
+        json!({
+            "summary": "List all scenes",
+            "responses": { "200": { "description": "Success" } }
+        }),
+    );
+
+    let scene_param = json!({
+        "name": "name",
+        "in": "path",
+        "required": true,
+        "schema": { "type": "string" }
+    });
+
+    spec.add_route(
+        "/scenes/{name}/apply",
+        &Method::POST,
+        json!({
+            "summary": "Apply a scene concurrently on all its devices, and report the result for each device",
+            "parameters": [scene_param],
+            "responses": {
+                "200": { "description": "Success" },
+                "404": OpenApiBuilder::error_response("Scene not found")
+            }
+        }),
+    );
+
+    spec.add_route(
+        "/scenes/{name}/capture",
+        &Method::POST,
+        json!({
+            "summary": "Create a scene from the current state of some devices (all devices that can be turned on and off by default)",
+            "parameters": [scene_param],
+            "requestBody": {
+                "required": false,
+                "content": {
+                    "application/json": {
+                        "schema": {
+                            "type": "object",
+                            "properties": {
+                                "devices": { "type": "array", "items": { "type": "string" } }
+                            }
+                        }
+                    }
+                }
+            },
+            "responses": {
+                "200": {
+                    "description": "The captured scene, which is kept in memory until the configuration is reloaded ('ephemeral' is always true)"
+                },
+                "400": OpenApiBuilder::error_response("The state of a device can't be captured"),
+                "404": OpenApiBuilder::error_response("Device not found"),
+                "502": OpenApiBuilder::error_response("Device error"),
+                "503": OpenApiBuilder::error_response("Device unreachable")
+            }
+        }),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use super::*;
+
+    fn validate(devices: Value) -> Result<()> {
+        let mut scene = json!({ "name": "evening" });
+        scene["devices"] = devices;
+        let scene = serde_json::from_value(scene).unwrap();
+
+        let devices = serde_json::from_value::<Vec<TapoConnectionInfos>>(json!([
+            { "name": "lamp", "device_type": "L530", "ip_addr": "127.0.0.1" },
+            { "name": "plug", "device_type": "P100", "ip_addr": "127.0.0.1" }
+        ]))
+        .unwrap();
+
+        validate_scene(&scene, &devices)
+    }
+
+    fn error(devices: Value) -> String {
+        validate(devices).err().unwrap().to_string()
+    }
+
+    #[test]
+    fn valid_scene() {
+        assert!(
+            validate(json!([
+                { "device": "lamp", "on": true, "brightness": 40, "color_temperature": 2700 },
+                { "device": "plug", "on": false }
+            ]))
+            .is_ok()
+        );
+    }
+
+    #[test]
+    fn devices_must_exist_and_support_the_properties() {
+        assert_eq!(
+            error(json!([{ "device": "desk", "on": true }])),
+            "Scene 'evening' refers to unknown device 'desk'"
+        );
+        assert_eq!(
+            error(json!([{ "device": "plug", "brightness": 40 }])),
+            "Scene 'evening' sets property 'brightness' of device 'plug', which it doesn't support"
+        );
+    }
+
+    #[test]
+    fn devices_are_set_once() {
+        assert_eq!(
+            error(json!([
+                { "device": "lamp", "on": true },
+                { "device": "lamp", "brightness": 40 }
+            ])),
+            "Scene 'evening' sets the state of device 'lamp' multiple times"
+        );
+    }
+
+    #[test]
+    fn color_settings_are_exclusive() {
+        assert!(
+            validate(json!([{ "device": "lamp", "color_temperature": 2700, "hue_saturation": { "hue": 120, "saturation": 50 } }]))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn captured_scenes_are_reported_as_ephemeral() {
+        let captured = CapturedScene {
+            scene: serde_json::from_value(json!({
+                "name": "evening",
+                "devices": [{ "device": "plug", "on": true }]
+            }))
+            .unwrap(),
+            ephemeral: true,
+        };
+
+        assert_eq!(
+            serde_json::to_value(captured).unwrap(),
+            json!({
+                "name": "evening",
+                "devices": [{ "device": "plug", "on": true }],
+                "ephemeral": true
+            })
+        );
+    }
+}
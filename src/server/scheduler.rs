@@ -0,0 +1,644 @@
+use std::{collections::HashMap, str::FromStr, time::Duration};
+
+use anyhow::{Context, Result, anyhow, bail};
+use axum::{
+    Json,
+    body::Bytes,
+    extract::{Path, State},
+    http::{Method, Uri},
+};
+use chrono::{DateTime, Local, NaiveDate, TimeDelta};
+use log::{info, warn};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::config::{Config, Location, Schedule, ScheduleTarget, ScheduleTrigger};
+
+use super::{
+    ApiError, ApiResult, ErrorCode, SharedState,
+    actions::dispatch_action,
+    groups::{ActionReport, dispatch_group_action},
+    openapi::OpenApiBuilder,
+    scenes::dispatch_scene,
+    sun::{SunEvent, sun_event_time},
+};
+
+/// Delay between two checks for schedules to run
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of days to look ahead for the next sunrise or sunset, to account for polar days and nights
+const SUN_EVENT_LOOKAHEAD_DAYS: usize = 366;
+
+/// Runtime informations about a schedule
+#[derive(Serialize, Clone, Default)]
+pub struct ScheduleRuns {
+    pub next_run: Option<DateTime<Local>>,
+    pub last_run: Option<DateTime<Local>>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ScheduleDetails {
+    #[serde(flatten)]
+    schedule: Schedule,
+    #[serde(flatten)]
+    runs: ScheduleRuns,
+}
+
+/// What a schedule's next run was computed from
+#[derive(PartialEq)]
+struct NextRunInputs {
+    trigger: ScheduleTrigger,
+    location: Option<Location>,
+    date: NaiveDate,
+}
+
+impl NextRunInputs {
+    fn new(trigger: &ScheduleTrigger, location: Option<Location>, now: DateTime<Local>) -> Self {
+        Self {
+            trigger: trigger.clone(),
+            location,
+            date: now.date_naive(),
+        }
+    }
+
+    /// Whether the next run must be computed again
+    ///
+    /// This is the case when the configuration was reloaded with a different trigger or location. Schedules without
+    /// any next run (e.g. a sunrise during a polar night) are only checked again once the date has changed, as looking
+    /// for the next sun event is expensive.
+    fn is_stale(&self, current: &Self, next_run: Option<DateTime<Local>>) -> bool {
+        self.trigger != current.trigger
+            || self.location != current.location
+            || (next_run.is_none() && self.date != current.date)
+    }
+}
+
+/// Run schedules when they are due
+pub async fn run_scheduler(state: SharedState) {
+    // Inputs each schedule's next run was computed from, to detect changes when the configuration is reloaded
+    let mut computed = HashMap::<String, NextRunInputs>::new();
+    let mut interval = tokio::time::interval(TICK_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let (schedules, location) = {
+            let config = state.config.read().await;
+            (config.schedules.clone(), config.location)
+        };
+
+        let now = Local::now();
+        let mut runs = state.schedule_runs.write().await;
+
+        // Forget about schedules that were removed from the configuration
+        runs.retain(|name, _| schedules.iter().any(|schedule| &schedule.name == name));
+        computed.retain(|name, _| schedules.iter().any(|schedule| &schedule.name == name));
+
+        for schedule in schedules {
+            let run = runs.entry(schedule.name.clone()).or_default();
+
+            if !schedule.enabled {
+                run.next_run = None;
+                computed.remove(&schedule.name);
+                continue;
+            }
+
+            let inputs = NextRunInputs::new(&schedule.when, location, now);
+
+            if computed
+                .get(&schedule.name)
+                .is_none_or(|previous| previous.is_stale(&inputs, run.next_run))
+            {
+                run.next_run = next_run(&schedule.when, location, now);
+                computed.insert(schedule.name.clone(), inputs);
+            }
+
+            if run.next_run.is_none_or(|next_run| next_run > now) {
+                continue;
+            }
+
+            run.last_run = Some(now);
+            run.next_run = next_run(&schedule.when, location, now);
+            computed.insert(
+                schedule.name.clone(),
+                NextRunInputs::new(&schedule.when, location, now),
+            );
+
+            tokio::spawn(execute_schedule(SharedState::clone(&state), schedule));
+        }
+    }
+}
+
+async fn execute_schedule(state: SharedState, schedule: Schedule) {
+    info!("|> Running schedule '{}'...", schedule.name);
+
+    let result = run_target(&state, &schedule.run).await;
+
+    if let Err(err) = &result {
+        warn!("! Schedule '{}' failed: {err:#}", schedule.name);
+    }
+
+    if let Some(run) = state.schedule_runs.write().await.get_mut(&schedule.name) {
+        run.last_error = result.err().map(|err| format!("{err:#}"));
+    }
+}
+
+async fn run_target(state: &SharedState, target: &ScheduleTarget) -> Result<()> {
+    // Scheduled actions are meant to change the state of devices, so they are run as POST requests
+    let uri = Uri::from_static("/");
+
+    match target {
+        ScheduleTarget::Device {
+            name,
+            action,
+            params,
+        } => {
+            let body = params_body(params.as_ref())?;
+
+            dispatch_action(state, name, action, &Method::POST, &uri, &body)
+                .await
+                .map_err(|err| anyhow!("{err}"))?;
+
+            Ok(())
+        }
+
+        ScheduleTarget::Group {
+            name,
+            action,
+            params,
+        } => {
+            let body = params_body(params.as_ref())?;
+
+            let report = dispatch_group_action(state, name, action, &Method::POST, &uri, &body)
+                .await
+                .map_err(|err| anyhow!("{err}"))?;
+
+            check_report(&report)
+        }
+
+        ScheduleTarget::Scene(name) => {
+            let report = dispatch_scene(state, name)
+                .await
+                .map_err(|err| anyhow!("{err}"))?;
+
+            check_report(&report)
+        }
+    }
+}
+
+pub fn params_body(params: Option<&serde_json::Value>) -> Result<Bytes> {
+    match params {
+        Some(params) => serde_json::to_vec(params)
+            .map(Bytes::from)
+            .context("Failed to serialize action parameters"),
+        None => Ok(Bytes::new()),
+    }
+}
+
+fn check_report(report: &ActionReport) -> Result<()> {
+    let errors = report.errors().map(ToString::to_string).collect::<Vec<_>>();
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        bail!("{}", errors.join(", "))
+    }
+}
+
+/// Compute the next time a schedule should run at
+pub fn next_run(
+    trigger: &ScheduleTrigger,
+    location: Option<Location>,
+    after: DateTime<Local>,
+) -> Option<DateTime<Local>> {
+    match trigger {
+        ScheduleTrigger::Cron(expr) => parse_cron(expr).ok()?.after(&after).next(),
+
+        ScheduleTrigger::Sunrise { offset_minutes } => {
+            next_sun_event(SunEvent::Sunrise, *offset_minutes, location?, after)
+        }
+
+        ScheduleTrigger::Sunset { offset_minutes } => {
+            next_sun_event(SunEvent::Sunset, *offset_minutes, location?, after)
+        }
+    }
+}
+
+fn next_sun_event(
+    event: SunEvent,
+    offset_minutes: i32,
+    location: Location,
+    after: DateTime<Local>,
+) -> Option<DateTime<Local>> {
+    let offset = TimeDelta::minutes(offset_minutes.into());
+
+    // Start from the previous day, as a positive offset may move the event to the next day
+    after
+        .date_naive()
+        .pred_opt()?
+        .iter_days()
+        .take(SUN_EVENT_LOOKAHEAD_DAYS)
+        .filter_map(|date| sun_event_time(event, date, location))
+        .map(|time| time.with_timezone(&Local) + offset)
+        .find(|time| *time > after)
+}
+
+/// Parse a cron expression, with an optional seconds field
+fn parse_cron(expr: &str) -> Result<cron::Schedule> {
+    let expr = if expr.split_whitespace().count() == 5 {
+        format!("0 {expr}")
+    } else {
+        expr.to_owned()
+    };
+
+    cron::Schedule::from_str(&expr).with_context(|| format!("Invalid cron expression '{expr}'"))
+}
+
+/// Ensure a schedule is valid and only refers to existing devices, groups and scenes
+pub fn validate_schedule(schedule: &Schedule, config: &Config) -> Result<()> {
+    let name = &schedule.name;
+
+    match &schedule.when {
+        ScheduleTrigger::Cron(expr) => {
+            parse_cron(expr).with_context(|| format!("Invalid trigger for schedule '{name}'"))?;
+        }
+
+        ScheduleTrigger::Sunrise { .. } | ScheduleTrigger::Sunset { .. } => {
+            if config.location.is_none() {
+                bail!(
+                    "Schedule '{name}' depends on sunrise or sunset, but no location is configured"
+                );
+            }
+        }
+    }
+
+    match &schedule.run {
+        ScheduleTarget::Device {
+            name: device,
+            action,
+            ..
+        } => {
+            let Some(conn_infos) = config
+                .devices
+                .iter()
+                .find(|candidate| &candidate.name == device)
+            else {
+                bail!("Schedule '{name}' refers to unknown device '{device}'");
+            };
+
+            match conn_infos.device_type.action_method(action) {
+                Some(method) => check_scheduled_method(name, action, &method)?,
+                None => bail!(
+                    "Schedule '{name}' runs action '{action}', which is not supported by device '{device}'"
+                ),
+            }
+        }
+
+        ScheduleTarget::Group {
+            name: group,
+            action,
+            ..
+        } => {
+            let Some(group) = config
+                .groups
+                .iter()
+                .find(|candidate| &candidate.name == group)
+            else {
+                bail!("Schedule '{name}' refers to unknown group '{group}'");
+            };
+
+            // Members not supporting the action are reported when running it, like for any group action
+            for conn_infos in config
+                .devices
+                .iter()
+                .filter(|device| group.devices.contains(&device.name))
+            {
+                if let Some(method) = conn_infos.device_type.action_method(action) {
+                    check_scheduled_method(name, action, &method)?;
+                }
+            }
+        }
+
+        ScheduleTarget::Scene(scene) => {
+            if !config
+                .scenes
+                .iter()
+                .any(|candidate| &candidate.name == scene)
+            {
+                bail!("Schedule '{name}' refers to unknown scene '{scene}'");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Ensure a scheduled action changes the state of devices, as it is run as a `POST` request
+fn check_scheduled_method(schedule: &str, action: &str, method: &Method) -> Result<()> {
+    if *method != Method::POST {
+        bail!(
+            "Schedule '{schedule}' runs read-only action '{action}', only actions changing the state of devices can be scheduled"
+        );
+    }
+
+    Ok(())
+}
+
+pub async fn list_schedules(State(state): State<SharedState>) -> Json<Vec<ScheduleDetails>> {
+    let schedules = state.config.read().await.schedules.clone();
+    let runs = state.schedule_runs.read().await;
+
+    Json(
+        schedules
+            .into_iter()
+            .map(|schedule| ScheduleDetails {
+                runs: runs.get(&schedule.name).cloned().unwrap_or_default(),
+                schedule,
+            })
+            .collect(),
+    )
+}
+
+pub async fn enable_schedule(
+    State(state): State<SharedState>,
+    Path(name): Path<String>,
+) -> ApiResult<Json<ScheduleDetails>> {
+    set_schedule_enabled(&state, &name, true).await.map(Json)
+}
+
+pub async fn disable_schedule(
+    State(state): State<SharedState>,
+    Path(name): Path<String>,
+) -> ApiResult<Json<ScheduleDetails>> {
+    set_schedule_enabled(&state, &name, false).await.map(Json)
+}
+
+/// Enable or disable a schedule until the configuration is reloaded
+async fn set_schedule_enabled(
+    state: &SharedState,
+    name: &str,
+    enabled: bool,
+) -> ApiResult<ScheduleDetails> {
+    let mut config = state.config.write().await;
+    let location = config.location;
+
+    let schedule = config
+        .schedules
+        .iter_mut()
+        .find(|candidate| candidate.name == name)
+        .ok_or_else(|| {
+            ApiError::new(
+                ErrorCode::ScheduleNotFound,
+                format!("Schedule '{name}' was not found"),
+            )
+        })?;
+
+    schedule.enabled = enabled;
+
+    let mut runs = state.schedule_runs.write().await;
+    let run = runs.entry(name.to_owned()).or_default();
+
+    run.next_run = if enabled {
+        next_run(&schedule.when, location, Local::now())
+    } else {
+        None
+    };
+
+    Ok(ScheduleDetails {
+        schedule: schedule.clone(),
+        runs: run.clone(),
+    })
+}
+
+/// Document the schedule routes in the `OpenAPI` specification
+pub fn add_schedules_to_openapi(spec: &mut OpenApiBuilder) {
+    spec.add_route(
+        "/schedules",
+        &Method::GET,
+        json!({
+            "summary": "List all schedules, along with their next and last run times",
+            "responses": { "200": { "description": "Success" } }
+        }),
+    );
+
+    for (action, summary) in [
+        (
+            "enable",
+            "Enable a schedule until the configuration is reloaded",
+        ),
+        (
+            "disable",
+            "Disable a schedule until the configuration is reloaded",
+        ),
+    ] {
+        spec.add_route(
+            &format!("/schedules/{{name}}/{action}"),
+            &Method::POST,
+            json!({
+                "summary": summary,
+                "parameters": [{
+                    "name": "name",
+                    "in": "path",
+                    "required": true,
+                    "schema": { "type": "string" }
+                }],
+                "responses": {
+                    "200": { "description": "Success" },
+                    "404": OpenApiBuilder::error_response("Schedule not found")
+                }
+            }),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, TimeZone, Timelike, Utc};
+    use serde_json::json;
+
+    use super::*;
+
+    const PARIS: Location = Location {
+        latitude: 48.8566,
+        longitude: 2.3522,
+    };
+
+    fn local(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        Local
+            .with_ymd_and_hms(year, month, day, hour, minute, 0)
+            .single()
+            .unwrap()
+    }
+
+    #[test]
+    fn five_field_cron_runs_at_second_zero() {
+        let trigger = ScheduleTrigger::Cron("30 7 * * *".to_owned());
+
+        assert_eq!(
+            next_run(&trigger, None, local(2024, 6, 15, 8, 0)),
+            Some(local(2024, 6, 16, 7, 30))
+        );
+        assert_eq!(
+            next_run(&trigger, None, local(2024, 6, 15, 7, 0)),
+            Some(local(2024, 6, 15, 7, 30))
+        );
+    }
+
+    #[test]
+    fn six_field_cron_keeps_its_seconds() {
+        let trigger = ScheduleTrigger::Cron("15 30 7 * * *".to_owned());
+
+        let next = next_run(&trigger, None, local(2024, 6, 15, 8, 0)).unwrap();
+
+        assert_eq!(next, local(2024, 6, 16, 7, 30) + TimeDelta::seconds(15));
+        assert_eq!(next.second(), 15);
+    }
+
+    #[test]
+    fn invalid_cron_never_runs() {
+        let trigger = ScheduleTrigger::Cron("every morning".to_owned());
+
+        assert!(next_run(&trigger, None, local(2024, 6, 15, 8, 0)).is_none());
+        assert!(parse_cron("every morning").is_err());
+        assert!(parse_cron("61 * * * *").is_err());
+    }
+
+    #[test]
+    fn sun_events_need_a_location() {
+        let trigger = ScheduleTrigger::Sunrise { offset_minutes: 0 };
+
+        assert!(next_run(&trigger, None, local(2024, 6, 15, 8, 0)).is_none());
+    }
+
+    #[test]
+    fn sunset_with_offset() {
+        let after = Utc
+            .with_ymd_and_hms(2024, 6, 21, 12, 0, 0)
+            .unwrap()
+            .with_timezone(&Local);
+
+        let today = NaiveDate::from_ymd_opt(2024, 6, 21).unwrap();
+        let sunset = sun_event_time(SunEvent::Sunset, today, PARIS).unwrap();
+
+        let trigger = ScheduleTrigger::Sunset {
+            offset_minutes: -30,
+        };
+
+        assert_eq!(
+            next_run(&trigger, Some(PARIS), after),
+            Some(sunset.with_timezone(&Local) - TimeDelta::minutes(30))
+        );
+    }
+
+    #[test]
+    fn sunrise_after_today_runs_tomorrow() {
+        let after = Utc
+            .with_ymd_and_hms(2024, 6, 21, 12, 0, 0)
+            .unwrap()
+            .with_timezone(&Local);
+
+        let tomorrow = NaiveDate::from_ymd_opt(2024, 6, 22).unwrap();
+        let sunrise = sun_event_time(SunEvent::Sunrise, tomorrow, PARIS).unwrap();
+
+        let trigger = ScheduleTrigger::Sunrise { offset_minutes: 15 };
+
+        assert_eq!(
+            next_run(&trigger, Some(PARIS), after),
+            Some(sunrise.with_timezone(&Local) + TimeDelta::minutes(15))
+        );
+    }
+
+    #[test]
+    fn missing_next_run_is_computed_again_on_the_next_day() {
+        let trigger = ScheduleTrigger::Sunrise { offset_minutes: 0 };
+        let computed = NextRunInputs::new(&trigger, Some(PARIS), local(2024, 6, 15, 8, 0));
+
+        let later = NextRunInputs::new(&trigger, Some(PARIS), local(2024, 6, 15, 23, 59));
+        assert!(!computed.is_stale(&later, None));
+        assert!(!computed.is_stale(&later, Some(local(2024, 6, 16, 5, 47))));
+
+        let next_day = NextRunInputs::new(&trigger, Some(PARIS), local(2024, 6, 16, 0, 0));
+        assert!(computed.is_stale(&next_day, None));
+        assert!(!computed.is_stale(&next_day, Some(local(2024, 6, 16, 5, 47))));
+    }
+
+    #[test]
+    fn next_run_is_computed_again_when_the_configuration_changes() {
+        let trigger = ScheduleTrigger::Sunrise { offset_minutes: 0 };
+        let now = local(2024, 6, 15, 8, 0);
+        let next_run = Some(local(2024, 6, 16, 5, 47));
+        let computed = NextRunInputs::new(&trigger, Some(PARIS), now);
+
+        let sunset = ScheduleTrigger::Sunset { offset_minutes: 0 };
+        assert!(computed.is_stale(&NextRunInputs::new(&sunset, Some(PARIS), now), next_run));
+
+        let tromso = Location {
+            latitude: 69.6492,
+            longitude: 18.9553,
+        };
+        assert!(computed.is_stale(&NextRunInputs::new(&trigger, Some(tromso), now), next_run));
+    }
+
+    fn config_with_schedule(run: serde_json::Value) -> (Config, Schedule) {
+        let config = serde_json::from_value(json!({
+            "tapo_credentials": { "email": "user@example.com", "password": "secret" },
+            "devices": [
+                { "name": "plug", "device_type": "P100", "ip_addr": "127.0.0.1" },
+                { "name": "bulb", "device_type": "L530", "ip_addr": "127.0.0.1" }
+            ],
+            "groups": [{ "name": "all", "devices": ["plug", "bulb"] }],
+            "server": { "password": "secret", "api_keys": [] }
+        }))
+        .unwrap();
+
+        let mut schedule = json!({ "name": "morning", "when": { "cron": "0 7 * * *" } });
+        schedule["run"] = run;
+        let schedule = serde_json::from_value(schedule).unwrap();
+
+        (config, schedule)
+    }
+
+    fn validation_error(run: serde_json::Value) -> Option<String> {
+        let (config, schedule) = config_with_schedule(run);
+
+        validate_schedule(&schedule, &config)
+            .err()
+            .map(|err| err.to_string())
+    }
+
+    #[test]
+    fn scheduled_device_actions_must_change_the_state() {
+        assert_eq!(
+            validation_error(json!({ "device": { "name": "bulb", "action": "set-brightness" } })),
+            None
+        );
+        assert_eq!(
+            validation_error(json!({ "device": { "name": "bulb", "action": "get-device-info" } }))
+                .as_deref(),
+            Some(
+                "Schedule 'morning' runs read-only action 'get-device-info', only actions changing the state of devices can be scheduled"
+            )
+        );
+        assert_eq!(
+            validation_error(json!({ "device": { "name": "plug", "action": "set-brightness" } }))
+                .as_deref(),
+            Some(
+                "Schedule 'morning' runs action 'set-brightness', which is not supported by device 'plug'"
+            )
+        );
+    }
+
+    #[test]
+    fn scheduled_group_actions_may_not_apply_to_all_members() {
+        // The plug doesn't support brightness, which is reported when running the schedule
+        assert_eq!(
+            validation_error(json!({ "group": { "name": "all", "action": "set-brightness" } })),
+            None
+        );
+        assert!(
+            validation_error(json!({ "group": { "name": "all", "action": "get-device-info" } }))
+                .is_some()
+        );
+    }
+}
@@ -1,46 +1,219 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::{HashMap, VecDeque},
+    path::PathBuf,
+    sync::Arc,
+};
 
 use anyhow::{Context, Result, bail};
-use tokio::{fs, sync::RwLock};
+use log::info;
+use serde::Serialize;
+use tokio::{
+    fs,
+    sync::{Mutex, RwLock, broadcast, watch},
+};
 
-use crate::{config::Config, devices::TapoDevice};
+use crate::{
+    config::{Config, ConfigFormat, TapoConnectionInfos},
+    devices::TapoDevice,
+    discovery::parse_mac,
+};
 
-use super::loader::load_tapo_devices;
+use super::{
+    costs::validate_tariffs,
+    energy::EnergyHistory,
+    events::{DeviceEvent, DeviceSnapshot, EVENTS_CAPACITY, validate_events_config},
+    loader::{connect_tapo_devices, load_tapo_devices},
+    metrics::RequestMetrics,
+    mqtt::validate_mqtt_config,
+    scenes::validate_scene,
+    scheduler::{ScheduleRuns, validate_schedule},
+    webhooks::{WebhookDelivery, new_delivery_log, validate_webhook},
+};
+
+/// Changes to the configured devices applied by a configuration reload
+#[derive(Serialize, Default)]
+pub struct ConfigDiff {
+    pub added: Vec<String>,
+    /// Devices whose connection infos or credentials changed, which were reconnected to
+    pub changed: Vec<String>,
+    pub removed: Vec<String>,
+    /// Devices that kept their established session
+    pub unchanged: Vec<String>,
+}
+
+impl ConfigDiff {
+    /// Compare the currently connected devices to the newly configured ones
+    ///
+    /// All devices are reconnected to if the Tapo credentials changed.
+    fn compute<'a>(
+        current: impl IntoIterator<Item = &'a TapoConnectionInfos>,
+        configured: &[TapoConnectionInfos],
+        credentials_changed: bool,
+    ) -> Self {
+        let mut diff = Self::default();
+        let mut current = current
+            .into_iter()
+            .map(|conn_infos| (&conn_infos.name, conn_infos))
+            .collect::<HashMap<_, _>>();
+
+        for conn_infos in configured {
+            match current.remove(&conn_infos.name) {
+                Some(previous) if !credentials_changed && previous == conn_infos => {
+                    diff.unchanged.push(conn_infos.name.clone());
+                }
+                Some(_) => diff.changed.push(conn_infos.name.clone()),
+                None => diff.added.push(conn_infos.name.clone()),
+            }
+        }
+
+        diff.removed = current.into_keys().cloned().collect();
+
+        for names in [
+            &mut diff.added,
+            &mut diff.changed,
+            &mut diff.removed,
+            &mut diff.unchanged,
+        ] {
+            names.sort();
+        }
+
+        diff
+    }
+}
 
 pub struct StateData {
     pub config_path: PathBuf,
     pub config: RwLock<Config>,
-    pub devices: RwLock<HashMap<String, TapoDevice>>,
+    pub devices: RwLock<HashMap<String, Arc<TapoDevice>>>,
+    pub schedule_runs: RwLock<HashMap<String, ScheduleRuns>>,
+    pub events: broadcast::Sender<DeviceEvent>,
+    /// Latest state of each device, as fetched by the poller
+    pub device_states: RwLock<HashMap<String, DeviceSnapshot>>,
+    pub webhook_deliveries: RwLock<VecDeque<WebhookDelivery>>,
+    pub request_metrics: RwLock<RequestMetrics>,
+    /// Opened once at startup, changes to its configuration require a restart
+    pub energy_history: Option<EnergyHistory>,
+    /// Set once the server is shutting down, to close long-lived connections
+    pub shutdown: watch::Sender<bool>,
+    reloading: Mutex<()>,
 }
 
 impl StateData {
     pub async fn init(config_path: PathBuf) -> Result<Self> {
-        let (config, devices) = load_config(&config_path).await?;
+        let config = read_config(&config_path).await?;
+
+        let devices = load_tapo_devices(&config)
+            .await
+            .context("Failed to load Tapo devices from configuration")?;
+
+        let devices = devices
+            .into_iter()
+            .map(|device| (device.conn_infos().name.clone(), Arc::new(device)))
+            .collect();
+
+        let energy_history = config
+            .energy_history
+            .as_ref()
+            .map(|history| EnergyHistory::open(&history.database))
+            .transpose()?;
 
         Ok(Self {
             config_path,
             config: RwLock::new(config),
             devices: RwLock::new(devices),
+            schedule_runs: RwLock::new(HashMap::new()),
+            events: broadcast::Sender::new(EVENTS_CAPACITY),
+            device_states: RwLock::new(HashMap::new()),
+            webhook_deliveries: RwLock::new(new_delivery_log()),
+            request_metrics: RwLock::new(RequestMetrics::new()),
+            energy_history,
+            shutdown: watch::Sender::new(false),
+            reloading: Mutex::new(()),
         })
     }
 
-    pub async fn reload_config(&self) -> Result<()> {
-        let (config, devices) = load_config(&self.config_path).await?;
+    /// Reload the configuration file, only reconnecting to the devices that were added or changed
+    ///
+    /// Unchanged devices keep their established session, and the current devices remain available
+    /// while connecting to the new ones.
+    pub async fn reload_config(&self) -> Result<ConfigDiff> {
+        // Reloads may be triggered concurrently by the API, the file watcher and signals
+        let _reloading = self.reloading.lock().await;
+
+        let config = read_config(&self.config_path).await?;
+
+        let credentials_changed =
+            self.config.read().await.tapo_credentials != config.tapo_credentials;
+
+        // Don't keep the devices locked while connecting to the new ones, as it may take a while
+        let current = self.devices.read().await.clone();
+
+        let diff = ConfigDiff::compute(
+            current.values().map(|device| device.conn_infos()),
+            &config.devices,
+            credentials_changed,
+        );
+
+        let kept = current
+            .into_iter()
+            .filter(|(name, _)| diff.unchanged.contains(name))
+            .collect::<HashMap<_, _>>();
+
+        let to_connect = config
+            .devices
+            .iter()
+            .filter(|device| !diff.unchanged.contains(&device.name))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if !to_connect.is_empty() {
+            info!(
+                "Attempting to connect to the {} added or changed device(s)...",
+                to_connect.len()
+            );
+        }
+
+        let connected = connect_tapo_devices(&to_connect, &config.tapo_credentials)
+            .await
+            .context("Failed to load Tapo devices from configuration")?;
+
+        let devices = kept
+            .into_iter()
+            .chain(
+                connected
+                    .into_iter()
+                    .map(|device| (device.conn_infos().name.clone(), Arc::new(device))),
+            )
+            .collect();
 
         *self.config.write().await = config;
         *self.devices.write().await = devices;
 
-        Ok(())
+        info!(
+            "Reloaded configuration: {} added, {} changed, {} removed and {} unchanged device(s)",
+            diff.added.len(),
+            diff.changed.len(),
+            diff.removed.len(),
+            diff.unchanged.len()
+        );
+
+        Ok(diff)
     }
 }
 
-async fn load_config(config_path: &PathBuf) -> Result<(Config, HashMap<String, TapoDevice>)> {
+/// Read and validate the configuration file
+async fn read_config(config_path: &PathBuf) -> Result<Config> {
+    let format = ConfigFormat::from_path(config_path)?;
+
     let config_str = fs::read_to_string(config_path)
         .await
         .context("Failed to read configuration file")?;
 
-    let config = serde_json::from_str::<Config>(&config_str)
-        .context("Failed to parse the devices configuration file")?;
+    let mut config = format.parse(&config_str)?;
+
+    for device in &mut config.devices {
+        validate_device(device)?;
+    }
 
     for api_key in &config.server.api_keys {
         if api_key.key.chars().any(|c| !c.is_ascii_alphanumeric()) {
@@ -58,14 +231,324 @@ async fn load_config(config_path: &PathBuf) -> Result<(Config, HashMap<String, T
         }
     }
 
-    let devices = load_tapo_devices(&config)
+    for (i, group) in config.groups.iter().enumerate() {
+        if config.groups[..i]
+            .iter()
+            .any(|other| other.name == group.name)
+        {
+            bail!("Group '{}' is defined multiple times", group.name);
+        }
+
+        if config
+            .devices
+            .iter()
+            .any(|device| device.name == group.name)
+        {
+            bail!("Group '{}' has the same name as a device", group.name);
+        }
+
+        for member in &group.devices {
+            if !config.devices.iter().any(|device| &device.name == member) {
+                bail!("Group '{}' refers to unknown device '{member}'", group.name);
+            }
+        }
+    }
+
+    for (i, scene) in config.scenes.iter().enumerate() {
+        if config.scenes[..i]
+            .iter()
+            .any(|other| other.name == scene.name)
+        {
+            bail!("Scene '{}' is defined multiple times", scene.name);
+        }
+
+        validate_scene(scene, &config.devices)?;
+    }
+
+    if let Some(location) = &config.location {
+        if !(-90.0..=90.0).contains(&location.latitude) {
+            bail!("Location's latitude must be between -90 and 90");
+        }
+
+        if !(-180.0..=180.0).contains(&location.longitude) {
+            bail!("Location's longitude must be between -180 and 180");
+        }
+    }
+
+    for (i, schedule) in config.schedules.iter().enumerate() {
+        if config.schedules[..i]
+            .iter()
+            .any(|other| other.name == schedule.name)
+        {
+            bail!("Schedule '{}' is defined multiple times", schedule.name);
+        }
+
+        validate_schedule(schedule, &config)?;
+    }
+
+    validate_events_config(&config)?;
+
+    for (i, webhook) in config.webhooks.iter().enumerate() {
+        if config.webhooks[..i]
+            .iter()
+            .any(|other| other.name == webhook.name)
+        {
+            bail!("Webhook '{}' is defined multiple times", webhook.name);
+        }
+
+        validate_webhook(webhook, &config)?;
+    }
+
+    validate_mqtt_config(&config)?;
+    validate_tariffs(&config)?;
+
+    if let Some(energy_history) = &config.energy_history
+        && energy_history.collect_interval_secs == 0
+    {
+        bail!("Energy history's collect interval must be at least 1 second");
+    }
+
+    Ok(config)
+}
+
+/// Check how a device is addressed, and normalize its MAC address
+fn validate_device(device: &mut TapoConnectionInfos) -> Result<()> {
+    if device.ip_addr.is_none() && device.host.is_none() && device.mac.is_none() {
+        bail!(
+            "Device '{}' must have an IP address ('ip_addr'), a host name ('host') or a MAC address ('mac')",
+            device.name
+        );
+    }
+
+    if device.ip_addr.is_some() && device.host.is_some() {
+        bail!(
+            "Device '{}' can't have both an IP address ('ip_addr') and a host name ('host')",
+            device.name
+        );
+    }
+
+    if device
+        .host
+        .as_deref()
+        .is_some_and(|host| host.trim().is_empty())
+    {
+        bail!("Device '{}' has an empty host name", device.name);
+    }
+
+    if let Some(mac) = &mut device.mac {
+        *mac = parse_mac(mac)
+            .with_context(|| format!("Device '{}' has an invalid MAC address", device.name))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+impl StateData {
+    /// Load a state from a minimal configuration, whose top-level fields are replaced by the provided ones
+    pub async fn for_tests(overrides: serde_json::Value) -> super::SharedState {
+        static NEXT_ID: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+        let mut config = serde_json::json!({
+            "tapo_credentials": { "email": "user@example.com", "password": "secret" },
+            "devices": [],
+            "server": { "password": "secret", "api_keys": [] }
+        });
+
+        for (key, value) in overrides.as_object().into_iter().flatten() {
+            config[key] = value.clone();
+        }
+
+        let id = NEXT_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let path =
+            std::env::temp_dir().join(format!("tapo-rest-test-{}-{id}.json", std::process::id()));
+
+        fs::write(&path, config.to_string()).await.unwrap();
+        let state = Self::init(path.clone()).await;
+        fs::remove_file(&path).await.unwrap();
+
+        Arc::new(state.unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::server::TapoDeviceType;
+
+    fn validate(mut infos: Value) -> Result<TapoConnectionInfos> {
+        infos["name"] = "plug".into();
+        infos["device_type"] = "P100".into();
+
+        let mut device = serde_json::from_value(infos).unwrap();
+        validate_device(&mut device)?;
+
+        Ok(device)
+    }
+
+    #[test]
+    fn devices_must_have_an_address() {
+        assert!(validate(json!({})).is_err());
+
+        for infos in [
+            json!({ "ip_addr": "192.168.1.42" }),
+            json!({ "host": "plug.lan" }),
+            json!({ "mac": "aa:bb:cc:dd:ee:ff" }),
+            json!({ "host": "plug.lan", "mac": "aa:bb:cc:dd:ee:ff" }),
+        ] {
+            assert!(validate(infos.clone()).is_ok(), "{infos}");
+        }
+    }
+
+    #[test]
+    fn ip_address_and_host_name_are_exclusive() {
+        assert!(validate(json!({ "ip_addr": "192.168.1.42", "host": "plug.lan" })).is_err());
+    }
+
+    #[test]
+    fn host_name_cannot_be_empty() {
+        assert!(validate(json!({ "host": " " })).is_err());
+    }
+
+    #[test]
+    fn mac_address_is_normalized() {
+        let device = validate(json!({ "mac": "aa-bb-cc-dd-ee-ff" })).unwrap();
+        assert_eq!(device.mac.as_deref(), Some("AA:BB:CC:DD:EE:FF"));
+
+        assert!(validate(json!({ "mac": "aa-bb-cc" })).is_err());
+    }
+
+    fn device(name: &str, ip_addr: &str) -> TapoConnectionInfos {
+        TapoConnectionInfos {
+            name: name.to_owned(),
+            device_type: TapoDeviceType::P110,
+            ip_addr: Some(ip_addr.parse().unwrap()),
+            host: None,
+            mac: None,
+        }
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn devices_are_added_changed_removed_or_unchanged() {
+        let current = [
+            device("kitchen", "192.168.1.10"),
+            device("desk", "192.168.1.11"),
+            device("garage", "192.168.1.12"),
+            device("bedroom", "192.168.1.13"),
+        ];
+
+        let configured = [
+            device("kitchen", "192.168.1.10"),
+            device("desk", "192.168.1.21"),
+            device("bedroom", "192.168.1.13"),
+            device("porch", "192.168.1.14"),
+        ];
+
+        let diff = ConfigDiff::compute(&current, &configured, false);
+
+        assert_eq!(diff.added, names(&["porch"]));
+        assert_eq!(diff.changed, names(&["desk"]));
+        assert_eq!(diff.removed, names(&["garage"]));
+        assert_eq!(diff.unchanged, names(&["bedroom", "kitchen"]));
+    }
+
+    #[test]
+    fn device_type_change_reconnects() {
+        let mut bulb = device("desk", "192.168.1.11");
+        bulb.device_type = TapoDeviceType::L530;
+
+        let diff = ConfigDiff::compute(&[device("desk", "192.168.1.11")], &[bulb], false);
+
+        assert_eq!(diff.changed, names(&["desk"]));
+        assert!(diff.unchanged.is_empty());
+    }
+
+    #[test]
+    fn credentials_change_reconnects_all_devices() {
+        let devices = [
+            device("kitchen", "192.168.1.10"),
+            device("desk", "192.168.1.11"),
+        ];
+
+        let diff = ConfigDiff::compute(&devices, &devices[..1], true);
+
+        assert!(diff.added.is_empty());
+        assert_eq!(diff.changed, names(&["kitchen"]));
+        assert_eq!(diff.removed, names(&["desk"]));
+        assert!(diff.unchanged.is_empty());
+    }
+
+    async fn read(overrides: Value) -> Result<Config> {
+        static NEXT_ID: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+        let mut config = json!({
+            "tapo_credentials": { "email": "user@example.com", "password": "secret" },
+            "devices": [
+                { "name": "lamp", "device_type": "L530", "ip_addr": "127.0.0.1" },
+                { "name": "plug", "device_type": "P100", "ip_addr": "127.0.0.1" }
+            ],
+            "server": { "password": "secret", "api_keys": [] }
+        });
+
+        for (key, value) in overrides.as_object().into_iter().flatten() {
+            config[key] = value.clone();
+        }
+
+        let id = NEXT_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!(
+            "tapo-rest-read-test-{}-{id}.json",
+            std::process::id()
+        ));
+
+        fs::write(&path, config.to_string()).await.unwrap();
+        let result = read_config(&path).await;
+        fs::remove_file(&path).await.unwrap();
+
+        result
+    }
+
+    #[tokio::test]
+    async fn group_names_are_unique() {
+        assert!(
+            read(json!({ "groups": [{ "name": "living-room", "devices": ["lamp", "plug"] }] }))
+                .await
+                .is_ok()
+        );
+
+        let err = read(json!({
+            "groups": [
+                { "name": "living-room", "devices": ["lamp"] },
+                { "name": "living-room", "devices": ["plug"] }
+            ]
+        }))
         .await
-        .context("Failed to load Tapo devices from configuration")?;
+        .err()
+        .unwrap();
 
-    let devices = devices
-        .into_iter()
-        .map(|device| (device.conn_infos().name.clone(), device))
-        .collect();
+        assert_eq!(
+            err.to_string(),
+            "Group 'living-room' is defined multiple times"
+        );
+    }
 
-    Ok((config, devices))
+    #[tokio::test]
+    async fn scene_names_are_unique() {
+        let err = read(json!({
+            "scenes": [
+                { "name": "evening", "devices": [{ "device": "lamp", "on": true }] },
+                { "name": "evening", "devices": [{ "device": "plug", "on": false }] }
+            ]
+        }))
+        .await
+        .err()
+        .unwrap();
+
+        assert_eq!(err.to_string(), "Scene 'evening' is defined multiple times");
+    }
 }
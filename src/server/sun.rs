@@ -0,0 +1,152 @@
+use std::time::Duration;
+
+use chrono::{DateTime, NaiveDate, TimeDelta, Utc};
+
+use crate::config::Location;
+
+/// Julian day of 2000-01-01 at noon (J2000 epoch)
+const J2000: f64 = 2_451_545.0;
+
+/// Julian day of the Unix epoch
+const UNIX_EPOCH_JULIAN_DAY: f64 = 2_440_587.5;
+
+/// Earth's axial tilt, in degrees
+const EARTH_OBLIQUITY: f64 = 23.4397;
+
+/// Altitude of the sun's center at sunrise and sunset, accounting for refraction and the sun's radius
+const SUN_ALTITUDE_AT_HORIZON: f64 = -0.833;
+
+#[derive(Clone, Copy)]
+pub enum SunEvent {
+    Sunrise,
+    Sunset,
+}
+
+/// Compute the time of sunrise or sunset on a given day, using the sunrise equation
+///
+/// Returns `None` if the sun doesn't rise or set on that day (polar day or night).
+pub fn sun_event_time(
+    event: SunEvent,
+    date: NaiveDate,
+    location: Location,
+) -> Option<DateTime<Utc>> {
+    let Location {
+        latitude,
+        longitude,
+    } = location;
+
+    let days_since_j2000 = (date - NaiveDate::from_ymd_opt(2000, 1, 1)?).num_days();
+    let days_since_j2000 = f64::from(i32::try_from(days_since_j2000).ok()?);
+
+    // Mean solar time
+    let mean_solar_time = days_since_j2000 + 0.0008 - longitude / 360.0;
+
+    // Solar mean anomaly
+    let mean_anomaly = (357.5291 + 0.985_600_28 * mean_solar_time).rem_euclid(360.0);
+    let mean_anomaly_rad = mean_anomaly.to_radians();
+
+    // Equation of the center
+    let center = 1.9148 * mean_anomaly_rad.sin()
+        + 0.02 * (2.0 * mean_anomaly_rad).sin()
+        + 0.0003 * (3.0 * mean_anomaly_rad).sin();
+
+    // Ecliptic longitude
+    let ecliptic_longitude = (mean_anomaly + center + 180.0 + 102.9372).rem_euclid(360.0);
+    let ecliptic_longitude_rad = ecliptic_longitude.to_radians();
+
+    // Solar transit (solar noon)
+    let transit = J2000 + mean_solar_time + 0.0053 * mean_anomaly_rad.sin()
+        - 0.0069 * (2.0 * ecliptic_longitude_rad).sin();
+
+    // Declination of the sun
+    let declination_sin = ecliptic_longitude_rad.sin() * EARTH_OBLIQUITY.to_radians().sin();
+    let declination_cos = declination_sin.asin().cos();
+
+    // Hour angle
+    let latitude_rad = latitude.to_radians();
+
+    let hour_angle_cos = (SUN_ALTITUDE_AT_HORIZON.to_radians().sin()
+        - latitude_rad.sin() * declination_sin)
+        / (latitude_rad.cos() * declination_cos);
+
+    if !(-1.0..=1.0).contains(&hour_angle_cos) {
+        return None;
+    }
+
+    let hour_angle = hour_angle_cos.acos().to_degrees();
+
+    let julian_day = match event {
+        SunEvent::Sunrise => transit - hour_angle / 360.0,
+        SunEvent::Sunset => transit + hour_angle / 360.0,
+    };
+
+    let since_unix_epoch =
+        Duration::try_from_secs_f64((julian_day - UNIX_EPOCH_JULIAN_DAY) * 86_400.0).ok()?;
+
+    Some(DateTime::UNIX_EPOCH + TimeDelta::from_std(since_unix_epoch).ok()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PARIS: Location = Location {
+        latitude: 48.8566,
+        longitude: 2.3522,
+    };
+
+    const TROMSO: Location = Location {
+        latitude: 69.6496,
+        longitude: 18.9560,
+    };
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    fn assert_close(time: Option<DateTime<Utc>>, expected: &str) {
+        let time = time.expect("the sun should rise and set");
+        let expected = expected.parse::<DateTime<Utc>>().unwrap();
+
+        assert!(
+            (time - expected).abs() < TimeDelta::minutes(3),
+            "{time} is too far from {expected}"
+        );
+    }
+
+    #[test]
+    fn summer_solstice_in_paris() {
+        let date = date(2024, 6, 21);
+
+        assert_close(
+            sun_event_time(SunEvent::Sunrise, date, PARIS),
+            "2024-06-21T03:47:00Z",
+        );
+        assert_close(
+            sun_event_time(SunEvent::Sunset, date, PARIS),
+            "2024-06-21T19:58:00Z",
+        );
+    }
+
+    #[test]
+    fn winter_solstice_in_paris() {
+        let date = date(2024, 12, 21);
+
+        assert_close(
+            sun_event_time(SunEvent::Sunrise, date, PARIS),
+            "2024-12-21T07:42:00Z",
+        );
+        assert_close(
+            sun_event_time(SunEvent::Sunset, date, PARIS),
+            "2024-12-21T15:56:00Z",
+        );
+    }
+
+    #[test]
+    fn polar_day_and_night() {
+        for date in [date(2024, 6, 21), date(2024, 12, 21)] {
+            assert!(sun_event_time(SunEvent::Sunrise, date, TROMSO).is_none());
+            assert!(sun_event_time(SunEvent::Sunset, date, TROMSO).is_none());
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use log::{debug, info, warn};
+use tokio::sync::mpsc;
+
+use crate::devices::{DeviceStatus, TapoDevice};
+
+use super::SharedState;
+
+/// Delay between two rounds of checks
+const TICK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Delay between two probes of a healthy device
+const PROBE_INTERVAL: Duration = Duration::from_mins(1);
+
+/// Delay before retrying to connect to a device after the first failure
+/// Doubled after each consecutive failure
+const BASE_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Maximum delay between two connection attempts to a failing device
+const MAX_RETRY_DELAY: Duration = Duration::from_mins(5);
+
+/// Periodically probe all devices, reconnecting to failing ones with an exponential backoff
+///
+/// Probes run independently from each other, so a slow device doesn't delay the probes of the other ones.
+pub async fn supervise_devices(state: SharedState) {
+    let mut next_probes = HashMap::<String, Instant>::new();
+    let mut probing = HashSet::<String>::new();
+    let mut interval = tokio::time::interval(TICK_INTERVAL);
+
+    // Probes report the delay before the next one once they complete
+    let (tx, mut rx) = mpsc::unbounded_channel::<(String, Duration)>();
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+
+            Some((name, delay)) = rx.recv() => {
+                probing.remove(&name);
+                next_probes.insert(name, Instant::now() + delay);
+                continue;
+            }
+        }
+
+        // Don't keep the devices locked while probing, as it may take a while
+        let devices = state
+            .devices
+            .read()
+            .await
+            .values()
+            .map(Arc::clone)
+            .collect::<Vec<_>>();
+
+        // Forget about devices that were removed from the configuration
+        next_probes.retain(|name, _| {
+            devices
+                .iter()
+                .any(|device| &device.conn_infos().name == name)
+        });
+
+        let now = Instant::now();
+
+        for device in devices {
+            let name = device.conn_infos().name.clone();
+
+            if probing.contains(&name) {
+                continue;
+            }
+
+            let health = device.health().await;
+
+            // Devices are connected to when loaded, so newly-seen ones don't need to be probed right away
+            let next_probe =
+                *next_probes
+                    .entry(name.clone())
+                    .or_insert_with(|| match health.status {
+                        DeviceStatus::Connected => now + PROBE_INTERVAL,
+                        DeviceStatus::Reconnecting | DeviceStatus::Unreachable => {
+                            now + retry_delay(health.consecutive_failures)
+                        }
+                    });
+
+            if next_probe > now {
+                continue;
+            }
+
+            let was_healthy = health.status == DeviceStatus::Connected;
+            let tx = tx.clone();
+
+            probing.insert(name.clone());
+
+            tokio::spawn(async move {
+                let delay = probe_device(&device, was_healthy).await;
+
+                // The receiver lives as long as the supervisor
+                let _ = tx.send((name, delay));
+            });
+        }
+    }
+}
+
+/// Probe a device, and return the delay before probing it again
+async fn probe_device(device: &TapoDevice, was_healthy: bool) -> Duration {
+    let name = &device.conn_infos().name;
+
+    match device.probe().await {
+        Ok(()) => {
+            if !was_healthy {
+                info!("|> Device {name} is now reachable");
+            }
+
+            debug!("Probed device '{name}' successfully");
+
+            PROBE_INTERVAL
+        }
+
+        Err(err) => {
+            let failures = device.health().await.consecutive_failures;
+            let delay = retry_delay(failures);
+
+            warn!(
+                "! Device '{name}' is unreachable ({failures} consecutive failure(s)), retrying in {}s: {err:#}",
+                delay.as_secs()
+            );
+
+            delay
+        }
+    }
+}
+
+fn retry_delay(failures: u32) -> Duration {
+    BASE_RETRY_DELAY
+        .saturating_mul(2_u32.saturating_pow(failures.saturating_sub(1)))
+        .min(MAX_RETRY_DELAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{TapoConnectionInfos, TapoCredentials};
+
+    use super::*;
+
+    #[test]
+    fn retry_delay_doubles_up_to_a_maximum() {
+        assert_eq!(retry_delay(0), BASE_RETRY_DELAY);
+        assert_eq!(retry_delay(1), BASE_RETRY_DELAY);
+        assert_eq!(retry_delay(2), BASE_RETRY_DELAY * 2);
+        assert_eq!(retry_delay(4), BASE_RETRY_DELAY * 8);
+        assert_eq!(retry_delay(10), MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(u32::MAX), MAX_RETRY_DELAY);
+    }
+
+    #[tokio::test]
+    async fn unreachable_device_is_retried_with_backoff() {
+        let device = TapoDevice::new(
+            serde_json::from_value::<TapoConnectionInfos>(serde_json::json!({
+                "name": "plug",
+                "device_type": "P100",
+                "ip_addr": "127.0.0.1"
+            }))
+            .unwrap(),
+            Arc::new(TapoCredentials {
+                email: "user@example.com".to_owned(),
+                password: "secret".to_owned(),
+            }),
+        );
+
+        assert_eq!(probe_device(&device, true).await, retry_delay(1));
+        assert_eq!(probe_device(&device, false).await, retry_delay(2));
+
+        let health = device.health().await;
+        assert!(health.status == DeviceStatus::Unreachable);
+        assert_eq!(health.consecutive_failures, 2);
+    }
+}
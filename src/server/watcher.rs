@@ -0,0 +1,211 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use log::{debug, error, info};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+
+use super::SharedState;
+
+/// Time without changes to wait for before reloading, as editors often write files in several steps
+const DEBOUNCE_DELAY: Duration = Duration::from_millis(500);
+
+/// Reload the configuration whenever its file changes
+pub async fn watch_config_file(state: SharedState) {
+    if let Err(err) = watch_config_file_inner(&state).await {
+        error!("! Failed to watch the configuration file: {err:#}");
+    }
+}
+
+async fn watch_config_file_inner(state: &SharedState) -> Result<()> {
+    let config_path = state
+        .config_path
+        .canonicalize()
+        .context("Failed to resolve the configuration file's path")?;
+
+    // Editors may replace the file instead of writing to it, so its parent directory is watched instead
+    let config_dir = config_path
+        .parent()
+        .context("Configuration file has no parent directory")?
+        .to_owned();
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+
+    let mut watcher = RecommendedWatcher::new(
+        move |event: notify::Result<Event>| {
+            // The receiver is only dropped when the server shuts down
+            let _ = tx.send(event);
+        },
+        notify::Config::default(),
+    )
+    .context("Failed to create the file watcher")?;
+
+    watcher
+        .watch(&config_dir, RecursiveMode::NonRecursive)
+        .with_context(|| format!("Failed to watch directory {}", config_dir.display()))?;
+
+    info!(
+        "Watching configuration file {} for changes...",
+        config_path.display()
+    );
+
+    let is_config_change = |event: &notify::Result<Event>| match event {
+        Ok(event) => {
+            !matches!(event.kind, EventKind::Access(_))
+                && event.paths.iter().any(|path| path == &config_path)
+        }
+
+        Err(err) => {
+            error!("! Error while watching the configuration file: {err}");
+            false
+        }
+    };
+
+    while let Some(event) = rx.recv().await {
+        if !is_config_change(&event) {
+            continue;
+        }
+
+        wait_for_quiet(&mut rx, DEBOUNCE_DELAY).await;
+
+        debug!("Configuration file changed");
+
+        reload(state, "configuration file changed").await;
+    }
+
+    Ok(())
+}
+
+/// Wait for the file to stop changing, discarding the events received in the meantime
+async fn wait_for_quiet<T>(rx: &mut mpsc::UnboundedReceiver<T>, delay: Duration) {
+    while let Ok(Some(_)) = tokio::time::timeout(delay, rx.recv()).await {}
+}
+
+/// Reload the configuration whenever the process receives a SIGHUP signal
+#[cfg(unix)]
+pub async fn reload_on_sighup(state: SharedState) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut signals = match signal(SignalKind::hangup()) {
+        Ok(signals) => signals,
+        Err(err) => {
+            error!("! Failed to listen for SIGHUP signals: {err}");
+            return;
+        }
+    };
+
+    while signals.recv().await.is_some() {
+        reload(&state, "received SIGHUP").await;
+    }
+}
+
+/// Reload the configuration, keeping the current one if the new one is invalid
+async fn reload(state: &SharedState, reason: &str) {
+    info!("Reloading configuration ({reason})...");
+
+    if let Err(err) = state.reload_config().await {
+        error!("! Failed to reload configuration, keeping the current one: {err:#}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, sync::Arc, time::Instant};
+
+    use serde_json::json;
+    use tokio::fs;
+
+    use super::*;
+    use crate::server::state::StateData;
+
+    #[tokio::test]
+    async fn quiet_after_the_last_event() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let delay = Duration::from_millis(100);
+
+        tokio::spawn(async move {
+            for _ in 0..3 {
+                tx.send(()).unwrap();
+                tokio::time::sleep(Duration::from_millis(40)).await;
+            }
+
+            // Keep the channel open, like the watcher does
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            drop(tx);
+        });
+
+        // Wait for the first event to be sent
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let start = Instant::now();
+        wait_for_quiet(&mut rx, delay).await;
+
+        // The last event was sent after ~80ms, so the wait can't end before ~180ms
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        assert!(rx.try_recv().is_err(), "all events were consumed");
+    }
+
+    async fn write_config(path: &Path, devices: &serde_json::Value) {
+        let config = json!({
+            "tapo_credentials": { "email": "user@example.com", "password": "secret" },
+            "devices": devices,
+            "server": { "password": "secret", "api_keys": [] }
+        });
+
+        fs::write(path, config.to_string()).await.unwrap();
+    }
+
+    async fn device_names(state: &SharedState) -> Vec<String> {
+        let config = state.config.read().await;
+        config
+            .devices
+            .iter()
+            .map(|device| device.name.clone())
+            .collect()
+    }
+
+    async fn wait_for_devices(state: &SharedState, expected: &[&str]) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+
+        while device_names(state).await != expected {
+            assert!(Instant::now() < deadline, "configuration was not reloaded");
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn file_changes_are_reloaded_unless_invalid() {
+        let dir = std::env::temp_dir().join(format!("tapo-rest-watcher-{}", std::process::id()));
+        fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("config.json");
+
+        write_config(&path, &json!([])).await;
+
+        let state = Arc::new(StateData::init(path.clone()).await.unwrap());
+        let watcher = tokio::spawn(watch_config_file(SharedState::clone(&state)));
+
+        // Let the watcher start
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        write_config(
+            &path,
+            &json!([{ "name": "plug", "device_type": "P100", "ip_addr": "127.0.0.1" }]),
+        )
+        .await;
+
+        wait_for_devices(&state, &["plug"]).await;
+
+        // An invalid configuration is reported, and the current one is kept
+        fs::write(&path, "{").await.unwrap();
+        tokio::time::sleep(DEBOUNCE_DELAY * 3).await;
+
+        assert_eq!(device_names(&state).await, ["plug"]);
+
+        // Later changes are still watched
+        write_config(&path, &json!([])).await;
+        wait_for_devices(&state, &[]).await;
+
+        watcher.abort();
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
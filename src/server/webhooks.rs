@@ -0,0 +1,598 @@
+use std::{collections::VecDeque, time::Duration};
+
+use anyhow::{Context, Result, bail};
+use axum::{
+    Json,
+    extract::State,
+    http::{Method, Uri},
+};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::Sha256;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::config::{Config, Webhook};
+
+use super::{
+    ApiResult, SharedState, ThresholdDirection,
+    actions::parse_params,
+    events::{DeviceEvent, DeviceEventKind, check_event_type},
+    openapi::OpenApiBuilder,
+};
+
+/// Maximum number of delivery attempts for a single event
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Delay before retrying a failed delivery for the first time
+/// Doubled after each failed attempt
+const BASE_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Maximum duration of a single delivery attempt
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of deliveries kept in the log
+pub const DELIVERY_LOG_CAPACITY: usize = 500;
+
+/// Header containing the HMAC-SHA256 signature of the timestamp and payload, when a secret is configured
+const SIGNATURE_HEADER: &str = "X-Tapo-Rest-Signature";
+
+/// Header containing the time the request was signed at, as a Unix timestamp in seconds
+const TIMESTAMP_HEADER: &str = "X-Tapo-Rest-Timestamp";
+
+const EVENT_HEADER: &str = "X-Tapo-Rest-Event";
+
+const DELIVERY_HEADER: &str = "X-Tapo-Rest-Delivery";
+
+#[derive(Serialize, Clone)]
+pub struct WebhookDelivery {
+    id: u64,
+    webhook: String,
+    event: DeviceEvent,
+    status: DeliveryStatus,
+    attempts: Vec<DeliveryAttempt>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    /// The delivery is still being attempted
+    Pending,
+
+    /// The endpoint accepted the event
+    Delivered,
+
+    /// All attempts failed
+    Failed,
+}
+
+#[derive(Serialize, Clone)]
+pub struct DeliveryAttempt {
+    timestamp: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status_code: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Send device events to the configured webhooks
+pub async fn deliver_webhooks(state: SharedState) {
+    let client = match reqwest::Client::builder().timeout(DELIVERY_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(err) => {
+            warn!("Failed to create HTTP client, webhooks are disabled: {err}");
+            return;
+        }
+    };
+
+    let mut receiver = state.events.subscribe();
+    let mut next_id = 0;
+
+    loop {
+        let event = match receiver.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("Webhooks are lagging behind, skipped {skipped} event(s)");
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+
+        let webhooks = state
+            .config
+            .read()
+            .await
+            .webhooks
+            .iter()
+            .filter(|webhook| webhook_matches(webhook, &event))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for webhook in webhooks {
+            next_id += 1;
+
+            let delivery = WebhookDelivery {
+                id: next_id,
+                webhook: webhook.name.clone(),
+                event: event.clone(),
+                status: DeliveryStatus::Pending,
+                attempts: vec![],
+            };
+
+            let mut log = state.webhook_deliveries.write().await;
+
+            if log.len() == DELIVERY_LOG_CAPACITY {
+                log.pop_front();
+            }
+
+            log.push_back(delivery);
+
+            tokio::spawn(deliver(
+                SharedState::clone(&state),
+                client.clone(),
+                webhook,
+                next_id,
+                event.clone(),
+            ));
+        }
+    }
+}
+
+fn webhook_matches(webhook: &Webhook, event: &DeviceEvent) -> bool {
+    if !webhook.devices.is_empty() && !webhook.devices.contains(&event.device) {
+        return false;
+    }
+
+    if !webhook.events.is_empty() && !webhook.events.iter().any(|name| name == event.kind.name()) {
+        return false;
+    }
+
+    match (&event.kind, webhook.threshold_direction) {
+        (DeviceEventKind::PowerThresholdCrossed { direction, .. }, Some(expected)) => {
+            *direction == expected
+        }
+        _ => true,
+    }
+}
+
+/// Deliver an event to a webhook, retrying with an exponential backoff on failure
+async fn deliver(
+    state: SharedState,
+    client: reqwest::Client,
+    webhook: Webhook,
+    id: u64,
+    event: DeviceEvent,
+) {
+    let payload = match serde_json::to_vec(&event) {
+        Ok(payload) => payload,
+        Err(err) => {
+            warn!("Failed to serialize device event: {err}");
+            return;
+        }
+    };
+
+    for attempt in 1..=MAX_ATTEMPTS {
+        let result = send(&client, &webhook, id, &event, &payload).await;
+
+        let delivered = matches!(result, Ok(status) if status.is_success());
+
+        // Client errors other than timeouts and rate limiting won't be fixed by retrying
+        let retryable =
+            result.as_ref().is_ok_and(|status| is_retryable(*status)) || result.is_err();
+
+        let status = if delivered {
+            DeliveryStatus::Delivered
+        } else if attempt == MAX_ATTEMPTS || !retryable {
+            DeliveryStatus::Failed
+        } else {
+            DeliveryStatus::Pending
+        };
+
+        let attempt_log = match result {
+            Ok(status) => DeliveryAttempt {
+                timestamp: Utc::now(),
+                status_code: Some(status.as_u16()),
+                error: None,
+            },
+            Err(err) => DeliveryAttempt {
+                timestamp: Utc::now(),
+                status_code: None,
+                error: Some(format!("{err:#}")),
+            },
+        };
+
+        if let Some(delivery) = state
+            .webhook_deliveries
+            .write()
+            .await
+            .iter_mut()
+            .find(|delivery| delivery.id == id)
+        {
+            delivery.status = status;
+            delivery.attempts.push(attempt_log);
+        }
+
+        match status {
+            DeliveryStatus::Delivered => {
+                debug!("Delivered event to webhook '{}'", webhook.name);
+                return;
+            }
+
+            DeliveryStatus::Failed => {
+                warn!(
+                    "! Failed to deliver event to webhook '{}' after {attempt} attempt(s)",
+                    webhook.name
+                );
+                return;
+            }
+
+            DeliveryStatus::Pending => {
+                tokio::time::sleep(retry_delay(attempt)).await;
+            }
+        }
+    }
+}
+
+async fn send(
+    client: &reqwest::Client,
+    webhook: &Webhook,
+    id: u64,
+    event: &DeviceEvent,
+    payload: &[u8],
+) -> Result<reqwest::StatusCode> {
+    let mut request = client
+        .post(&webhook.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(EVENT_HEADER, event.kind.name())
+        .header(DELIVERY_HEADER, id.to_string());
+
+    if let Some(secret) = &webhook.secret {
+        // Signing the timestamp along with the payload lets receivers reject replayed requests
+        let timestamp = Utc::now().timestamp();
+
+        request = request
+            .header(TIMESTAMP_HEADER, timestamp.to_string())
+            .header(SIGNATURE_HEADER, sign_payload(secret, timestamp, payload)?);
+    }
+
+    let response = request
+        .body(payload.to_vec())
+        .send()
+        .await
+        .context("Failed to send request")?;
+
+    Ok(response.status())
+}
+
+/// Compute the value of the signature header for a payload, signed as `<timestamp>.<payload>`
+fn sign_payload(secret: &str, timestamp: i64, payload: &[u8]) -> Result<String> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).context("Failed to initialize HMAC")?;
+    mac.update(format!("{timestamp}.").as_bytes());
+    mac.update(payload);
+
+    Ok(format!(
+        "sha256={}",
+        hex::encode(mac.finalize().into_bytes())
+    ))
+}
+
+/// Whether a delivery that got a response with this status may succeed when retried
+fn is_retryable(status: reqwest::StatusCode) -> bool {
+    !status.is_client_error()
+        || matches!(
+            status,
+            reqwest::StatusCode::REQUEST_TIMEOUT | reqwest::StatusCode::TOO_MANY_REQUESTS
+        )
+}
+
+/// Delay to wait for after a failed attempt (starting from 1), before the next one
+fn retry_delay(attempt: u32) -> Duration {
+    BASE_RETRY_DELAY.saturating_mul(2_u32.saturating_pow(attempt - 1))
+}
+
+/// Ensure a webhook is valid and only refers to existing devices and event types
+pub fn validate_webhook(webhook: &Webhook, config: &Config) -> Result<()> {
+    let name = &webhook.name;
+
+    let url = reqwest::Url::parse(&webhook.url)
+        .with_context(|| format!("Invalid URL for webhook '{name}'"))?;
+
+    if !matches!(url.scheme(), "http" | "https") {
+        bail!("Webhook '{name}' must use an HTTP or HTTPS URL");
+    }
+
+    for event in &webhook.events {
+        check_event_type(event).with_context(|| format!("Invalid event for webhook '{name}'"))?;
+    }
+
+    for device in &webhook.devices {
+        if !config
+            .devices
+            .iter()
+            .any(|candidate| &candidate.name == device)
+        {
+            bail!("Webhook '{name}' refers to unknown device '{device}'");
+        }
+    }
+
+    Ok(())
+}
+
+/// Webhook configuration, without the secret
+#[derive(Serialize)]
+pub struct WebhookDetails {
+    name: String,
+    url: String,
+    events: Vec<String>,
+    devices: Vec<String>,
+    threshold_direction: Option<ThresholdDirection>,
+    signed: bool,
+}
+
+pub async fn list_webhooks(State(state): State<SharedState>) -> Json<Vec<WebhookDetails>> {
+    Json(
+        state
+            .config
+            .read()
+            .await
+            .webhooks
+            .iter()
+            .map(|webhook| WebhookDetails {
+                name: webhook.name.clone(),
+                url: webhook.url.clone(),
+                events: webhook.events.clone(),
+                devices: webhook.devices.clone(),
+                threshold_direction: webhook.threshold_direction,
+                signed: webhook.secret.is_some(),
+            })
+            .collect(),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct DeliveriesParams {
+    /// Only list deliveries to this webhook
+    #[serde(default)]
+    webhook: Option<String>,
+
+    /// Only list deliveries with this status
+    #[serde(default)]
+    status: Option<DeliveryStatus>,
+
+    /// Maximum number of deliveries to list, most recent first
+    #[serde(default)]
+    limit: Option<usize>,
+}
+
+/// List the most recent deliveries
+pub async fn list_deliveries(
+    State(state): State<SharedState>,
+    uri: Uri,
+) -> ApiResult<Json<Vec<WebhookDelivery>>> {
+    let DeliveriesParams {
+        webhook,
+        status,
+        limit,
+    } = parse_params(&uri, &[])?;
+
+    let log = state.webhook_deliveries.read().await;
+
+    Ok(Json(
+        log.iter()
+            .rev()
+            .filter(|delivery| {
+                webhook
+                    .as_ref()
+                    .is_none_or(|name| &delivery.webhook == name)
+            })
+            .filter(|delivery| status.is_none_or(|status| delivery.status == status))
+            .take(limit.unwrap_or(DELIVERY_LOG_CAPACITY))
+            .cloned()
+            .collect(),
+    ))
+}
+
+pub fn new_delivery_log() -> VecDeque<WebhookDelivery> {
+    VecDeque::with_capacity(DELIVERY_LOG_CAPACITY)
+}
+
+/// Document the webhook routes in the `OpenAPI` specification
+pub fn add_webhooks_to_openapi(spec: &mut OpenApiBuilder) {
+    spec.add_route(
+        "/webhooks",
+        &Method::GET,
+        json!({
+            "summary": "List all webhooks",
+            "responses": { "200": { "description": "Success" } }
+        }),
+    );
+
+    spec.add_route(
+        "/webhooks/deliveries",
+        &Method::GET,
+        json!({
+            "summary": "List the most recent webhook deliveries, along with their attempts",
+            "parameters": [
+                {
+                    "name": "webhook",
+                    "in": "query",
+                    "required": false,
+                    "schema": { "type": "string" }
+                },
+                {
+                    "name": "status",
+                    "in": "query",
+                    "required": false,
+                    "schema": { "type": "string", "enum": ["pending", "delivered", "failed"] }
+                },
+                {
+                    "name": "limit",
+                    "in": "query",
+                    "required": false,
+                    "schema": { "type": "integer", "minimum": 0 }
+                }
+            ],
+            "responses": {
+                "200": { "description": "Success" },
+                "400": OpenApiBuilder::error_response("Invalid parameter")
+            }
+        }),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn webhook(events: &[&str], devices: &[&str]) -> Webhook {
+        Webhook {
+            name: "hook".to_owned(),
+            url: "http://localhost/hook".to_owned(),
+            events: events.iter().map(|&event| event.to_owned()).collect(),
+            devices: devices.iter().map(|&device| device.to_owned()).collect(),
+            threshold_direction: None,
+            secret: None,
+        }
+    }
+
+    fn event(device: &str, kind: DeviceEventKind) -> DeviceEvent {
+        DeviceEvent {
+            device: device.to_owned(),
+            timestamp: Utc::now(),
+            kind,
+        }
+    }
+
+    fn threshold_crossed(direction: ThresholdDirection) -> DeviceEventKind {
+        DeviceEventKind::PowerThresholdCrossed {
+            threshold: 100,
+            current_power: 120,
+            direction,
+        }
+    }
+
+    #[test]
+    fn signature_is_a_hex_hmac_sha256() {
+        assert_eq!(
+            sign_payload(
+                "key",
+                1_700_000_000,
+                b"The quick brown fox jumps over the lazy dog"
+            )
+            .unwrap(),
+            "sha256=2f658d6aef4f246e91cd741bbcded7479e9605f9d41c9e248122a117e0e1765b"
+        );
+    }
+
+    #[test]
+    fn signature_depends_on_secret_timestamp_and_payload() {
+        let signature = sign_payload("secret", 1_700_000_000, b"{}").unwrap();
+
+        assert_ne!(
+            signature,
+            sign_payload("other", 1_700_000_000, b"{}").unwrap()
+        );
+        assert_ne!(
+            signature,
+            sign_payload("secret", 1_700_000_001, b"{}").unwrap()
+        );
+        assert_ne!(
+            signature,
+            sign_payload("secret", 1_700_000_000, b"[]").unwrap()
+        );
+    }
+
+    #[test]
+    fn only_server_errors_timeouts_and_rate_limiting_are_retried() {
+        use reqwest::StatusCode;
+
+        for status in [
+            StatusCode::INTERNAL_SERVER_ERROR,
+            StatusCode::BAD_GATEWAY,
+            StatusCode::SERVICE_UNAVAILABLE,
+            StatusCode::REQUEST_TIMEOUT,
+            StatusCode::TOO_MANY_REQUESTS,
+        ] {
+            assert!(is_retryable(status), "{status}");
+        }
+
+        for status in [
+            StatusCode::BAD_REQUEST,
+            StatusCode::UNAUTHORIZED,
+            StatusCode::NOT_FOUND,
+            StatusCode::GONE,
+        ] {
+            assert!(!is_retryable(status), "{status}");
+        }
+    }
+
+    #[test]
+    fn empty_filters_match_everything() {
+        let webhook = webhook(&[], &[]);
+
+        assert!(webhook_matches(
+            &webhook,
+            &event("plug", DeviceEventKind::DeviceOnline)
+        ));
+        assert!(webhook_matches(
+            &webhook,
+            &event("bulb", DeviceEventKind::PowerChanged { on: true })
+        ));
+    }
+
+    #[test]
+    fn filter_by_device_and_event() {
+        let webhook = webhook(&["power_changed"], &["plug"]);
+
+        assert!(webhook_matches(
+            &webhook,
+            &event("plug", DeviceEventKind::PowerChanged { on: false })
+        ));
+        assert!(!webhook_matches(
+            &webhook,
+            &event("bulb", DeviceEventKind::PowerChanged { on: false })
+        ));
+        assert!(!webhook_matches(
+            &webhook,
+            &event("plug", DeviceEventKind::DeviceOnline)
+        ));
+    }
+
+    #[test]
+    fn filter_by_threshold_direction() {
+        let mut webhook = webhook(&[], &[]);
+        webhook.threshold_direction = Some(ThresholdDirection::Above);
+
+        assert!(webhook_matches(
+            &webhook,
+            &event("plug", threshold_crossed(ThresholdDirection::Above))
+        ));
+        assert!(!webhook_matches(
+            &webhook,
+            &event("plug", threshold_crossed(ThresholdDirection::Below))
+        ));
+
+        // The direction only applies to threshold events
+        assert!(webhook_matches(
+            &webhook,
+            &event("plug", DeviceEventKind::PowerChanged { on: true })
+        ));
+    }
+
+    #[test]
+    fn retry_delay_doubles_after_each_attempt() {
+        let delays = (1..MAX_ATTEMPTS).map(retry_delay).collect::<Vec<_>>();
+
+        assert_eq!(
+            delays,
+            [2, 4, 8, 16].map(Duration::from_secs),
+            "one delay between each of the {MAX_ATTEMPTS} attempts"
+        );
+
+        // Large attempt numbers saturate instead of overflowing
+        assert!(retry_delay(u32::MAX) > retry_delay(MAX_ATTEMPTS));
+    }
+}